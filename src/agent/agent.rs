@@ -1,92 +1,250 @@
 extern crate futures;
 extern crate itertools;
+extern crate serde;
 extern crate serde_json;
 extern crate spoolq;
+extern crate tokio_core;
 
 use std;
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 use std::os::unix::fs::OpenOptionsExt;
+use std::sync::{Arc, Mutex};
 
+use self::futures::{Future, Stream};
 use self::futures::sync::mpsc;
 
 use super::*;
-
+use self::api::MatchStatus;
+
+/// Seconds to wait before evaluating a match again, after it failed or its check scripts did
+/// not hold while its timers were due.
+const RETRY_SECS: u64 = 1;
+
+/// An agent, running its matches as a stream which yields each time one of them fires.
+///
+/// Rather than polling every match in turn, the agent only evaluates matches which may fire
+/// because something changed: a message arrived on a topic they filter on, a transaction
+/// committed, or one of their timers came due. When none may fire, the agent is parked until a
+/// message arrives or the earliest timer is due.
 pub struct Agent<S: runtime::Storage> {
     matches: Vec<runtime::Match>,
     st: runtime::State<S>,
     receiver: mpsc::Receiver<Message>,
-    match_index: usize,
+    remote: tokio_core::reactor::Remote,
+    /// Matches to evaluate, which are evaluated in the order they are declared.
+    pending: BTreeSet<usize>,
+    /// Matches filtering on each topic.
+    by_topic: HashMap<String, Vec<usize>>,
+    /// Matches filtering on each topic pattern.
+    by_pattern: HashMap<String, Vec<usize>>,
+    /// Time at which each match is next evaluated, if it has timers.
+    wake_at: Vec<Option<u64>>,
+    /// Time at which the agent was last scheduled to wake up.
+    scheduled: Option<u64>,
+    status: Arc<Mutex<Vec<MatchStatus>>>,
 }
 
 impl<S: runtime::Storage> Agent<S> {
+    /// Create an agent. A new agent starts with an init message, with the given contents.
+    /// Timers waking the agent up are run on the reactor of the given remote.
     pub fn new(glop: &ast::Glop,
                st: runtime::State<S>,
-               receiver: mpsc::Receiver<Message>)
+               init: Obj,
+               receiver: mpsc::Receiver<Message>,
+               remote: tokio_core::reactor::Remote)
                -> Result<Agent<S>, Error> {
         let mut st = st;
+        st.set_schemas(Schemas::from_ast(&glop.topics));
         let (seq, _) = st.mut_storage().load()?;
         if seq == 0 {
             st.mut_storage()
-                .push_msg(Message::new("init", Obj::new()))?;
+                .push_msg(Message::new("init", init))?;
         }
         let m_excs = glop.matches
             .iter()
-            .map(|m_ast| runtime::Match::new_from_ast(&m_ast))
+            .enumerate()
+            .map(|(i, m_ast)| runtime::Match::new_at(&m_ast, i))
             .collect::<Vec<_>>();
+        let mut by_topic = HashMap::new();
+        let mut by_pattern = HashMap::new();
+        for (i, m) in m_excs.iter().enumerate() {
+            for filter in m.filters() {
+                let index = if ast::is_topic_pattern(&filter.topic) {
+                    &mut by_pattern
+                } else {
+                    &mut by_topic
+                };
+                index.entry(filter.topic).or_insert_with(Vec::new).push(i);
+            }
+        }
+        let status = m_excs.iter()
+            .map(|m| {
+                MatchStatus {
+                    name: format!("{}", m),
+                    description: m.description.clone(),
+                    evaluated: 0,
+                    fired: 0,
+                    failed: 0,
+                    last_error: None,
+                }
+            })
+            .collect();
         Ok(Agent {
+            pending: (0..m_excs.len()).collect(),
+            wake_at: vec![None; m_excs.len()],
             matches: m_excs,
             st: st,
             receiver: receiver,
-            match_index: 0,
+            remote: remote,
+            by_topic: by_topic,
+            by_pattern: by_pattern,
+            scheduled: None,
+            status: Arc::new(Mutex::new(status)),
         })
     }
 
-    fn poll_matches(&mut self) -> futures::Poll<Option<()>, Error> {
-        let i = self.match_index % self.matches.len();
+    /// Status of each of the agent's matches, updated as the agent runs.
+    pub fn status(&self) -> Arc<Mutex<Vec<MatchStatus>>> {
+        self.status.clone()
+    }
+
+    /// Store all the messages received so far, marking the matches filtering on their topics
+    /// to be evaluated. Returns false once the agent's sender has been dropped.
+    fn receive(&mut self) -> Result<bool, Error> {
+        loop {
+            match self.receiver.poll() {
+                Ok(futures::Async::Ready(Some(msg))) => {
+                    if let Some(ms) = self.by_topic.get(&msg.topic) {
+                        self.pending.extend(ms);
+                    }
+                    for (pattern, ms) in &self.by_pattern {
+                        if ast::topic_matches(pattern, &msg.topic) {
+                            self.pending.extend(ms);
+                        }
+                    }
+                    self.st.mut_storage().push_msg(msg)?;
+                }
+                Ok(futures::Async::Ready(None)) |
+                Err(_) => return Ok(false),
+                Ok(futures::Async::NotReady) => return Ok(true),
+            }
+        }
+    }
+
+    /// Evaluate a match, committing its transaction if it matched. Returns whether a
+    /// transaction was attempted.
+    fn poll_match(&mut self, i: usize, now: u64) -> Result<bool, Error> {
         let m = &self.matches[i];
-        // TODO: intelligent selection of next match?
-        self.match_index = self.match_index + 1;
-        let mut txn = match self.st.eval(m.clone()) {
-            Ok(Some(txn)) => txn,
-            Ok(None) => return Ok(futures::Async::Ready(Some(()))),
-            Err(e) => return Err(e),
+        // A match can't fire without its messages, and will be evaluated again when they
+        // arrive.
+        if !m.required_topics().iter().all(|topic| self.st.storage().has_pending(topic)) {
+            self.wake_at[i] = None;
+            return Ok(false);
+        }
+        self.status.lock().unwrap()[i].evaluated += 1;
+        let mut txn = match self.st.eval(m.clone())? {
+            Some(txn) => txn,
+            None => {
+                self.wake_at[i] = match m.due_at(self.st.storage().timers(), now) {
+                    Some(t) if t > now => Some(t),
+                    // Only a check script may hold later with nothing else changing; other
+                    // conditions change with messages and commits, which evaluate it again.
+                    Some(_) if m.has_checks() => Some(now + RETRY_SECS),
+                    _ => None,
+                };
+                // Messages further back than evaluation got to are tried next time.
+                if self.st.has_untried(m) {
+                    let retry = now + RETRY_SECS;
+                    self.wake_at[i] = Some(self.wake_at[i].map_or(retry, |t| t.min(retry)));
+                }
+                return Ok(false);
+            }
         };
         // TODO: graceful agent termination (nothing left to do)?
         let result = self.st.commit(&mut txn);
+        let mut status = self.status.lock().unwrap();
         match result {
-            Ok(_) => {}
+            Ok(_) => {
+                status[i].fired += 1;
+                // Vars, timers and pending messages may all have changed.
+                self.pending.extend(0..self.matches.len());
+            }
             Err(e) => {
-                error!("transaction seq={} failed: {}", txn.seq, e);
-                match self.st.rollback(txn) {
-                    Ok(_) => {}
-                    Err(e) => return Err(e),
-                }
+                error!("transaction seq={} of match {} failed: {}", txn.seq, m, e);
+                status[i].failed += 1;
+                status[i].last_error = Some(e.to_string());
+                self.st.rollback(txn)?;
+                self.wake_at[i] = Some(now + RETRY_SECS);
             }
         }
-        Ok(futures::Async::Ready(Some(())))
+        Ok(true)
+    }
+
+    /// Wake the current task up when the earliest match timer is due, unless it is already
+    /// set to wake up by then.
+    fn schedule_wakeup(&mut self, now: u64) {
+        let next = match self.wake_at.iter().filter_map(|t| *t).min() {
+            Some(next) => next,
+            None => return,
+        };
+        if self.scheduled.map_or(false, |t| t > now && t <= next) {
+            return;
+        }
+        self.scheduled = Some(next);
+        let task = futures::task::current();
+        let dur = std::time::Duration::from_secs(next - now);
+        self.remote.spawn(move |handle| {
+            futures::future::result(tokio_core::reactor::Timeout::new(dur, handle))
+                .flatten()
+                .then(move |_| {
+                    task.notify();
+                    Ok(())
+                })
+        });
     }
 }
 
-impl<S: runtime::Storage> futures::stream::Stream for Agent<S> {
+impl<S: runtime::Storage> Stream for Agent<S> {
     type Item = ();
     type Error = Error;
 
     fn poll(&mut self) -> futures::Poll<Option<Self::Item>, Self::Error> {
-        // TODO: poll mpsc channel (receiver end) for state changes & apply?
-        match self.receiver.poll() {
-            Ok(futures::Async::Ready(Some(msg))) => {
-                self.st.mut_storage().push_msg(msg)?;
+        loop {
+            if !self.receive()? {
+                return Ok(futures::Async::Ready(None));
+            }
+            let now = self.st.now();
+            for (i, t) in self.wake_at.iter_mut().enumerate() {
+                if t.map_or(false, |t| t <= now) {
+                    *t = None;
+                    self.pending.insert(i);
+                }
+            }
+            let i = match self.pending.iter().next().cloned() {
+                Some(i) => i,
+                None => {
+                    // Parked until a message arrives or a timer is due.
+                    self.schedule_wakeup(now);
+                    return Ok(futures::Async::NotReady);
+                }
+            };
+            self.pending.remove(&i);
+            if self.poll_match(i, now)? {
+                return Ok(futures::Async::Ready(Some(())));
             }
-            Ok(futures::Async::Ready(None)) => return Ok(futures::Async::Ready(None)),
-            Ok(futures::Async::NotReady) => {}
-            Err(_) => return Ok(futures::Async::Ready(None)),
         }
-        let result = self.poll_matches();
-        std::thread::sleep(std::time::Duration::from_millis(100));
-        result
     }
 }
 
+/// An agent instantiated from a template registered on the server.
+#[derive(Serialize, Deserialize)]
+#[derive(Clone, Debug)]
+pub struct Instance {
+    pub template: String,
+    /// Parameters of the instance, given to it as the contents of its init message.
+    pub params: Obj,
+}
+
 pub trait AgentStorage {
     type RuntimeStorage: runtime::Storage + Send;
 
@@ -97,18 +255,29 @@ pub trait AgentStorage {
     fn add_agent(&mut self, name: String, glop: ast::Glop) -> Result<(), Error>;
     fn remove_agent(&mut self, name: &str) -> Result<(), Error>;
     fn agents(&self) -> Result<HashMap<String, ast::Glop>, Error>;
+    fn add_template(&mut self, name: String, glop: ast::Glop) -> Result<(), Error>;
+    fn remove_template(&mut self, name: &str) -> Result<(), Error>;
+    fn templates(&self) -> Result<HashMap<String, ast::Glop>, Error>;
+    fn add_instance(&mut self, name: String, instance: Instance) -> Result<(), Error>;
+    fn instances(&self) -> Result<HashMap<String, Instance>, Error>;
     fn push_remote_msg(&mut self, msg: Message) -> Result<(), Error>;
     fn fetch_remote_reply(&mut self,
                           remote_id: &str,
                           in_reply_to: &str)
                           -> Result<Option<Message>, Error>;
     fn fetch_remote_msgs(&mut self, remote_id: &str) -> Result<Vec<Message>, Error>;
+    /// Journal entries of the transactions an agent committed, from a sequence number on.
+    fn journal(&self, name: &str, since_seq: i32) -> Result<Vec<runtime::JournalEntry>, Error>;
 }
 
 #[derive(Clone)]
 pub struct MemAgentStorage {
     agents: HashMap<String, ast::Glop>,
+    templates: HashMap<String, ast::Glop>,
+    instances: HashMap<String, Instance>,
     remote_msgs: HashMap<String, Vec<Message>>,
+    /// Journal of each agent started, shared with its runtime storage.
+    journals: Arc<Mutex<HashMap<String, Arc<Mutex<Vec<runtime::JournalEntry>>>>>>,
 }
 
 impl MemAgentStorage {
@@ -116,7 +285,10 @@ impl MemAgentStorage {
     pub fn new() -> MemAgentStorage {
         MemAgentStorage {
             agents: HashMap::new(),
+            templates: HashMap::new(),
+            instances: HashMap::new(),
             remote_msgs: HashMap::new(),
+            journals: Arc::new(Mutex::new(HashMap::new())),
         }
     }
 }
@@ -128,11 +300,13 @@ impl AgentStorage for MemAgentStorage {
                  name: &str,
                  outbox: Box<runtime::Outbox + Send + 'static>)
                  -> Result<runtime::State<Self::RuntimeStorage>, Error> {
-        Ok(runtime::State::new_outbox(name, Self::RuntimeStorage::new(), outbox))
+        let journal = Arc::new(Mutex::new(vec![]));
+        self.journals.lock().unwrap().insert(name.to_string(), journal.clone());
+        Ok(runtime::State::new_outbox(name, Self::RuntimeStorage::new_journal(journal), outbox))
     }
 
     fn add_agent(&mut self, name: String, glop: ast::Glop) -> Result<(), Error> {
-        if self.agents.contains_key(&name) {
+        if self.agents.contains_key(&name) || self.instances.contains_key(&name) {
             return Err(Error::AgentExists(name));
         }
         self.agents.insert(name, glop);
@@ -141,6 +315,8 @@ impl AgentStorage for MemAgentStorage {
 
     fn remove_agent(&mut self, name: &str) -> Result<(), Error> {
         self.agents.remove(name);
+        self.instances.remove(name);
+        self.journals.lock().unwrap().remove(name);
         Ok(())
     }
 
@@ -148,6 +324,32 @@ impl AgentStorage for MemAgentStorage {
         Ok(self.agents.clone())
     }
 
+    fn add_template(&mut self, name: String, glop: ast::Glop) -> Result<(), Error> {
+        self.templates.insert(name, glop);
+        Ok(())
+    }
+
+    fn remove_template(&mut self, name: &str) -> Result<(), Error> {
+        self.templates.remove(name);
+        Ok(())
+    }
+
+    fn templates(&self) -> Result<HashMap<String, ast::Glop>, Error> {
+        Ok(self.templates.clone())
+    }
+
+    fn add_instance(&mut self, name: String, instance: Instance) -> Result<(), Error> {
+        if self.agents.contains_key(&name) || self.instances.contains_key(&name) {
+            return Err(Error::AgentExists(name));
+        }
+        self.instances.insert(name, instance);
+        Ok(())
+    }
+
+    fn instances(&self) -> Result<HashMap<String, Instance>, Error> {
+        Ok(self.instances.clone())
+    }
+
     fn push_remote_msg(&mut self, msg: Message) -> Result<(), Error> {
         if let Some(ref dst_target) = msg.dst_remote.clone() {
             if !self.remote_msgs.contains_key(dst_target) {
@@ -185,12 +387,23 @@ impl AgentStorage for MemAgentStorage {
             None => Ok(vec![]),
         }
     }
+
+    fn journal(&self, name: &str, since_seq: i32) -> Result<Vec<runtime::JournalEntry>, Error> {
+        match self.journals.lock().unwrap().get(name) {
+            Some(journal) => {
+                Ok(journal.lock().unwrap().iter().filter(|e| e.seq >= since_seq).cloned().collect())
+            }
+            None => Ok(vec![]),
+        }
+    }
 }
 
 #[derive(Clone)]
 pub struct DurableAgentStorage {
     path: String,
     agents_json_path: String,
+    templates_json_path: String,
+    instances_json_path: String,
     remote_msgs: HashMap<String, spoolq::Queue<Message>>,
 }
 
@@ -203,34 +416,155 @@ impl DurableAgentStorage {
                 .to_str()
                 .unwrap()
                 .to_string(),
+            templates_json_path: std::path::PathBuf::from(path)
+                .join("templates.json")
+                .to_str()
+                .unwrap()
+                .to_string(),
+            instances_json_path: std::path::PathBuf::from(path)
+                .join("instances.json")
+                .to_str()
+                .unwrap()
+                .to_string(),
             remote_msgs: HashMap::new(),
         }
     }
 
     fn load_agents(&self) -> Result<HashMap<String, ast::Glop>, Error> {
-        if !std::path::PathBuf::from(&self.agents_json_path).exists() {
-            return Ok(HashMap::new());
+        let agents: HashMap<String, serde_json::Value> = load_json_map(&self.agents_json_path)?;
+        let mut result = HashMap::new();
+        for (name, mut glop) in agents {
+            upgrade_glop_json(&mut glop);
+            let glop = serde_json::from_value(glop).map_err(to_ioerror).map_err(Error::IO)?;
+            result.insert(name, glop);
         }
-        let agents_file = std::fs::OpenOptions::new().read(true)
-            .open(&self.agents_json_path)?;
-        let agents: HashMap<String, ast::Glop> =
-            serde_json::from_reader(agents_file).map_err(to_ioerror)
-                .map_err(Error::IO)?;
-        Ok(agents)
+        Ok(result)
     }
 
     fn save_agents(&self, agents: HashMap<String, ast::Glop>) -> Result<(), Error> {
-        let mut agents_file = std::fs::OpenOptions::new().write(true)
-            .mode(0o600)
-            .create(true)
-            .truncate(true)
-            .open(&self.agents_json_path)?;
-        serde_json::to_writer(&mut agents_file, &agents).map_err(to_ioerror)
+        save_json_map(&self.agents_json_path, agents)
+    }
+
+    fn load_templates(&self) -> Result<HashMap<String, ast::Glop>, Error> {
+        load_json_map(&self.templates_json_path)
+    }
+
+    fn save_templates(&self, templates: HashMap<String, ast::Glop>) -> Result<(), Error> {
+        save_json_map(&self.templates_json_path, templates)
+    }
+
+    fn load_instances(&self) -> Result<HashMap<String, Instance>, Error> {
+        load_json_map(&self.instances_json_path)
+    }
+
+    fn save_instances(&self, instances: HashMap<String, Instance>) -> Result<(), Error> {
+        save_json_map(&self.instances_json_path, instances)
+    }
+
+    fn runtime_path(&self, name: &str) -> String {
+        std::path::PathBuf::from(&self.path)
+            .join(name)
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+}
+
+fn load_json_map<T>(path: &str) -> Result<HashMap<String, T>, Error>
+    where T: serde::Deserialize
+{
+    if !std::path::PathBuf::from(path).exists() {
+        return Ok(HashMap::new());
+    }
+    let f = std::fs::OpenOptions::new().read(true)
+        .open(path)?;
+    let result: HashMap<String, T> =
+        serde_json::from_reader(f).map_err(to_ioerror)
             .map_err(Error::IO)?;
-        Ok(())
+    Ok(result)
+}
+
+/// Rewrite an agent saved by a version of glop whose conditions and var sets took plain
+/// var names and string values into the form it is now saved in. Agents already in that
+/// form are left as they are.
+fn upgrade_glop_json(glop: &mut serde_json::Value) {
+    if let Some(matches) = glop.pointer_mut("/matches").and_then(|v| v.as_array_mut()) {
+        for m in matches.iter_mut() {
+            upgrade_match_json(m);
+        }
     }
 }
 
+fn upgrade_match_json(m: &mut serde_json::Value) {
+    if let Some(conditions) = m.pointer_mut("/conditions").and_then(|v| v.as_array_mut()) {
+        for c in conditions.iter_mut() {
+            if let Some(c) = c.as_object_mut() {
+                for (tag, args) in c.iter_mut() {
+                    upgrade_condition_json(tag, args);
+                }
+            }
+        }
+    }
+    if let Some(actions) = m.pointer_mut("/actions").and_then(|v| v.as_array_mut()) {
+        for a in actions.iter_mut() {
+            if let Some(a) = a.as_object_mut() {
+                for (tag, args) in a.iter_mut() {
+                    match tag.as_str() {
+                        "SetVar" => {
+                            if let Some(args) = args.as_array_mut() {
+                                if args.len() == 2 && args[1].is_string() {
+                                    let value = tagged_json("Str", args[1].clone());
+                                    args[1] = tagged_json("Literal", value);
+                                }
+                            }
+                        }
+                        "Match" => upgrade_match_json(args),
+                        _ => {}
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn upgrade_condition_json(tag: &str, args: &mut serde_json::Value) {
+    match tag {
+        "Cmp" => {
+            if let Some(args) = args.as_array_mut() {
+                if args.len() == 3 && args[0].is_array() && args[2].is_string() {
+                    args[0] = tagged_json("Var", args[0].clone());
+                    args[2] = tagged_json("Str", args[2].clone());
+                }
+            }
+        }
+        "IsSet" | "IsUnset" => {
+            if args.is_array() {
+                *args = tagged_json("Var", args.clone());
+            }
+        }
+        _ => {}
+    }
+}
+
+fn tagged_json(tag: &str, value: serde_json::Value) -> serde_json::Value {
+    let mut m = serde_json::Map::new();
+    m.insert(tag.to_string(), value);
+    serde_json::Value::Object(m)
+}
+
+fn save_json_map<T>(path: &str, m: HashMap<String, T>) -> Result<(), Error>
+    where T: serde::Serialize
+{
+    let mut f = std::fs::OpenOptions::new().write(true)
+        .mode(0o600)
+        .create(true)
+        .truncate(true)
+        .open(path)?;
+    serde_json::to_writer(&mut f, &m).map_err(to_ioerror)
+        .map_err(Error::IO)?;
+    Ok(())
+}
+
 impl AgentStorage for DurableAgentStorage {
     type RuntimeStorage = runtime::DurableStorage;
 
@@ -238,17 +572,15 @@ impl AgentStorage for DurableAgentStorage {
                  name: &str,
                  outbox: Box<runtime::Outbox + Send + 'static>)
                  -> Result<runtime::State<Self::RuntimeStorage>, Error> {
-        let runtime_path = std::path::PathBuf::from(&self.path)
-            .join(name)
-            .to_str()
-            .unwrap()
-            .to_string();
-        let runtime_storage = runtime::DurableStorage::new(&runtime_path)?;
+        let runtime_storage = runtime::DurableStorage::new(&self.runtime_path(name))?;
         Ok(runtime::State::new_outbox(name, runtime_storage, outbox))
     }
 
     fn add_agent(&mut self, name: String, glop: ast::Glop) -> Result<(), Error> {
         let mut agents = self.load_agents()?;
+        if agents.contains_key(&name) || self.load_instances()?.contains_key(&name) {
+            return Err(Error::AgentExists(name));
+        }
         agents.insert(name, glop);
         self.save_agents(agents)?;
         Ok(())
@@ -258,6 +590,10 @@ impl AgentStorage for DurableAgentStorage {
         let mut agents = self.load_agents()?;
         agents.remove(name);
         self.save_agents(agents)?;
+        let mut instances = self.load_instances()?;
+        if instances.remove(name).is_some() {
+            self.save_instances(instances)?;
+        }
         Ok(())
     }
 
@@ -266,6 +602,35 @@ impl AgentStorage for DurableAgentStorage {
         Ok(agents)
     }
 
+    fn add_template(&mut self, name: String, glop: ast::Glop) -> Result<(), Error> {
+        let mut templates = self.load_templates()?;
+        templates.insert(name, glop);
+        self.save_templates(templates)
+    }
+
+    fn remove_template(&mut self, name: &str) -> Result<(), Error> {
+        let mut templates = self.load_templates()?;
+        templates.remove(name);
+        self.save_templates(templates)
+    }
+
+    fn templates(&self) -> Result<HashMap<String, ast::Glop>, Error> {
+        self.load_templates()
+    }
+
+    fn add_instance(&mut self, name: String, instance: Instance) -> Result<(), Error> {
+        let mut instances = self.load_instances()?;
+        if instances.contains_key(&name) || self.load_agents()?.contains_key(&name) {
+            return Err(Error::AgentExists(name));
+        }
+        instances.insert(name, instance);
+        self.save_instances(instances)
+    }
+
+    fn instances(&self) -> Result<HashMap<String, Instance>, Error> {
+        self.load_instances()
+    }
+
     fn push_remote_msg(&mut self, msg: Message) -> Result<(), Error> {
         if let Some(ref dst_target) = msg.dst_remote.clone() {
             if !self.remote_msgs.contains_key(dst_target) {
@@ -325,4 +690,8 @@ impl AgentStorage for DurableAgentStorage {
             None => Ok(vec![]),
         }
     }
+
+    fn journal(&self, name: &str, since_seq: i32) -> Result<Vec<runtime::JournalEntry>, Error> {
+        Ok(runtime::DurableStorage::read_journal(&self.runtime_path(name), since_seq)?)
+    }
 }
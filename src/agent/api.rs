@@ -5,7 +5,28 @@ use super::*;
 pub enum Request {
     Add { contents: String, name: String },
     Remove { name: String },
+    AddTemplate { contents: String, name: String },
+    /// Replace a template's source, optionally restarting its instances with the new source.
+    UpdateTemplate {
+        contents: String,
+        name: String,
+        update_instances: bool,
+    },
+    RemoveTemplate { name: String },
+    ListTemplates,
+    /// Add an agent instantiated from a template, with parameters for its init message.
+    Instantiate {
+        name: String,
+        template: String,
+        params: Obj,
+    },
     List,
+    /// Fetch the topic schemas declared by an agent.
+    Schema { name: String },
+    /// Fetch the status of each of an agent's matches.
+    Matches { name: String },
+    /// Fetch the journal of transactions an agent committed, from a sequence number on.
+    History { name: String, since_seq: i32 },
     SendTo(Message),
     Introduce(Vec<AgentRole>),
     FetchReply { in_reply_to: String },
@@ -25,12 +46,37 @@ pub struct AgentRole {
     pub role: String,
 }
 
+/// How a match in a running agent has fared since the agent started.
+#[derive(Serialize, Deserialize)]
+#[derive(Clone, Debug)]
+pub struct MatchStatus {
+    /// Name of the match, or its conditions if it is unnamed.
+    pub name: String,
+    pub description: Option<String>,
+    /// Number of times the match was evaluated, which it only is when it may fire.
+    #[serde(default)]
+    pub evaluated: u64,
+    /// Number of transactions committed.
+    pub fired: u64,
+    /// Number of transactions rolled back on error.
+    pub failed: u64,
+    pub last_error: Option<String>,
+}
+
 #[derive(Serialize, Deserialize)]
 #[derive(Debug)]
 pub enum Response {
     Add,
     Remove,
+    AddTemplate,
+    UpdateTemplate { instances: Vec<String> },
+    RemoveTemplate,
+    ListTemplates { names: Vec<String> },
+    Instantiate,
     List { names: Vec<String> },
+    Schema { topics: Vec<ast::Topic> },
+    Matches { matches: Vec<MatchStatus> },
+    History { entries: Vec<runtime::JournalEntry> },
     SendTo {
         id: String,
         src_agent: String,
@@ -1,9 +1,9 @@
 use super::ast;
 use super::crypto;
+use super::diagnostic;
 use super::error::{Error, to_ioerror};
-use super::grammar;
 use super::runtime;
-use super::value::{Message, Obj};
+use super::value::{Message, Obj, Schemas};
 
 mod agent;
 mod api;
@@ -11,8 +11,10 @@ mod client;
 mod server;
 mod token;
 
-pub use self::agent::Agent;
-pub use self::api::{AgentRole, Request, Response};
+pub use self::agent::{Agent, AgentStorage, DurableAgentStorage, Instance, MemAgentStorage};
+pub use self::api::{AgentRole, MatchStatus, Request, Response};
 pub use self::client::Client;
 pub use self::server::Server;
 pub use self::token::{DurableTokenStorage, Token, TokenStorage};
+
+mod test_agent;
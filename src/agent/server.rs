@@ -15,14 +15,14 @@ use std::os::unix::fs::DirBuilderExt;
 use std::sync::{Arc, Mutex};
 
 use self::futures::{Future, Stream, Sink};
-use self::futures::sync::mpsc;
+use self::futures::sync::{mpsc, oneshot};
 use self::itertools::Itertools;
 use self::tokio_io::AsyncRead;
 use self::tokio_service::Service as TokioService;
 
 use super::*;
-use self::agent::{AgentStorage, DurableAgentStorage};
-use self::api::{Authenticated, Request, Response};
+use self::agent::{AgentStorage, DurableAgentStorage, Instance};
+use self::api::{Authenticated, MatchStatus, Request, Response};
 use self::token::{DurableTokenStorage, TOKEN_NAME_LEN, TokenStorage};
 
 pub struct ServiceCodec;
@@ -86,6 +86,12 @@ type AgentSenderMap = HashMap<String, mpsc::Sender<Message>>;
 struct ServiceState<S: AgentStorage + Send + 'static> {
     storage: S,
     local_senders: AgentSenderMap,
+    /// Topic schemas declared by each local agent, checked on messages delivered to it.
+    schemas: HashMap<String, Schemas>,
+    /// Status of the matches of each local agent.
+    match_status: HashMap<String, Arc<Mutex<Vec<MatchStatus>>>>,
+    /// Completes when the agent last started under each name has stopped.
+    stopped: HashMap<String, oneshot::Receiver<()>>,
 }
 
 impl<S: AgentStorage + Send> ServiceState<S> {
@@ -93,6 +99,9 @@ impl<S: AgentStorage + Send> ServiceState<S> {
         ServiceState {
             storage: storage,
             local_senders: AgentSenderMap::new(),
+            schemas: HashMap::new(),
+            match_status: HashMap::new(),
+            stopped: HashMap::new(),
         }
     }
 
@@ -103,13 +112,30 @@ impl<S: AgentStorage + Send> ServiceState<S> {
     fn remove(&mut self, name: &str) -> Result<(), Error> {
         self.storage.remove_agent(name)?;
         self.local_senders.remove(name);
+        self.schemas.remove(name);
+        self.match_status.remove(name);
         Ok(())
     }
 
+    /// Check a message against the schemas declared by its local destination agent.
+    fn check_msg(&self, msg: &Message) -> Result<(), Error> {
+        match self.schemas.get(&msg.dst_agent) {
+            Some(schemas) => schemas.check(&msg.topic, &msg.contents),
+            None => Ok(()),
+        }
+    }
+
     fn add_all_agents(&mut self, svc: &Service<S>) -> Result<(), Error> {
         let agents = self.storage.agents()?;
         for (name, glop) in agents {
-            svc.spawn_agent(&name, &glop, self)?;
+            svc.spawn_agent(&name, &glop, Obj::new(), self)?;
+        }
+        let templates = self.storage.templates()?;
+        for (name, instance) in self.storage.instances()? {
+            match templates.get(&instance.template) {
+                Some(glop) => svc.spawn_agent(&name, glop, instance.params, self)?,
+                None => warn!("agent {} template {} not found", name, instance.template),
+            }
         }
         Ok(())
     }
@@ -122,7 +148,7 @@ pub struct Service<S: AgentStorage + Send + 'static> {
     pool: futures_cpupool::CpuPool,
 }
 
-impl<S: AgentStorage + Send> Service<S> {
+impl<S: AgentStorage + Send + 'static> Service<S> {
     pub fn new(storage: S, h: &tokio_core::reactor::Handle) -> Result<Service<S>, Error> {
         let svc = Service {
             state: Arc::new(Mutex::new(ServiceState::new(storage))),
@@ -141,7 +167,11 @@ impl<S: AgentStorage + Send> Service<S> {
                           contents: &str,
                           state: &mut ServiceState<S>)
                           -> Result<(), Error> {
-        let glop = grammar::glop(contents).map_err(Error::Parse)?;
+        let glop = diagnostic::parse(name, contents).map_err(Error::Diagnostics)?;
+        if !glop.includes.is_empty() {
+            return Err(Error::InvalidArgument("includes must be resolved before adding an agent"
+                .to_string()));
+        }
         self.add_agent(name, glop, state)
     }
 
@@ -150,13 +180,114 @@ impl<S: AgentStorage + Send> Service<S> {
                  glop: ast::Glop,
                  state: &mut ServiceState<S>)
                  -> Result<(), Error> {
-        self.spawn_agent(name, &glop, state)?;
+        self.spawn_agent(name, &glop, Obj::new(), state)?;
         state.storage.add_agent(name.to_string(), glop)
     }
 
+    fn parse_template(&self, name: &str, contents: &str) -> Result<ast::Glop, Error> {
+        let glop = diagnostic::parse(name, contents).map_err(Error::Diagnostics)?;
+        if !glop.includes.is_empty() {
+            return Err(Error::InvalidArgument("includes must be resolved before adding a template"
+                .to_string()));
+        }
+        Ok(glop)
+    }
+
+    fn instantiate(&self,
+                   name: &str,
+                   template: &str,
+                   params: Obj,
+                   state: &mut ServiceState<S>)
+                   -> Result<(), Error> {
+        let glop = match state.storage.templates()?.remove(template) {
+            Some(glop) => glop,
+            None => return Err(Error::InvalidArgument(format!("template {} not found", template))),
+        };
+        self.spawn_agent(name, &glop, params.clone(), state)?;
+        state.storage.add_instance(name.to_string(),
+                                   Instance {
+                                       template: template.to_string(),
+                                       params: params,
+                                   })
+    }
+
+    /// Replace a template, restarting its instances with the new source if requested.
+    ///
+    /// Restarted instances keep their state; they are not sent another init message.
+    fn update_template(&self,
+                       name: &str,
+                       glop: ast::Glop,
+                       update_instances: bool,
+                       state: &mut ServiceState<S>)
+                       -> Result<Vec<String>, Error> {
+        if !state.storage.templates()?.contains_key(name) {
+            return Err(Error::InvalidArgument(format!("template {} not found", name)));
+        }
+        state.storage.add_template(name.to_string(), glop.clone())?;
+        let mut updated = vec![];
+        if !update_instances {
+            return Ok(updated);
+        }
+        for (instance_name, instance) in state.storage.instances()? {
+            if instance.template != name {
+                continue;
+            }
+            // Dropping the sender stops the running agent.
+            state.local_senders.remove(&instance_name);
+            self.spawn_agent(&instance_name, &glop, instance.params, state)?;
+            updated.push(instance_name);
+        }
+        Ok(updated)
+    }
+
+    /// Start an agent. Messages may be sent to it right away, but if an agent of the same name
+    /// is still stopping, the new one only starts once it has stopped, as they share state.
     fn spawn_agent(&self,
                    name: &str,
                    glop: &ast::Glop,
+                   init: Obj,
+                   state: &mut ServiceState<S>)
+                   -> Result<(), Error> {
+        let (sender, receiver) = mpsc::channel(10);
+        let (stopped_tx, stopped_rx) = oneshot::channel();
+        let previous = state.stopped.insert(name.to_string(), stopped_rx);
+        state.local_senders.insert(name.to_string(), sender);
+        state.schemas.insert(name.to_string(), Schemas::from_ast(&glop.topics));
+        let previous = match previous {
+            Some(previous) => previous,
+            None => {
+                let result = self.start_agent(name, glop, init, receiver, stopped_tx, state);
+                if result.is_err() {
+                    state.local_senders.remove(name);
+                    state.schemas.remove(name);
+                    state.stopped.remove(name);
+                }
+                return result;
+            }
+        };
+        let svc = Service {
+            state: self.state.clone(),
+            handle: self.handle.clone(),
+            pool: self.pool.clone(),
+        };
+        let name = name.to_string();
+        let glop = glop.clone();
+        self.handle.spawn(previous.then(move |_| {
+            let mut state = svc.state.lock().unwrap();
+            if let Err(e) = svc.start_agent(&name, &glop, init, receiver, stopped_tx, &mut state) {
+                error!("failed to start agent {}: {}", name, e);
+            }
+            Ok(())
+        }));
+        Ok(())
+    }
+
+    fn start_agent(&self,
+                   name: &str,
+                   glop: &ast::Glop,
+                   init: Obj,
+                   receiver: mpsc::Receiver<Message>,
+                   stopped: oneshot::Sender<()>,
                    state: &mut ServiceState<S>)
                    -> Result<(), Error> {
         let runtime_st = state.storage
@@ -166,9 +297,8 @@ impl<S: AgentStorage + Send> Service<S> {
                            remote: self.handle.remote().clone(),
                            state: self.state.clone(),
                        }) as Box<runtime::Outbox + Send>)?;
-        let (sender, receiver) = mpsc::channel(10);
-        let agent = Agent::new(glop, runtime_st, receiver)?;
-        state.local_senders.insert(name.to_string(), sender);
+        let agent = Agent::new(glop, runtime_st, init, receiver, self.handle.remote().clone())?;
+        state.match_status.insert(name.to_string(), agent.status());
         self.handle
             .spawn(self.pool
                 .spawn(agent.for_each(|_| Ok(()))
@@ -176,7 +306,10 @@ impl<S: AgentStorage + Send> Service<S> {
                         error!("{}", e);
                         Err(e)
                     })
-                    .then(|_| Ok(()))));
+                    .then(move |_| {
+                        let _ = stopped.send(());
+                        Ok(())
+                    })));
         Ok(())
     }
 
@@ -196,10 +329,75 @@ impl<S: AgentStorage + Send> Service<S> {
                 state.remove(name)?;
                 Response::Remove
             }
+            Request::AddTemplate { ref contents, ref name } => {
+                let mut state = self.state.lock().unwrap();
+                if state.storage.templates()?.contains_key(name) {
+                    return Ok(Response::Error(format!("template {} already added", name)));
+                }
+                let glop = self.parse_template(name, contents)?;
+                state.storage.add_template(name.to_string(), glop)?;
+                Response::AddTemplate
+            }
+            Request::UpdateTemplate { ref contents, ref name, update_instances } => {
+                let mut state = self.state.lock().unwrap();
+                let glop = self.parse_template(name, contents)?;
+                let instances = self.update_template(name, glop, update_instances, &mut state)?;
+                Response::UpdateTemplate { instances: instances }
+            }
+            Request::RemoveTemplate { ref name } => {
+                let mut state = self.state.lock().unwrap();
+                let mut instances = state.storage
+                    .instances()?
+                    .into_iter()
+                    .filter(|&(_, ref instance)| &instance.template == name)
+                    .map(|(instance_name, _)| instance_name)
+                    .collect::<Vec<_>>();
+                instances.sort();
+                if !instances.is_empty() {
+                    return Ok(Response::Error(format!("template {} in use by {}",
+                                                      name,
+                                                      instances.join(", "))));
+                }
+                state.storage.remove_template(name)?;
+                Response::RemoveTemplate
+            }
+            Request::ListTemplates => {
+                let state = self.state.lock().unwrap();
+                Response::ListTemplates { names: state.storage.templates()?.keys().cloned().collect() }
+            }
+            Request::Instantiate { ref name, ref template, ref params } => {
+                let mut state = self.state.lock().unwrap();
+                if state.has_agent(name) {
+                    return Ok(Response::Error(format!("agent {} already added", name)));
+                }
+                self.instantiate(name, template, params.clone(), &mut state)?;
+                Response::Instantiate
+            }
             Request::List => {
                 let state = self.state.lock().unwrap();
                 Response::List { names: state.local_senders.keys().cloned().collect() }
             }
+            Request::Schema { ref name } => {
+                let state = self.state.lock().unwrap();
+                match state.schemas.get(name) {
+                    Some(schemas) => Response::Schema { topics: schemas.topics() },
+                    None => return Ok(Response::Error(format!("agent {} not found", name))),
+                }
+            }
+            Request::Matches { ref name } => {
+                let state = self.state.lock().unwrap();
+                match state.match_status.get(name) {
+                    Some(status) => Response::Matches { matches: status.lock().unwrap().clone() },
+                    None => return Ok(Response::Error(format!("agent {} not found", name))),
+                }
+            }
+            Request::History { ref name, since_seq } => {
+                let state = self.state.lock().unwrap();
+                if !state.has_agent(name) {
+                    return Ok(Response::Error(format!("agent {} not found", name)));
+                }
+                Response::History { entries: state.storage.journal(name, since_seq)? }
+            }
             Request::SendTo(msg) => self.send_to(msg.src_remote(&req.auth_id)),
             Request::Introduce(agent_roles) => {
                 let mut result = vec![];
@@ -232,11 +430,11 @@ impl<S: AgentStorage + Send> Service<S> {
     }
 
     fn send_to(&self, msg: Message) -> Response {
-        if let Some(sender) = self.state
-            .lock()
-            .unwrap()
-            .local_senders
-            .get(&msg.dst_agent) {
+        let state = self.state.lock().unwrap();
+        if let Err(e) = state.check_msg(&msg) {
+            return Response::Error(format!("{}", e));
+        }
+        if let Some(sender) = state.local_senders.get(&msg.dst_agent) {
             let resp = Response::SendTo {
                 id: msg.id.to_string(),
                 src_agent: msg.src_agent.to_string(),
@@ -264,6 +462,7 @@ impl<S: AgentStorage + Send> runtime::Outbox for SenderOutbox<S> {
         match msg.dst_remote.clone() {
             Some(_) => state.storage.push_remote_msg(msg),
             None => {
+                state.check_msg(&msg)?;
                 let sender = match state.local_senders.get(&msg.dst_agent) {
                     Some(s) => s.clone(),
                     None => return Err(Error::InvalidArgument(msg.dst_agent.to_string())),
@@ -286,6 +485,10 @@ impl<S: AgentStorage + Send> TokioService for Service<S> {
     fn call(&self, req: Self::Request) -> Self::Future {
         match self.do_call(req) {
             Ok(res) => futures::future::ok(res).boxed(),
+            // Source errors are reported as-is, so they read the same as from the command line.
+            Err(e @ Error::Diagnostics(_)) => {
+                futures::future::ok(Response::Error(format!("{}", e))).boxed()
+            }
             Err(e) => {
                 futures::future::ok(Response::Error(format!("agent service error: {}", e))).boxed()
             }
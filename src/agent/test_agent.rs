@@ -0,0 +1,540 @@
+#![cfg(test)]
+
+extern crate futures;
+extern crate textnonce;
+extern crate tokio_core;
+extern crate tokio_service;
+
+use std;
+use std::io::Write;
+
+use self::futures::{Async, Future, Sink};
+use self::futures::executor::{self, Notify, Spawn};
+use self::futures::sync::mpsc;
+use self::tokio_service::Service as TokioService;
+
+use super::*;
+use super::super::cleanup;
+use super::super::source;
+use super::super::value::Value;
+use self::api::Authenticated;
+use self::runtime::Storage;
+use self::server::Service;
+
+fn test_dir() -> (String, cleanup::Cleanup) {
+    let mut path_buf = std::env::temp_dir();
+    path_buf.push(textnonce::TextNonce::sized_urlsafe(32)
+                      .unwrap()
+                      .into_string());
+    let path = path_buf.to_str().unwrap().to_string();
+    std::fs::create_dir_all(&path).unwrap();
+    (path.clone(), cleanup::Cleanup::Dir(path))
+}
+
+fn write_file(dir: &str, name: &str, contents: &str) -> String {
+    let path = std::path::PathBuf::from(dir).join(name);
+    let mut f = std::fs::File::create(&path).unwrap();
+    f.write_all(contents.as_bytes()).unwrap();
+    path.to_str().unwrap().to_string()
+}
+
+fn call<S: AgentStorage + Send>(svc: &Service<S>, req: Request) -> Response {
+    svc.call(Authenticated {
+                 auth_id: "test".to_string(),
+                 item: req,
+             })
+        .wait()
+        .unwrap()
+}
+
+#[test]
+fn add_bundled_source() {
+    let (dir, _cleanup) = test_dir();
+    write_file(&dir,
+               "status.glop",
+               "when (message status, status.level > 1) { var set alert true; }");
+    let main = write_file(&dir,
+                          "main.glop",
+                          "include \"status.glop\";\nwhen (message init) { var set ready true; }");
+    let glop = source::load(&main).unwrap();
+    let contents = format!("{}", glop);
+    // The server parses the bundle the same way it was loaded.
+    let parsed = diagnostic::parse("main", &contents).unwrap();
+    assert_eq!(format!("{}", parsed), contents);
+
+    let core = tokio_core::reactor::Core::new().unwrap();
+    let svc = Service::new(MemAgentStorage::new(), &core.handle()).unwrap();
+    match call(&svc,
+               Request::Add {
+                   contents: contents,
+                   name: "main".to_string(),
+               }) {
+        Response::Add => {}
+        resp => panic!("unexpected response {:?}", resp),
+    }
+    match call(&svc, Request::List) {
+        Response::List { ref names } => assert_eq!(names, &vec!["main".to_string()]),
+        resp => panic!("unexpected response {:?}", resp),
+    }
+}
+
+/// Run the reactor until `f` holds, failing after a few seconds.
+fn run_until<F>(core: &mut tokio_core::reactor::Core, f: F)
+    where F: Fn() -> bool
+{
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+    while !f() {
+        assert!(std::time::Instant::now() < deadline, "timed out");
+        core.turn(Some(std::time::Duration::from_millis(10)));
+    }
+}
+
+fn test_glop(src: &str) -> ast::Glop {
+    diagnostic::parse("test", src).unwrap()
+}
+
+struct TestClock(std::sync::Arc<std::sync::Mutex<u64>>);
+
+impl runtime::Clock for TestClock {
+    fn now(&self) -> u64 {
+        *self.0.lock().unwrap()
+    }
+}
+
+/// Counts the times an agent's task is woken up.
+struct CountNotify(std::sync::Mutex<usize>);
+
+impl Notify for CountNotify {
+    fn notify(&self, _id: usize) {
+        *self.0.lock().unwrap() += 1;
+    }
+}
+
+/// An agent run by hand, with a clock set by the test.
+struct TestAgent {
+    core: tokio_core::reactor::Core,
+    agent: Spawn<Agent<runtime::MemStorage>>,
+    sender: mpsc::Sender<Message>,
+    now: std::sync::Arc<std::sync::Mutex<u64>>,
+    notify: std::sync::Arc<CountNotify>,
+}
+
+impl TestAgent {
+    fn new(src: &str, now: u64) -> TestAgent {
+        TestAgent::new_pending(src, now, vec![])
+    }
+
+    /// An agent started with messages already pending.
+    fn new_pending(src: &str, now: u64, msgs: Vec<Message>) -> TestAgent {
+        let core = tokio_core::reactor::Core::new().unwrap();
+        let now = std::sync::Arc::new(std::sync::Mutex::new(now));
+        let mut st = runtime::State::new("test", runtime::MemStorage::new());
+        for msg in msgs {
+            st.mut_storage().push_msg(msg).unwrap();
+        }
+        st.set_clock(Box::new(TestClock(now.clone())));
+        let (sender, receiver) = mpsc::channel(10);
+        let agent = Agent::new(&test_glop(src), st, Obj::new(), receiver, core.remote()).unwrap();
+        TestAgent {
+            core: core,
+            agent: executor::spawn(agent),
+            sender: sender,
+            now: now,
+            notify: std::sync::Arc::new(CountNotify(std::sync::Mutex::new(0))),
+        }
+    }
+
+    /// Poll the agent until it parks, returning the number of matches fired.
+    fn run(&mut self) -> usize {
+        let mut fired = 0;
+        loop {
+            match self.agent.poll_stream_notify(&self.notify, 0).unwrap() {
+                Async::Ready(Some(())) => fired += 1,
+                Async::Ready(None) => panic!("agent stopped"),
+                Async::NotReady => return fired,
+            }
+        }
+    }
+
+    fn send(&mut self, topic: &str) {
+        let msg = Message::new(topic, Obj::new()).src_agent("test").dst_agent("test");
+        self.sender.clone().send(msg).wait().unwrap();
+    }
+
+    fn notified(&self) -> usize {
+        *self.notify.0.lock().unwrap()
+    }
+
+    /// Times each match has been evaluated.
+    fn evaluated(&self) -> Vec<u64> {
+        let status = self.agent.get_ref().status();
+        let status = status.lock().unwrap();
+        status.iter().map(|m| m.evaluated).collect()
+    }
+
+    /// Turn the reactor for a while, so any timers due in that time fire.
+    fn idle(&mut self, millis: u64) {
+        let deadline = std::time::Instant::now() + std::time::Duration::from_millis(millis);
+        while std::time::Instant::now() < deadline {
+            self.core.turn(Some(std::time::Duration::from_millis(10)));
+        }
+    }
+}
+
+#[test]
+fn agent_parks_idle() {
+    let mut agent = TestAgent::new("when (message init) { }\n\nwhen (message ping) { }", 1000);
+    assert_eq!(agent.run(), 1);
+    // Nothing is evaluated again until something changes, and nothing wakes the agent.
+    assert_eq!(agent.evaluated(), vec![1, 0]);
+    assert_eq!(agent.run(), 0);
+    agent.idle(200);
+    assert_eq!(agent.notified(), 0);
+    assert_eq!(agent.evaluated(), vec![1, 0]);
+}
+
+#[test]
+fn agent_wakes_on_message() {
+    let mut agent = TestAgent::new(r#"when (message init) { }
+
+when (message ping, is_set never) { }
+
+when (message config.*, is_set never) { }
+
+when (is_set never) { }
+"#,
+                                   1000);
+    assert_eq!(agent.run(), 1);
+    // Matches without their messages are not evaluated.
+    assert_eq!(agent.evaluated(), vec![1, 0, 0, 1]);
+
+    // Only the matches filtering on a message's topic are evaluated when it arrives.
+    agent.send("ping");
+    assert_eq!(agent.notified(), 1);
+    assert_eq!(agent.run(), 0);
+    assert_eq!(agent.evaluated(), vec![1, 1, 0, 1]);
+    agent.send("config.db");
+    assert_eq!(agent.run(), 0);
+    assert_eq!(agent.evaluated(), vec![1, 1, 1, 1]);
+}
+
+#[test]
+fn agent_wakes_for_timer() {
+    let mut agent = TestAgent::new("when (message init) { }\n\nwhen (elapsed 1s) { }", 1000);
+    // The timer has never fired, so it fires at once.
+    assert_eq!(agent.run(), 2);
+    let evaluated = agent.evaluated();
+    assert_eq!(agent.run(), 0);
+    assert_eq!(agent.evaluated(), evaluated);
+
+    // The agent is woken up when the timer is next due, a second later.
+    let notify = agent.notify.clone();
+    run_until(&mut agent.core, || *notify.0.lock().unwrap() > 0);
+    *agent.now.lock().unwrap() = 1001;
+    assert_eq!(agent.run(), 1);
+}
+
+#[test]
+fn agent_timer_retry() {
+    // A due timer whose other conditions don't hold waits for them to change.
+    let mut agent = TestAgent::new("when (every 1s, is_set never) { }", 1000);
+    assert_eq!(agent.run(), 0);
+    agent.idle(1500);
+    assert_eq!(agent.notified(), 0);
+    assert_eq!(agent.evaluated(), vec![1]);
+
+    // Check scripts may hold later without anything changing, so they are retried.
+    let mut agent = TestAgent::new("when (every 1s, check #!/bin/sh\nexit 1\n!#) { }", 1000);
+    assert_eq!(agent.run(), 0);
+    let notify = agent.notify.clone();
+    run_until(&mut agent.core, || *notify.0.lock().unwrap() > 0);
+    *agent.now.lock().unwrap() = 1001;
+    assert_eq!(agent.run(), 0);
+    assert_eq!(agent.evaluated(), vec![2]);
+}
+
+#[test]
+fn agent_untried_retry() {
+    // Messages further back than one evaluation gets to are tried on the next.
+    let msgs = (0..150)
+        .map(|n| {
+            let text = if n < 149 { "goodbye" } else { "hello" };
+            let contents = [("text".to_string(), Value::from_str(text))].iter().cloned().collect();
+            Message::new("ping", contents)
+        })
+        .collect();
+    let mut agent =
+        TestAgent::new_pending("when (message ping, ping.text == hello) { }", 1000, msgs);
+    assert_eq!(agent.run(), 0);
+    assert_eq!(agent.evaluated(), vec![1]);
+    let notify = agent.notify.clone();
+    run_until(&mut agent.core, || *notify.0.lock().unwrap() > 0);
+    *agent.now.lock().unwrap() = 1001;
+    assert_eq!(agent.run(), 1);
+    // Once more after firing, for the messages still pending.
+    assert_eq!(agent.evaluated(), vec![3]);
+}
+
+#[test]
+fn mem_agent_storage() {
+    agent_storage(MemAgentStorage::new());
+}
+
+#[test]
+fn durable_agent_storage() {
+    let (dir, _cleanup) = test_dir();
+    agent_storage(DurableAgentStorage::new(&dir));
+}
+
+fn agent_storage<S: AgentStorage>(storage: S) {
+    let mut storage = storage;
+    let glop = test_glop("when (message init) { }");
+    storage.add_template("web".to_string(), glop.clone()).unwrap();
+    storage.add_template("db".to_string(), glop.clone()).unwrap();
+    let mut templates = storage.templates().unwrap().keys().cloned().collect::<Vec<_>>();
+    templates.sort();
+    assert_eq!(templates, vec!["db", "web"]);
+    storage.remove_template("db").unwrap();
+    assert_eq!(storage.templates().unwrap().keys().collect::<Vec<_>>(), vec!["web"]);
+
+    storage.add_agent("solo".to_string(), glop.clone()).unwrap();
+    let instance = Instance {
+        template: "web".to_string(),
+        params: Obj::new(),
+    };
+    storage.add_instance("web1".to_string(), instance.clone()).unwrap();
+    assert_eq!(storage.instances().unwrap()["web1"].template, "web");
+    // Agents and instances share one namespace.
+    match storage.add_instance("web1".to_string(), instance.clone()) {
+        Err(Error::AgentExists(ref name)) => assert_eq!(name, "web1"),
+        _ => panic!("expected existing agent"),
+    }
+    match storage.add_instance("solo".to_string(), instance.clone()) {
+        Err(Error::AgentExists(ref name)) => assert_eq!(name, "solo"),
+        _ => panic!("expected existing agent"),
+    }
+    match storage.add_agent("web1".to_string(), glop.clone()) {
+        Err(Error::AgentExists(ref name)) => assert_eq!(name, "web1"),
+        _ => panic!("expected existing agent"),
+    }
+    storage.remove_agent("web1").unwrap();
+    assert!(storage.instances().unwrap().is_empty());
+    assert_eq!(storage.agents().unwrap().keys().collect::<Vec<_>>(), vec!["solo"]);
+}
+
+#[test]
+fn durable_agents_saved_with_acting_role() {
+    // Matches were saved with a single `acting_role` before they could act in several.
+    let (dir, _cleanup) = test_dir();
+    write_file(&dir,
+               "agents.json",
+               r#"{"old": {"comments": [], "includes": [], "topics": [], "trailing_comments": [],
+                   "matches": [{
+                       "conditions": [{"Message": {"topic": "query", "src_role": null,
+                                                   "acting_role": "server"}}],
+                       "actions": [{"ReplyMsg": {"src_topic": "query", "topic": "result",
+                                                 "contents": []}}],
+                       "acting_role": "server",
+                       "else_actions": [],
+                       "comments": [],
+                       "span": {"start": 0, "end": 80}}]}}"#);
+    let storage = DurableAgentStorage::new(&dir);
+    let agents = storage.agents().unwrap();
+    let m = runtime::Match::new_at(&agents["old"].matches[0], 0);
+    assert_eq!(m.acting_role, Ok(Some("server".to_string())));
+    match m.actions[0] {
+        runtime::Action::ReplyTo { ref src_role, .. } => {
+            assert_eq!(src_role, &Ok(Some("server".to_string())))
+        }
+        _ => panic!("expected reply"),
+    }
+}
+
+#[test]
+fn durable_agents_saved_by_baseline() {
+    // Conditions and var sets took plain var names and string values before refs, typed
+    // literals and expressions.
+    let (dir, _cleanup) = test_dir();
+    write_file(&dir,
+               "agents.json",
+               r##"{"old": {"matches": [{
+                   "conditions": [
+                       {"Message": {"topic": "init", "src_role": null, "acting_role": "server"}},
+                       {"Cmp": [["installed"], "Equal", "false"]},
+                       {"IsSet": ["mode"]},
+                       {"IsUnset": ["busy"]}],
+                   "actions": [
+                       {"SetVar": [["installed"], "true"]},
+                       {"UnsetVar": ["busy"]},
+                       {"Match": {
+                           "conditions": [{"Cmp": [["initialized"], "NotEqual", "yes"]}],
+                           "actions": [{"SetVar": [["initialized"], "yes"]}],
+                           "acting_role": null}},
+                       {"Script": "#!/bin/bash\necho hi\n"}],
+                   "acting_role": "server"}]}}"##);
+    let storage = DurableAgentStorage::new(&dir);
+    let agents = storage.agents().unwrap();
+    assert_eq!(format!("{}", agents["old"]),
+               r#"when (message init as server, installed == "false", is_set mode, is_unset busy) {
+    var set installed "true";
+    var unset busy;
+    when (initialized != yes) {
+        var set initialized yes;
+    }
+    script #!/bin/bash
+echo hi
+!#
+}
+
+"#);
+    let m = runtime::Match::new_at(&agents["old"].matches[0], 0);
+    assert_eq!(m.acting_role, Ok(Some("server".to_string())));
+}
+
+fn match_names<S: AgentStorage + Send>(svc: &Service<S>, name: &str) -> Vec<String> {
+    match call(svc, Request::Matches { name: name.to_string() }) {
+        Response::Matches { ref matches } => {
+            matches.iter().map(|m| format!("{} {}", m.name, m.fired)).collect()
+        }
+        _ => vec![],
+    }
+}
+
+fn send_ping<S: AgentStorage + Send>(svc: &Service<S>, name: &str) {
+    match call(svc,
+               Request::SendTo(Message::new("ping", Obj::new()).src_agent("test").dst_agent(name))) {
+        Response::SendTo { .. } => {}
+        resp => panic!("unexpected response {:?}", resp),
+    }
+}
+
+fn history<S: AgentStorage + Send>(svc: &Service<S>, name: &str, since_seq: i32) -> Response {
+    call(svc,
+         Request::History {
+             name: name.to_string(),
+             since_seq: since_seq,
+         })
+}
+
+fn history_seqs<S: AgentStorage + Send>(svc: &Service<S>,
+                                        name: &str,
+                                        since_seq: i32)
+                                        -> Vec<i32> {
+    match history(svc, name, since_seq) {
+        Response::History { ref entries } => entries.iter().map(|e| e.seq).collect(),
+        resp => panic!("unexpected response {:?}", resp),
+    }
+}
+
+#[test]
+fn mem_server_history() {
+    server_history(MemAgentStorage::new());
+}
+
+#[test]
+fn durable_server_history() {
+    let (dir, _cleanup) = test_dir();
+    server_history(DurableAgentStorage::new(&dir));
+}
+
+fn server_history<S: AgentStorage + Send + 'static>(storage: S) {
+    let mut core = tokio_core::reactor::Core::new().unwrap();
+    let svc = Service::new(storage, &core.handle()).unwrap();
+    match call(&svc,
+               Request::Add {
+                   contents: r#"when "pinged" (message ping) { var set pinged true; }"#.to_string(),
+                   name: "main".to_string(),
+               }) {
+        Response::Add => {}
+        resp => panic!("unexpected response {:?}", resp),
+    }
+    assert!(history_seqs(&svc, "main", 0).is_empty());
+    for _ in 0..2 {
+        send_ping(&svc, "main");
+    }
+    run_until(&mut core, || history_seqs(&svc, "main", 0).len() == 2);
+    match history(&svc, "main", 0) {
+        Response::History { ref entries } => {
+            assert_eq!(entries[0].seq, 0);
+            assert_eq!(entries[0].msgs.len(), 1);
+            match entries[0].actions[0] {
+                runtime::JournalAction::SetVar { ref name, .. } => assert_eq!(name, "pinged"),
+                ref a => panic!("unexpected action {:?}", a),
+            }
+        }
+        resp => panic!("unexpected response {:?}", resp),
+    }
+    assert_eq!(history_seqs(&svc, "main", 1), vec![1]);
+    match history(&svc, "nope", 0) {
+        Response::Error(ref msg) => assert_eq!(msg, "agent nope not found"),
+        resp => panic!("unexpected response {:?}", resp),
+    }
+}
+
+#[test]
+fn server_templates() {
+    let (dir, _cleanup) = test_dir();
+    let mut core = tokio_core::reactor::Core::new().unwrap();
+    let svc = Service::new(DurableAgentStorage::new(&dir), &core.handle()).unwrap();
+    let add = call(&svc,
+                   Request::AddTemplate {
+                       contents: r#"when "v1" (message ping) { }"#.to_string(),
+                       name: "web".to_string(),
+                   });
+    assert!(match add {
+                Response::AddTemplate => true,
+                _ => false,
+            });
+    for name in &["web1", "web2"] {
+        match call(&svc,
+                   Request::Instantiate {
+                       name: name.to_string(),
+                       template: "web".to_string(),
+                       params: Obj::new(),
+                   }) {
+            Response::Instantiate => {}
+            resp => panic!("unexpected response {:?}", resp),
+        }
+    }
+    send_ping(&svc, "web1");
+    run_until(&mut core, || match_names(&svc, "web1") == vec!["\"v1\" 1"]);
+
+    match call(&svc,
+               Request::UpdateTemplate {
+                   contents: r#"when "v2" (message ping) { }"#.to_string(),
+                   name: "web".to_string(),
+                   update_instances: true,
+               }) {
+        Response::UpdateTemplate { ref instances } => {
+            let mut instances = instances.clone();
+            instances.sort();
+            assert_eq!(instances, vec!["web1", "web2"]);
+        }
+        resp => panic!("unexpected response {:?}", resp),
+    }
+    // Messages sent while an instance restarts are handled by the new source, once.
+    send_ping(&svc, "web1");
+    run_until(&mut core, || match_names(&svc, "web1") == vec!["\"v2\" 1"]);
+    run_until(&mut core, || match_names(&svc, "web2") == vec!["\"v2\" 0"]);
+
+    // Templates in use can't be removed.
+    match call(&svc, Request::RemoveTemplate { name: "web".to_string() }) {
+        Response::Error(ref msg) => assert_eq!(msg, "template web in use by web1, web2"),
+        resp => panic!("unexpected response {:?}", resp),
+    }
+    for name in &["web1", "web2"] {
+        call(&svc, Request::Remove { name: name.to_string() });
+    }
+    match call(&svc, Request::List) {
+        Response::List { ref names } => assert!(names.is_empty()),
+        resp => panic!("unexpected response {:?}", resp),
+    }
+    match call(&svc, Request::RemoveTemplate { name: "web".to_string() }) {
+        Response::RemoveTemplate => {}
+        resp => panic!("unexpected response {:?}", resp),
+    }
+    match call(&svc, Request::ListTemplates) {
+        Response::ListTemplates { ref names } => assert!(names.is_empty()),
+        resp => panic!("unexpected response {:?}", resp),
+    }
+}
@@ -1,52 +1,485 @@
-use std::collections::HashSet;
+extern crate regex;
+
+use std::collections::{HashMap, HashSet};
 use std::ops::Deref;
 
 #[derive(Serialize, Deserialize)]
 #[derive(Clone)]
 pub struct Glop {
+    /// Comments at the top of the source, ahead of any includes.
+    #[serde(default)]
+    pub comments: Vec<String>,
+    /// Paths of included sources, relative to the including file.
+    #[serde(default)]
+    pub includes: Vec<String>,
+    /// Schemas declared for message topics.
+    #[serde(default)]
+    pub topics: Vec<Topic>,
     pub matches: Vec<Match>,
+    /// Comments at the end of the source.
+    #[serde(default)]
+    pub trailing_comments: Vec<String>,
+}
+
+/// Comments on lines of their own, each with the whitespace following it. A blank line after
+/// a comment is kept as an empty line, so that separate paragraphs of comments stay apart.
+pub fn comment_lines(comments: Vec<(String, &str)>) -> Vec<String> {
+    let mut result = vec![];
+    for (c, ws) in comments {
+        result.push(c);
+        if ws.matches('\n').count() > 1 {
+            result.push("".to_string());
+        }
+    }
+    result
+}
+
+/// Location of a node in its source, as byte offsets, which `diagnostic::line_col` turns into
+/// lines and columns. Topics, matches, and the conditions and actions of matches carry a span;
+/// conditions grouped within another are located by the group.
+#[derive(Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+/// A message topic declaration, giving the fields its messages carry.
+#[derive(Serialize, Deserialize)]
+#[derive(Clone, Debug)]
+pub struct Topic {
+    pub name: String,
+    pub fields: Vec<Field>,
+    /// Comments on the lines before the declaration.
+    #[serde(default)]
+    pub comments: Vec<String>,
+    #[serde(default)]
+    pub span: Span,
+}
+
+#[derive(Serialize, Deserialize)]
+#[derive(Clone, Debug)]
+pub struct Field {
+    pub name: String,
+    pub field_type: FieldType,
+    /// Optional fields may be absent from a message; all others are required.
+    pub optional: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq)]
+pub enum FieldType {
+    String,
+    Int,
+    Object,
+}
+
+/// Check that no topic, nor any field within a topic, is declared more than once.
+pub fn check_topics(topics: &Vec<Topic>) -> Result<(), &'static str> {
+    let mut names = HashSet::new();
+    for t in topics {
+        if !names.insert(&t.name) {
+            return Err("topic declared once");
+        }
+        let mut fields = HashSet::new();
+        for field in &t.fields {
+            if !fields.insert(&field.name) {
+                return Err("field declared once per topic");
+            }
+        }
+    }
+    Ok(())
 }
 
 #[derive(Serialize, Deserialize)]
 #[derive(Clone)]
 pub struct Match {
+    /// Name identifying the match in logs and agent status, unique within its source.
+    #[serde(default)]
+    pub name: Option<String>,
+    /// What the match is for.
+    #[serde(default)]
+    pub description: Option<String>,
     pub conditions: Vec<Condition>,
+    /// Location of each condition in `conditions`.
+    #[serde(default)]
+    pub condition_spans: Vec<Span>,
     pub actions: Vec<Action>,
-    pub acting_role: Option<String>,
+    /// Actions taken instead when a nested match's conditions do not hold.
+    #[serde(default)]
+    pub else_actions: Vec<Action>,
+    /// Comments on the lines before a top-level match. Comments before a nested match are
+    /// actions of the enclosing block.
+    #[serde(default)]
+    pub comments: Vec<String>,
+    #[serde(default)]
+    pub span: Span,
+    /// Location of each action other than comments, in `actions` and then `else_actions`.
+    #[serde(default)]
+    pub action_spans: Vec<Span>,
 }
 
 pub fn acting_roles(conditions: &Vec<Condition>) -> HashSet<String> {
-    conditions.iter()
-        .map(|c| {
-            if let &Condition::Message { topic: _, src_role: _, ref acting_role } = c {
-                if let &Some(ref role) = acting_role {
-                    return Some(role.to_string());
-                }
+    let mut result = HashSet::new();
+    for c in conditions {
+        c.collect_acting_roles(&mut result);
+    }
+    result
+}
+
+/// Acting roles of a match and the matches enclosing it, which decide the role its messages
+/// are sent from.
+#[derive(Clone, Debug, Default)]
+pub struct RoleScope {
+    roles: HashSet<String>,
+    /// Acting role of each consumed topic received in one.
+    topic_roles: HashMap<String, String>,
+}
+
+impl RoleScope {
+    /// The scope of a match, within the scope of its enclosing matches.
+    pub fn enter(&self, m: &Match) -> RoleScope {
+        let mut result = self.clone();
+        // Roles come from the conditions rather than being stored with the match, so matches
+        // saved with the single `acting_role` of earlier versions get them too.
+        result.roles.extend(acting_roles(&m.conditions));
+        for c in &m.conditions {
+            c.collect_topic_roles(&mut result.topic_roles);
+        }
+        result
+    }
+
+    /// The role a message is sent from: the one named with `as`, or the only acting role in
+    /// scope. With more than one acting role in scope, the role must be named.
+    pub fn send_role(&self, src_role: &Option<String>) -> Result<Option<String>, &'static str> {
+        if src_role.is_some() {
+            return Ok(src_role.clone());
+        }
+        match self.roles.len() {
+            0 => Ok(None),
+            1 => Ok(self.roles.iter().next().cloned()),
+            _ => Err("`as` role on each send, in a match with more than one acting role"),
+        }
+    }
+
+    /// The role a reply is sent from: the one named with `as`, or the role the message replied
+    /// to was received in, or else as for any other send.
+    pub fn reply_role(&self,
+                      src_topic: &str,
+                      src_role: &Option<String>)
+                      -> Result<Option<String>, &'static str> {
+        if src_role.is_some() {
+            return Ok(src_role.clone());
+        }
+        match self.topic_roles.get(src_topic) {
+            Some(role) => Ok(Some(role.to_string())),
+            None => self.send_role(src_role),
+        }
+    }
+}
+
+/// Check that every message sent from each match has an unambiguous source role.
+pub fn check_roles(matches: &Vec<Match>) -> Result<(), &'static str> {
+    for m in matches {
+        check_match_roles(m, &RoleScope::default())?;
+    }
+    Ok(())
+}
+
+fn check_match_roles(m: &Match, parent_scope: &RoleScope) -> Result<(), &'static str> {
+    let scope = parent_scope.enter(m);
+    check_action_roles(&m.actions, &scope)?;
+    // The else branch only runs when this match's messages were not matched.
+    check_action_roles(&m.else_actions, parent_scope)
+}
+
+fn check_action_roles(actions: &Vec<Action>, scope: &RoleScope) -> Result<(), &'static str> {
+    for a in actions {
+        match a {
+            &Action::SendMsg { ref src_role, .. } => {
+                scope.send_role(src_role)?;
+            }
+            &Action::ReplyMsg { ref src_topic, ref src_role, .. } => {
+                scope.reply_role(src_topic, src_role)?;
+            }
+            &Action::Match(ref m) => check_match_roles(m, scope)?,
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Check that match names, including those of nested matches, are unique.
+pub fn check_match_names(matches: &Vec<Match>) -> Result<(), &'static str> {
+    let mut names = HashSet::new();
+    let mut pending = matches.iter().collect::<Vec<_>>();
+    while let Some(m) = pending.pop() {
+        if let Some(ref name) = m.name {
+            if !names.insert(name) {
+                return Err("unique match name");
+            }
+        }
+        for a in m.actions.iter().chain(m.else_actions.iter()) {
+            if let &Action::Match(ref nested) = a {
+                pending.push(nested);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Resolve the references in each match's conditions.
+///
+/// A reference whose leading segments name a topic consumed by the match, or by an enclosing
+/// match, refers to a field of that message. References to topics consumed elsewhere in the
+/// same file are rejected as ambiguous; anything else refers to a var.
+pub fn resolve_refs(matches: Vec<Match>) -> Result<Vec<Match>, &'static str> {
+    let mut all_topics = HashSet::new();
+    for m in &matches {
+        m.collect_topics(&mut all_topics);
+    }
+    matches.into_iter()
+        .map(|m| m.resolve_refs(&HashSet::new(), &all_topics))
+        .collect()
+}
+
+impl Match {
+    /// Topics of messages this match may consume, including those under `or`.
+    pub fn topics(&self) -> HashSet<String> {
+        let mut result = HashSet::new();
+        for c in &self.conditions {
+            c.collect_topics(false, &mut result);
+        }
+        result
+    }
+
+    fn collect_topics(&self, result: &mut HashSet<String>) {
+        for c in &self.conditions {
+            c.collect_topics(true, result);
+        }
+        for a in self.actions.iter().chain(self.else_actions.iter()) {
+            if let &Action::Match(ref m) = a {
+                m.collect_topics(result);
             }
-            None
+        }
+    }
+
+    fn resolve_refs(self,
+                    parent_scope: &HashSet<String>,
+                    all_topics: &HashSet<String>)
+                    -> Result<Match, &'static str> {
+        let mut scope = parent_scope.clone();
+        scope.extend(self.topics());
+        let conditions = self.conditions
+            .into_iter()
+            .map(|c| c.resolve_refs(&scope, all_topics))
+            .collect::<Result<Vec<_>, _>>()?;
+        let actions = resolve_action_refs(self.actions, &scope, all_topics)?;
+        // The else branch only runs when this match's messages were not matched.
+        let else_actions = resolve_action_refs(self.else_actions, parent_scope, all_topics)?;
+        Ok(Match {
+            name: self.name,
+            description: self.description,
+            conditions: conditions,
+            condition_spans: self.condition_spans,
+            actions: actions,
+            else_actions: else_actions,
+            comments: self.comments,
+            span: self.span,
+            action_spans: self.action_spans,
         })
-        .filter(|maybe_role| if let &Some(_) = maybe_role {
-            true
-        } else {
-            false
+    }
+}
+
+fn resolve_action_refs(actions: Vec<Action>,
+                       scope: &HashSet<String>,
+                       all_topics: &HashSet<String>)
+                       -> Result<Vec<Action>, &'static str> {
+    actions.into_iter()
+        .map(|a| match a {
+            Action::Match(m) => m.resolve_refs(scope, all_topics).map(Action::Match),
+            Action::SetVar(k, v) => Ok(Action::SetVar(k, v.resolve_refs(scope, all_topics)?)),
+            Action::SendMsg { dst_agent, topic, contents, src_role } => {
+                Ok(Action::SendMsg {
+                    dst_agent: dst_agent,
+                    topic: topic,
+                    contents: resolve_contents_refs(contents, scope, all_topics)?,
+                    src_role: src_role,
+                })
+            }
+            Action::ReplyMsg { src_topic, topic, contents, src_role } => {
+                if !scope.contains(&src_topic) {
+                    return Err("reply to a message consumed by this match");
+                }
+                Ok(Action::ReplyMsg {
+                    src_topic: src_topic,
+                    topic: topic,
+                    contents: resolve_contents_refs(contents, scope, all_topics)?,
+                    src_role: src_role,
+                })
+            }
+            _ => Ok(a),
         })
-        .map(|maybe_role| maybe_role.unwrap().to_string())
         .collect()
 }
 
 pub type Identifier = Vec<String>;
 
+/// A reference to a var, or to a field of a message consumed by the match.
+#[derive(Serialize, Deserialize)]
+#[derive(Clone)]
+pub enum Ref {
+    Var(Identifier),
+    Msg(String, Identifier),
+}
+
+impl Ref {
+    fn resolve(self,
+               scope: &HashSet<String>,
+               all_topics: &HashSet<String>)
+               -> Result<Ref, &'static str> {
+        let id = match self {
+            Ref::Var(id) => id,
+            Ref::Msg(_, _) => return Ok(self),
+        };
+        let parts = id.iter()
+            .flat_map(|part| part.split('.'))
+            .map(|part| part.to_string())
+            .collect::<Vec<_>>();
+        // Prefer the longest topic, as topics may themselves contain dots.
+        for n in (1..parts.len() + 1).rev() {
+            let topic = parts[..n].join(".");
+            if scope.contains(&topic) {
+                if n == parts.len() {
+                    return Err("field of message, not the message itself");
+                }
+                return Ok(Ref::Msg(topic, parts[n..].to_vec()));
+            }
+            if all_topics.contains(&topic) {
+                return Err("message topic consumed by this match");
+            }
+        }
+        Ok(Ref::Var(id))
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 #[derive(Clone)]
 pub enum Condition {
-    Cmp(Identifier, CmpOpcode, String),
-    IsSet(Identifier),
-    IsUnset(Identifier),
+    Cmp(Ref, CmpOpcode, Literal),
+    /// Compares two refs. Unset values are equal only to each other, and are not ordered.
+    CmpRef(Ref, CmpOpcode, Ref),
+    IsSet(Ref),
+    IsUnset(Ref),
     Message {
         topic: String,
         src_role: Option<String>,
         acting_role: Option<String>,
     },
+    /// Holds when at least this many seconds have elapsed since the match last fired.
+    Elapsed(u64),
+    /// Holds once per period of this many seconds, aligned to the Unix epoch.
+    Every(u64),
+    /// Holds once a day, at or after the given hour and minute (UTC).
+    At(u32, u32),
+    /// Holds when a script exits successfully. The script may read vars and messages, but not
+    /// change anything, and is stopped after the timeout in seconds, if given.
+    Check {
+        script: String,
+        timeout: Option<u64>,
+    },
+    /// Holds when the condition does not. Messages under `not` are never consumed.
+    Not(Box<Condition>),
+    /// Holds when any of the conditions hold. Only messages in the first branch that holds,
+    /// from left to right, are consumed.
+    Or(Vec<Condition>),
+    /// A parenthesized group of conditions, which holds when all of them hold.
+    And(Vec<Condition>),
+}
+
+impl Condition {
+    fn collect_acting_roles(&self, result: &mut HashSet<String>) {
+        match self {
+            &Condition::Message { acting_role: Some(ref role), .. } => {
+                result.insert(role.to_string());
+            }
+            &Condition::Not(ref c) => c.collect_acting_roles(result),
+            &Condition::Or(ref cs) |
+            &Condition::And(ref cs) => {
+                for c in cs {
+                    c.collect_acting_roles(result);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn collect_topic_roles(&self, result: &mut HashMap<String, String>) {
+        match self {
+            &Condition::Message { ref topic, acting_role: Some(ref role), .. } => {
+                result.insert(topic.to_string(), role.to_string());
+            }
+            &Condition::Or(ref cs) |
+            &Condition::And(ref cs) => {
+                for c in cs {
+                    c.collect_topic_roles(result);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    pub fn has_acting_role(&self) -> bool {
+        let mut roles = HashSet::new();
+        self.collect_acting_roles(&mut roles);
+        !roles.is_empty()
+    }
+
+    fn collect_topics(&self, include_not: bool, result: &mut HashSet<String>) {
+        match self {
+            &Condition::Message { ref topic, .. } => {
+                result.insert(topic.to_string());
+            }
+            &Condition::Not(ref c) => {
+                if include_not {
+                    c.collect_topics(include_not, result);
+                }
+            }
+            &Condition::Or(ref cs) |
+            &Condition::And(ref cs) => {
+                for c in cs {
+                    c.collect_topics(include_not, result);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn resolve_refs(self,
+                    scope: &HashSet<String>,
+                    all_topics: &HashSet<String>)
+                    -> Result<Condition, &'static str> {
+        Ok(match self {
+            Condition::Cmp(l, op, r) => Condition::Cmp(l.resolve(scope, all_topics)?, op, r),
+            Condition::CmpRef(l, op, r) => {
+                Condition::CmpRef(l.resolve(scope, all_topics)?, op, r.resolve(scope, all_topics)?)
+            }
+            Condition::IsSet(k) => Condition::IsSet(k.resolve(scope, all_topics)?),
+            Condition::IsUnset(k) => Condition::IsUnset(k.resolve(scope, all_topics)?),
+            Condition::Not(c) => Condition::Not(Box::new(c.resolve_refs(scope, all_topics)?)),
+            Condition::Or(cs) => {
+                Condition::Or(cs.into_iter()
+                    .map(|c| c.resolve_refs(scope, all_topics))
+                    .collect::<Result<Vec<_>, _>>()?)
+            }
+            Condition::And(cs) => {
+                Condition::And(cs.into_iter()
+                    .map(|c| c.resolve_refs(scope, all_topics))
+                    .collect::<Result<Vec<_>, _>>()?)
+            }
+            _ => self,
+        })
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -54,15 +487,118 @@ pub enum Condition {
 pub enum CmpOpcode {
     Equal,
     NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    Match,
+}
+
+/// A literal value in glop source.
+#[derive(Serialize, Deserialize)]
+#[derive(Clone)]
+pub enum Literal {
+    Int(i32),
+    Str(String),
+    Bool(bool),
+}
+
+pub fn is_valid_regex(pattern: &str) -> bool {
+    regex::Regex::new(pattern).is_ok()
+}
+
+/// Whether a topic in a message condition matches a family of topics rather than just one.
+pub fn is_topic_pattern(topic: &str) -> bool {
+    topic.split('.').any(|part| part == "*" || part == "**")
+}
+
+/// Match a topic against a topic pattern. In a pattern, `*` matches any one name of a
+/// dot-separated topic, and `**` matches one or more names.
+pub fn topic_matches(pattern: &str, topic: &str) -> bool {
+    let pattern = pattern.split('.').collect::<Vec<_>>();
+    let topic = topic.split('.').collect::<Vec<_>>();
+    parts_match(&pattern, &topic)
+}
+
+fn parts_match(pattern: &[&str], topic: &[&str]) -> bool {
+    match (pattern.first(), topic.first()) {
+        (None, None) => true,
+        (Some(&"**"), Some(_)) => {
+            (1..topic.len() + 1).any(|n| parts_match(&pattern[1..], &topic[n..]))
+        }
+        (Some(&p), Some(&t)) if p == "*" || p == t => parts_match(&pattern[1..], &topic[1..]),
+        _ => false,
+    }
 }
 
 #[derive(Serialize, Deserialize)]
 #[derive(Clone)]
 pub enum Action {
-    SetVar(Identifier, String),
+    SetVar(Identifier, Expr),
     UnsetVar(Identifier),
     Script(String),
     Match(Match),
+    /// Send a message, from the role named with `as` if any.
+    SendMsg {
+        dst_agent: String,
+        topic: String,
+        contents: Vec<(Identifier, Expr)>,
+        #[serde(default)]
+        src_role: Option<String>,
+    },
+    /// Reply to the sender of a message consumed by the match.
+    ReplyMsg {
+        src_topic: String,
+        topic: String,
+        contents: Vec<(Identifier, Expr)>,
+        #[serde(default)]
+        src_role: Option<String>,
+    },
+    /// A comment in a block, which does nothing. A trailing comment follows the preceding
+    /// action on the same line.
+    Comment { text: String, trailing: bool },
+}
+
+/// A value computed when an action is applied.
+#[derive(Serialize, Deserialize)]
+#[derive(Clone)]
+pub enum Expr {
+    Literal(Literal),
+    Ref(Ref),
+    BinOp(Box<Expr>, ExprOpcode, Box<Expr>),
+}
+
+#[derive(Serialize, Deserialize)]
+#[derive(Clone)]
+pub enum ExprOpcode {
+    Add,
+    Subtract,
+}
+
+impl Expr {
+    fn resolve_refs(self,
+                    scope: &HashSet<String>,
+                    all_topics: &HashSet<String>)
+                    -> Result<Expr, &'static str> {
+        match self {
+            Expr::Ref(r) => r.resolve(scope, all_topics).map(Expr::Ref),
+            Expr::BinOp(l, op, r) => {
+                Ok(Expr::BinOp(Box::new(l.resolve_refs(scope, all_topics)?),
+                               op,
+                               Box::new(r.resolve_refs(scope, all_topics)?)))
+            }
+            _ => Ok(self),
+        }
+    }
+}
+
+fn resolve_contents_refs(contents: Vec<(Identifier, Expr)>,
+                         scope: &HashSet<String>,
+                         all_topics: &HashSet<String>)
+                         -> Result<Vec<(Identifier, Expr)>, &'static str> {
+    contents.into_iter()
+        .map(|(k, v)| v.resolve_refs(scope, all_topics).map(|v| (k, v)))
+        .collect()
 }
 
 use std::fmt;
@@ -73,27 +609,104 @@ impl fmt::Display for Action {
             &Action::SetVar(ref k, ref v) => write!(f, "var set {} {};", FmtIdentifier(k), v),
             &Action::UnsetVar(ref k) => write!(f, "var unset {};", FmtIdentifier(k)),
             &Action::Script(ref v) => write!(f, r#"script {}!#"#, v),
-            &Action::Match(ref v) => write!(f, "{}", v),
+            &Action::Match(ref v) => v.fmt_indented(f, ""),
+            &Action::SendMsg { ref dst_agent, ref topic, ref contents, ref src_role } => {
+                write!(f,
+                       "msg send {} {}{}{};",
+                       FmtName(dst_agent),
+                       FmtTopic(topic),
+                       FmtContents(contents),
+                       FmtSrcRole(src_role))
+            }
+            &Action::ReplyMsg { ref src_topic, ref topic, ref contents, ref src_role } => {
+                write!(f,
+                       "msg reply {} {}{}{};",
+                       FmtTopic(src_topic),
+                       FmtTopic(topic),
+                       FmtContents(contents),
+                       FmtSrcRole(src_role))
+            }
+            &Action::Comment { ref text, .. } => write!(f, "{}", text),
         }
     }
 }
 
-struct FmtActions<'a>(&'a Vec<Action>);
+struct FmtSrcRole<'a>(&'a Option<String>);
 
-impl<'a> Deref for FmtActions<'a> {
-    type Target = Vec<Action>;
+impl<'a> fmt::Display for FmtSrcRole<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            &Some(ref role) => write!(f, " as {}", FmtName(role)),
+            &None => Ok(()),
+        }
+    }
+}
 
-    fn deref(&self) -> &Vec<Action> {
-        self.0
+struct FmtContents<'a>(&'a Vec<(Identifier, Expr)>);
+
+impl<'a> fmt::Display for FmtContents<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0.is_empty() {
+            return Ok(());
+        }
+        write!(f, " {{ ")?;
+        for (i, &(ref k, ref v)) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{} = {}", FmtIdentifier(k), v)?;
+        }
+        write!(f, " }}")
     }
 }
 
-impl<'a> fmt::Display for FmtActions<'a> {
+impl fmt::Display for Expr {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        for i in self.iter() {
-            try!(writeln!(f, "    {}", i));
+        match self {
+            &Expr::Literal(ref l) => write!(f, "{}", l),
+            _ => write!(f, "${{{}}}", FmtInnerExpr(self)),
+        }
+    }
+}
+
+/// An expression inside `${...}`, where bare words are references rather than strings.
+struct FmtInnerExpr<'a>(&'a Expr);
+
+impl<'a> fmt::Display for FmtInnerExpr<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            &Expr::Literal(Literal::Str(ref s)) => write!(f, "{}", FmtString(s)),
+            &Expr::Literal(ref l) => write!(f, "{}", l),
+            &Expr::Ref(ref r) => write!(f, "{}", r),
+            &Expr::BinOp(ref l, ref op, ref r) => {
+                // Operators are left-associative, so only a compound right operand needs
+                // parentheses.
+                match **r {
+                    Expr::BinOp(_, _, _) => {
+                        write!(f, "{} {} ({})", FmtInnerExpr(l), op, FmtInnerExpr(r))
+                    }
+                    _ => write!(f, "{} {} {}", FmtInnerExpr(l), op, FmtInnerExpr(r)),
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for ExprOpcode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &ExprOpcode::Add => write!(f, "+"),
+            &ExprOpcode::Subtract => write!(f, "-"),
+        }
+    }
+}
+
+impl Action {
+    fn fmt_indented(&self, f: &mut fmt::Formatter, indent: &str) -> fmt::Result {
+        match self {
+            &Action::Match(ref m) => m.fmt_indented(f, indent),
+            _ => write!(f, "{}", self),
         }
-        Ok(())
     }
 }
 
@@ -102,6 +715,33 @@ impl fmt::Display for CmpOpcode {
         match self {
             &CmpOpcode::Equal => write!(f, "=="),
             &CmpOpcode::NotEqual => write!(f, "!="),
+            &CmpOpcode::Less => write!(f, "<"),
+            &CmpOpcode::LessEqual => write!(f, "<="),
+            &CmpOpcode::Greater => write!(f, ">"),
+            &CmpOpcode::GreaterEqual => write!(f, ">="),
+            &CmpOpcode::Match => write!(f, "=~"),
+        }
+    }
+}
+
+impl fmt::Display for Literal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &Literal::Int(i) => write!(f, "{}", i),
+            &Literal::Bool(b) => write!(f, "{}", b),
+            &Literal::Str(ref s) => {
+                // Quote strings that would otherwise read back as another kind of literal.
+                let bare = !s.is_empty() &&
+                           s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') &&
+                           s != "true" && s != "false" &&
+                           s.parse::<i32>().is_err() &&
+                           !s.chars().all(|c| c.is_ascii_digit());
+                if bare {
+                    write!(f, "{}", s)
+                } else {
+                    write!(f, "{}", FmtString(s))
+                }
+            }
         }
     }
 }
@@ -109,19 +749,58 @@ impl fmt::Display for CmpOpcode {
 impl fmt::Display for Condition {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            &Condition::Cmp(ref l, ref op, ref r) => write!(f, "{} {} {}", FmtIdentifier(l), op, r),
-            &Condition::IsSet(ref k) => write!(f, "is_set {}", FmtIdentifier(k)),
-            &Condition::IsUnset(ref k) => write!(f, "is_unset {}", FmtIdentifier(k)),
+            &Condition::Cmp(ref l, ref op, ref r) => write!(f, "{} {} {}", l, op, r),
+            &Condition::CmpRef(ref l, ref op, ref r) => write!(f, "{} {} {}", l, op, FmtRefOperand(r)),
+            &Condition::IsSet(ref k) => write!(f, "is_set {}", k),
+            &Condition::IsUnset(ref k) => write!(f, "is_unset {}", k),
             &Condition::Message { ref topic, ref src_role, ref acting_role } => {
-                write!(f, "message {}", topic)?;
+                write!(f, "message {}", FmtTopic(topic))?;
                 if let &Some(ref role) = src_role {
-                    write!(f, " from {}", role)?;
+                    write!(f, " from {}", FmtName(role))?;
                 }
                 if let &Some(ref role) = acting_role {
-                    write!(f, " as {}", role)?;
+                    write!(f, " as {}", FmtName(role))?;
                 }
                 Ok(())
             }
+            &Condition::Elapsed(secs) => write!(f, "elapsed {}", FmtDuration(secs)),
+            &Condition::Every(secs) => write!(f, "every {}", FmtDuration(secs)),
+            &Condition::At(hour, minute) => write!(f, "at {:02}:{:02}", hour, minute),
+            &Condition::Check { ref script, ref timeout } => {
+                write!(f, "check ")?;
+                if let &Some(secs) = timeout {
+                    write!(f, "{} ", FmtDuration(secs))?;
+                }
+                write!(f, "{}!#", script)
+            }
+            &Condition::Not(ref c) => write!(f, "not {}", c),
+            &Condition::Or(ref cs) => {
+                for (i, c) in cs.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " or ")?;
+                    }
+                    write!(f, "{}", c)?;
+                }
+                Ok(())
+            }
+            &Condition::And(ref cs) => write!(f, "({})", FmtConditions(cs)),
+        }
+    }
+}
+
+struct FmtDuration(u64);
+
+impl fmt::Display for FmtDuration {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let secs = self.0;
+        if secs % 86400 == 0 {
+            write!(f, "{}d", secs / 86400)
+        } else if secs % 3600 == 0 {
+            write!(f, "{}h", secs / 3600)
+        } else if secs % 60 == 0 {
+            write!(f, "{}m", secs / 60)
+        } else {
+            write!(f, "{}s", secs)
         }
     }
 }
@@ -149,28 +828,236 @@ impl<'a> fmt::Display for FmtConditions<'a> {
     }
 }
 
+impl Match {
+    /// Roles the agent acts in, for the messages the match consumes, in sorted order.
+    pub fn acting_roles(&self) -> Vec<String> {
+        let mut roles = acting_roles(&self.conditions).into_iter().collect::<Vec<_>>();
+        roles.sort();
+        roles
+    }
+
+    /// Nested matches are indented one level deeper than their parent; script contents are
+    /// written as-is.
+    fn fmt_indented(&self, f: &mut fmt::Formatter, indent: &str) -> fmt::Result {
+        write!(f, "when ")?;
+        if let Some(ref name) = self.name {
+            write!(f, "{} ", FmtString(name))?;
+            if let Some(ref description) = self.description {
+                write!(f, "{} ", FmtString(description))?;
+            }
+        }
+        // A lone script is written in the short form, without a block around it.
+        if let (1, true) = (self.actions.len(), self.else_actions.is_empty()) {
+            if let Action::Script(ref v) = self.actions[0] {
+                return write!(f, "({}) {}!#", FmtConditions(&self.conditions), v);
+            }
+        }
+        writeln!(f, "({}) {{", FmtConditions(&self.conditions))?;
+        fmt_block(f, &self.actions, indent)?;
+        if !self.else_actions.is_empty() {
+            writeln!(f, " else {{")?;
+            fmt_block(f, &self.else_actions, indent)?;
+        }
+        Ok(())
+    }
+}
+
+fn fmt_block(f: &mut fmt::Formatter, actions: &Vec<Action>, indent: &str) -> fmt::Result {
+    let inner = format!("{}    ", indent);
+    for (i, a) in actions.iter().enumerate() {
+        if let &Action::Comment { trailing: true, .. } = a {
+            if i > 0 {
+                continue;
+            }
+        }
+        if let &Action::Comment { ref text, .. } = a {
+            if text.is_empty() {
+                writeln!(f, "")?;
+                continue;
+            }
+        }
+        write!(f, "{}", inner)?;
+        a.fmt_indented(f, &inner)?;
+        if let Some(&Action::Comment { ref text, trailing: true }) = actions.get(i + 1) {
+            write!(f, " {}", text)?;
+        }
+        writeln!(f, "")?;
+    }
+    write!(f, "{}}}", indent)
+}
+
 impl fmt::Display for Match {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        writeln!(f,
-                 "when ({}) {{\n{}}}",
-                 FmtConditions(&self.conditions),
-                 FmtActions(&self.actions))
+        self.fmt_indented(f, "")?;
+        writeln!(f, "")
     }
 }
 
 impl fmt::Display for Glop {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for c in &self.comments {
+            writeln!(f, "{}", c)?;
+        }
+        for path in &self.includes {
+            writeln!(f, "include {};", FmtString(path))?;
+        }
+        if !self.comments.is_empty() || !self.includes.is_empty() {
+            writeln!(f, "")?;
+        }
+        for t in &self.topics {
+            for c in &t.comments {
+                writeln!(f, "{}", c)?;
+            }
+            writeln!(f, "{}", t)?;
+        }
+        if !self.topics.is_empty() {
+            writeln!(f, "")?;
+        }
         for m in &self.matches {
+            for c in &m.comments {
+                writeln!(f, "{}", c)?;
+            }
             try!(writeln!(f, "{}", m));
         }
+        for c in &self.trailing_comments {
+            writeln!(f, "{}", c)?;
+        }
         Ok(())
     }
 }
 
+impl fmt::Display for Topic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "topic {} {{", FmtTopic(&self.name))?;
+        for (i, field) in self.fields.iter().enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+            write!(f, " {}: {}", FmtName(&field.name), field.field_type)?;
+            if field.optional {
+                write!(f, "?")?;
+            }
+        }
+        if !self.fields.is_empty() {
+            write!(f, " ")?;
+        }
+        write!(f, "}}")
+    }
+}
+
+impl fmt::Display for FieldType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &FieldType::String => write!(f, "string"),
+            &FieldType::Int => write!(f, "int"),
+            &FieldType::Object => write!(f, "object"),
+        }
+    }
+}
+
+impl fmt::Display for Ref {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &Ref::Var(ref k) => write!(f, "{}", FmtIdentifier(k)),
+            &Ref::Msg(ref topic, ref k) => write!(f, "{}.{}", FmtTopic(topic), FmtIdentifier(k)),
+        }
+    }
+}
+
+/// A ref compared against, which is written bare when dotted, so as not to read as a string.
+struct FmtRefOperand<'a>(&'a Ref);
+
+impl<'a> fmt::Display for FmtRefOperand<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            &Ref::Var(ref k) if k.len() == 1 => write!(f, "${{{}}}", self.0),
+            _ => write!(f, "{}", self.0),
+        }
+    }
+}
+
 struct FmtIdentifier<'a>(&'a Identifier);
 
 impl<'a> fmt::Display for FmtIdentifier<'a> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.0.join("."))
+        for (i, part) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, ".")?;
+            }
+            write!(f, "{}", FmtName(part))?;
+        }
+        Ok(())
+    }
+}
+
+/// A topic name, which is written as an identifier, or a topic pattern with its wildcards.
+struct FmtTopic<'a>(&'a str);
+
+impl<'a> fmt::Display for FmtTopic<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, part) in self.0.split('.').enumerate() {
+            if i > 0 {
+                write!(f, ".")?;
+            }
+            match part {
+                "*" | "**" => write!(f, "{}", part)?,
+                _ => write!(f, "{}", FmtName(part))?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A single name, quoted in backticks unless it can be written bare.
+struct FmtName<'a>(&'a str);
+
+impl<'a> fmt::Display for FmtName<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bare = self.0.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') &&
+                   self.0.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if bare {
+            write!(f, "{}", self.0)
+        } else {
+            write!(f, "`{}`", escape(self.0, '`'))
+        }
+    }
+}
+
+/// A quoted string. Strings with backslashes are written raw where possible, as they are
+/// often regular expressions.
+struct FmtString<'a>(&'a str);
+
+impl<'a> fmt::Display for FmtString<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = self.0;
+        if s.contains('\\') && !s.chars().any(|c| c.is_control()) {
+            if !s.contains('"') {
+                return write!(f, "r\"{}\"", s);
+            } else if !s.contains("\"#") {
+                return write!(f, "r#\"{}\"#", s);
+            }
+        }
+        write!(f, "\"{}\"", escape(s, '"'))
+    }
+}
+
+/// Escape a string to be written between `quote` characters.
+fn escape(s: &str, quote: char) -> String {
+    let mut result = String::new();
+    for c in s.chars() {
+        match c {
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '\r' => result.push_str("\\r"),
+            '\t' => result.push_str("\\t"),
+            '\0' => result.push_str("\\0"),
+            c if c == quote => {
+                result.push('\\');
+                result.push(c);
+            }
+            c if c.is_control() => result.push_str(&format!("\\u{{{:x}}}", c as u32)),
+            c => result.push(c),
+        }
     }
+    result
 }
@@ -0,0 +1,296 @@
+extern crate regex;
+
+use std::collections::{HashMap, HashSet};
+
+use super::ast;
+use super::diagnostic::Diagnostic;
+use super::error::Result;
+use super::runtime::CmpOpcode;
+use super::source::{self, Unit};
+use super::value::Value;
+
+/// Topics sent by the agent server rather than by agents.
+const SYSTEM_TOPICS: &'static [&'static str] = &["init", "intro"];
+
+/// Check the agents defined by glop source files, each along with the sources it includes.
+pub fn check_files(paths: &Vec<&str>) -> Result<Vec<Diagnostic>> {
+    let agents = paths.iter()
+        .map(|path| source::load_units(path))
+        .collect::<Result<Vec<_>>>()?;
+    Ok(check(&agents))
+}
+
+/// Check agent definitions for likely mistakes, which would leave an agent silently doing
+/// nothing. Each agent is given by its source units, as loaded by `source::load_units`.
+///
+/// Reported are vars that are tested but never set by the agent, topics matched but never sent
+/// by any of the agents, matches whose conditions contradict each other, and nested matches on
+/// a topic already consumed by an enclosing match. Scripts are searched for `glop var set` and
+/// `glop msg send` commands. Topics with a declared schema are taken to be sent from outside,
+/// so they are not reported.
+pub fn check(agents: &Vec<Vec<Unit>>) -> Vec<Diagnostic> {
+    let names = agents.iter()
+        .map(|agent| agent.iter().map(names).collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+    let sent = names.iter()
+        .flat_map(|agent| agent.iter().flat_map(|n| n.sent_topics.iter().cloned()))
+        .collect::<HashSet<_>>();
+    let mut diags = vec![];
+    for (agent, agent_names) in agents.iter().zip(names.iter()) {
+        let mut set_vars = HashSet::new();
+        let mut declared = HashSet::new();
+        for n in agent_names {
+            set_vars.extend(n.set_vars.iter().cloned());
+            declared.extend(n.declared_topics.iter().cloned());
+        }
+        let mut checker = Checker {
+            sent: &sent,
+            set_vars: &set_vars,
+            declared: &declared,
+            reported: HashSet::new(),
+            diags: &mut diags,
+        };
+        for unit in agent {
+            for m in &unit.glop.matches {
+                checker.check_match(unit, m, &HashSet::new());
+            }
+        }
+    }
+    diags
+}
+
+/// Names of the vars and topics used in a glop source.
+#[derive(Debug, Default)]
+pub struct Names {
+    pub set_vars: HashSet<String>,
+    pub tested_vars: HashSet<String>,
+    pub sent_topics: HashSet<String>,
+    pub matched_topics: HashSet<String>,
+    pub declared_topics: HashSet<String>,
+}
+
+/// Collect the names of vars and topics used in a source unit, including those set and sent
+/// by its scripts.
+pub fn names(unit: &Unit) -> Names {
+    let mut result = Names::default();
+    result.declared_topics.extend(unit.glop.topics.iter().map(|t| t.name.to_string()));
+    let set_re = regex::Regex::new(r"glop\s+var\s+set\s+([A-Za-z0-9_.]+)").unwrap();
+    let send_re =
+        regex::Regex::new(r"glop\s+msg\s+(?:send|reply)(?:\s+-\S+\s+\S+)*\s+\S+\s+([A-Za-z0-9_.]+)")
+            .unwrap();
+    for m in &unit.glop.matches {
+        collect_names(m, &set_re, &send_re, &mut result);
+    }
+    result
+}
+
+fn collect_names(m: &ast::Match,
+                 set_re: &regex::Regex,
+                 send_re: &regex::Regex,
+                 result: &mut Names) {
+    let mut tested = vec![];
+    let mut matched = vec![];
+    for c in &m.conditions {
+        collect_tested(c, &mut tested, &mut matched);
+    }
+    result.tested_vars.extend(tested);
+    result.matched_topics.extend(matched);
+    for a in m.actions.iter().chain(m.else_actions.iter()) {
+        match a {
+            &ast::Action::SetVar(ref k, _) => {
+                result.set_vars.insert(k.join("."));
+            }
+            &ast::Action::SendMsg { ref topic, .. } |
+            &ast::Action::ReplyMsg { ref topic, .. } => {
+                result.sent_topics.insert(topic.to_string());
+            }
+            &ast::Action::Script(ref contents) => {
+                for caps in set_re.captures_iter(contents) {
+                    result.set_vars.insert(caps.get(1).unwrap().as_str().to_string());
+                }
+                for caps in send_re.captures_iter(contents) {
+                    result.sent_topics.insert(caps.get(1).unwrap().as_str().to_string());
+                }
+            }
+            &ast::Action::Match(ref m) => collect_names(m, set_re, send_re, result),
+            _ => {}
+        }
+    }
+}
+
+/// Whether a var read by one name may be written by setting the other, where either one is
+/// the same var or contains it.
+pub fn vars_overlap(a: &str, b: &str) -> bool {
+    a == b || a.starts_with(&format!("{}.", b)) || b.starts_with(&format!("{}.", a))
+}
+
+struct Checker<'a> {
+    sent: &'a HashSet<String>,
+    set_vars: &'a HashSet<String>,
+    declared: &'a HashSet<String>,
+    /// Vars and topics already reported, so each is only reported once per agent.
+    reported: HashSet<String>,
+    diags: &'a mut Vec<Diagnostic>,
+}
+
+impl<'a> Checker<'a> {
+    fn check_match(&mut self, unit: &Unit, m: &ast::Match, parent_scope: &HashSet<String>) {
+        let warn_at = |diags: &mut Vec<Diagnostic>, offset: usize, msg: String| {
+            diags.push(Diagnostic::warning(&unit.path, &unit.src, offset, &msg));
+        };
+        let warn = |diags: &mut Vec<Diagnostic>, msg: String| warn_at(diags, m.span.start, msg);
+
+        // Vars and topics are reported at the condition naming them.
+        let mut tested = vec![];
+        let mut matched = vec![];
+        for (i, c) in m.conditions.iter().enumerate() {
+            let offset = m.condition_spans.get(i).map_or(m.span.start, |span| span.start);
+            let mut vars = vec![];
+            let mut topics = vec![];
+            collect_tested(c, &mut vars, &mut topics);
+            tested.extend(vars.into_iter().map(|var| (var, offset)));
+            matched.extend(topics.into_iter().map(|topic| (topic, offset)));
+        }
+        for (var, offset) in tested {
+            let set = self.set_vars.iter().any(|s| vars_overlap(s, &var));
+            if !set && self.reported.insert(format!("var {}", var)) {
+                warn_at(self.diags, offset, format!("var {} is tested but never set", var));
+            }
+        }
+        for (topic, offset) in matched {
+            let sent = self.sent
+                .iter()
+                .chain(self.declared.iter())
+                .map(|t| t.as_str())
+                .chain(SYSTEM_TOPICS.iter().cloned())
+                .any(|t| ast::topic_matches(&topic, t));
+            if sent {
+                continue;
+            }
+            if self.reported.insert(format!("topic {}", topic)) {
+                warn_at(self.diags,
+                        offset,
+                        format!("topic {} is matched but never sent by any checked agent",
+                                topic));
+            }
+        }
+
+        let mut topics = m.topics().into_iter().collect::<Vec<_>>();
+        topics.sort();
+        for topic in &topics {
+            if parent_scope.contains(topic) {
+                warn(self.diags,
+                     format!("nested match on topic {}, which is consumed by an enclosing match",
+                             topic));
+            }
+        }
+
+        let mut conjuncts = vec![];
+        flatten_conjuncts(&m.conditions, &mut conjuncts);
+        if let Some(msg) = contradiction(&conjuncts) {
+            warn(self.diags, msg);
+        }
+
+        let mut scope = parent_scope.clone();
+        scope.extend(topics);
+        for a in &m.actions {
+            if let &ast::Action::Match(ref nested) = a {
+                self.check_match(unit, nested, &scope);
+            }
+        }
+        for a in &m.else_actions {
+            if let &ast::Action::Match(ref nested) = a {
+                self.check_match(unit, nested, parent_scope);
+            }
+        }
+    }
+}
+
+/// Collect the vars tested and the topics matched by a condition.
+fn collect_tested(c: &ast::Condition, vars: &mut Vec<String>, topics: &mut Vec<String>) {
+    match c {
+        &ast::Condition::Cmp(ast::Ref::Var(ref k), _, _) |
+        &ast::Condition::IsSet(ast::Ref::Var(ref k)) |
+        &ast::Condition::IsUnset(ast::Ref::Var(ref k)) => vars.push(k.join(".")),
+        &ast::Condition::CmpRef(ref l, _, ref r) => {
+            for r in &[l, r] {
+                if let &&ast::Ref::Var(ref k) = r {
+                    vars.push(k.join("."));
+                }
+            }
+        }
+        &ast::Condition::Message { ref topic, .. } => topics.push(topic.to_string()),
+        &ast::Condition::Not(ref c) => collect_tested(c, vars, topics),
+        &ast::Condition::Or(ref cs) |
+        &ast::Condition::And(ref cs) => {
+            for c in cs {
+                collect_tested(c, vars, topics);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Conditions which must all hold, looking into parenthesized groups.
+fn flatten_conjuncts<'a>(conditions: &'a Vec<ast::Condition>,
+                         result: &mut Vec<&'a ast::Condition>) {
+    for c in conditions {
+        match c {
+            &ast::Condition::And(ref cs) => flatten_conjuncts(cs, result),
+            _ => result.push(c),
+        }
+    }
+}
+
+/// Describe why conditions that must all hold never can, if they can't.
+fn contradiction(conjuncts: &Vec<&ast::Condition>) -> Option<String> {
+    for (i, a) in conjuncts.iter().enumerate() {
+        for b in conjuncts.iter().skip(i + 1) {
+            if contradicts(a, b) || contradicts(b, a) {
+                return Some(format!("conditions can never all hold: `{}` and `{}`", a, b));
+            }
+        }
+    }
+    // Integer bounds on each ref, from comparisons with integer literals.
+    let mut bounds: HashMap<String, (i64, i64)> = HashMap::new();
+    for c in conjuncts {
+        if let &&ast::Condition::Cmp(ref r, ref op, ast::Literal::Int(n)) = c {
+            let n = n as i64;
+            let (lo, hi) = match op {
+                &ast::CmpOpcode::Equal => (n, n),
+                &ast::CmpOpcode::Less => (i64::min_value(), n - 1),
+                &ast::CmpOpcode::LessEqual => (i64::min_value(), n),
+                &ast::CmpOpcode::Greater => (n + 1, i64::max_value()),
+                &ast::CmpOpcode::GreaterEqual => (n, i64::max_value()),
+                _ => continue,
+            };
+            let key = format!("{}", r);
+            let entry = bounds.entry(key.clone()).or_insert((i64::min_value(), i64::max_value()));
+            entry.0 = entry.0.max(lo);
+            entry.1 = entry.1.min(hi);
+            if entry.0 > entry.1 {
+                return Some(format!("conditions on {} can never all hold", key));
+            }
+        }
+    }
+    None
+}
+
+/// Whether `a` and `b` cannot both hold, in that order of arguments.
+fn contradicts(a: &ast::Condition, b: &ast::Condition) -> bool {
+    let same = |l: &ast::Ref, r: &ast::Ref| format!("{}", l) == format!("{}", r);
+    match (a, b) {
+        (&ast::Condition::IsSet(ref l), &ast::Condition::IsUnset(ref r)) |
+        (&ast::Condition::IsUnset(ref l), &ast::Condition::Cmp(ref r, _, _)) => same(l, r),
+        (&ast::Condition::Cmp(ref l, ast::CmpOpcode::Equal, ref lv),
+         &ast::Condition::Cmp(ref r, ast::CmpOpcode::Equal, ref rv)) => {
+            same(l, r) && !CmpOpcode::Equal.eval(&Value::from_literal(lv), &Value::from_literal(rv))
+        }
+        (&ast::Condition::Cmp(ref l, ast::CmpOpcode::Equal, ref lv),
+         &ast::Condition::Cmp(ref r, ast::CmpOpcode::NotEqual, ref rv)) => {
+            same(l, r) && CmpOpcode::Equal.eval(&Value::from_literal(lv), &Value::from_literal(rv))
+        }
+        (&ast::Condition::Not(ref c), _) => format!("{}", c) == format!("{}", b),
+        _ => false,
+    }
+}
@@ -0,0 +1,177 @@
+use std::fmt;
+
+use super::ast;
+use super::grammar;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Severity {
+    Error,
+    /// A likely mistake, in source that is otherwise valid.
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &Severity::Error => write!(f, "error"),
+            &Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+/// A problem found in glop source, located by line and column.
+#[derive(Clone, Debug)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// Name of the source, usually its file path.
+    pub source: String,
+    /// Line number, starting from 1.
+    pub line: usize,
+    /// Column number in characters, starting from 1.
+    pub column: usize,
+    pub message: String,
+    /// The line of source containing the error, for display.
+    pub snippet: String,
+}
+
+impl Diagnostic {
+    pub fn new(source: &str, src: &str, offset: usize, message: &str) -> Diagnostic {
+        let (line, column) = line_col(src, offset);
+        Diagnostic {
+            severity: Severity::Error,
+            source: source.to_string(),
+            line: line,
+            column: column,
+            message: message.to_string(),
+            snippet: src.lines().nth(line - 1).unwrap_or("").to_string(),
+        }
+    }
+
+    pub fn warning(source: &str, src: &str, offset: usize, message: &str) -> Diagnostic {
+        Diagnostic { severity: Severity::Warning, ..Diagnostic::new(source, src, offset, message) }
+    }
+
+    fn from_parse_error(source: &str, src: &str, offset: usize, e: &grammar::ParseError) -> Diagnostic {
+        Diagnostic::new(source, src, offset, &expected_message(e))
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let gutter = " ".repeat(self.line.to_string().len());
+        let indent = self.snippet
+            .chars()
+            .take(self.column - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect::<String>();
+        writeln!(f, "{}: {}", self.severity, self.message)?;
+        writeln!(f, "{}--> {}:{}:{}", gutter, self.source, self.line, self.column)?;
+        writeln!(f, "{} |", gutter)?;
+        writeln!(f, "{} | {}", self.line, self.snippet)?;
+        write!(f, "{} | {}^", gutter, indent)
+    }
+}
+
+/// Parse glop source, reporting all the errors that can be found in it.
+///
+/// When the source does not parse, each top-level `include`, `topic` and `when` starting at
+/// the beginning of a line is parsed on its own, so that an error in one of them does not hide
+/// errors in the rest. Errors that only show up when parsing the source as a whole, such as
+/// ambiguous references between matches, are reported once the individual parts are correct.
+///
+/// Recovery works on the text rather than the grammar: an item that is indented, or that
+/// shares a line with the end of the one before it, is parsed together with the item before
+/// it, and only the first error among them is reported.
+pub fn parse(source: &str, src: &str) -> Result<ast::Glop, Vec<Diagnostic>> {
+    let e = match grammar::glop(src) {
+        Ok(glop) => return Ok(glop),
+        Err(e) => e,
+    };
+    let mut diags = vec![];
+    let starts = item_starts(src);
+    for (i, &start) in starts.iter().enumerate() {
+        let end = if i + 1 < starts.len() {
+            starts[i + 1]
+        } else {
+            src.len()
+        };
+        if let Err(item_e) = grammar::glop(&src[start..end]) {
+            let item = &src[start..start + item_e.offset];
+            match item.rfind("#!") {
+                // A script missing its `!#` runs to the end of the item; point at its start.
+                Some(i) if item_e.expected.contains("!#") && item[i..].find("!#").is_none() => {
+                    diags.push(Diagnostic::new(source,
+                                               src,
+                                               start + i,
+                                               "script is not closed with `!#`"));
+                }
+                _ => {
+                    diags.push(Diagnostic::from_parse_error(source,
+                                                            src,
+                                                            start + item_e.offset,
+                                                            &item_e))
+                }
+            }
+        }
+    }
+    if diags.is_empty() {
+        diags.push(Diagnostic::from_parse_error(source, src, e.offset, &e));
+    }
+    Err(diags)
+}
+
+/// Offsets of the top-level items in the source. The first item also takes any leading
+/// whitespace and comments.
+fn item_starts(src: &str) -> Vec<usize> {
+    let mut starts = vec![0];
+    let mut offset = 0;
+    let mut seen_item = false;
+    for line in src.split('\n') {
+        let keyword = ["when", "topic", "include"].iter().any(|kw| {
+            line.starts_with(kw) &&
+            !line[kw.len()..].starts_with(|c: char| c.is_alphanumeric() || c == '_')
+        });
+        if keyword {
+            if seen_item {
+                starts.push(offset);
+            }
+            seen_item = true;
+        }
+        offset += line.len() + 1;
+    }
+    starts
+}
+
+/// Line and column, starting from 1, of a byte offset in the source.
+pub fn line_col(src: &str, offset: usize) -> (usize, usize) {
+    let offset = offset.min(src.len());
+    let before = &src[..offset];
+    let line = before.matches('\n').count() + 1;
+    let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+    (line, before[line_start..].chars().count() + 1)
+}
+
+/// Describe what the parser expected, leaving out whitespace and comments which may appear
+/// almost anywhere. Tokens are quoted; descriptions from semantic checks are not.
+fn expected_message(e: &grammar::ParseError) -> String {
+    const NOISE: &'static [&'static str] = &["\n", "\r\n", "\r", "\u{2028}", "\u{2029}",
+                                              "//", "/*"];
+    let mut expected = e.expected
+        .iter()
+        .filter(|x| !NOISE.contains(x))
+        .filter_map(|x| match *x {
+                        "[A-Za-z_]" | "`" => Some("name".to_string()),
+                        "[0-9]" => Some("number".to_string()),
+                        _ if x.starts_with('[') && x.len() > 2 => None,
+                        _ if x.contains(' ') => Some(x.to_string()),
+                        _ => Some(format!("`{}`", x)),
+                    })
+        .collect::<Vec<_>>();
+    expected.sort();
+    expected.dedup();
+    match expected.len() {
+        0 => "unexpected input".to_string(),
+        1 => format!("expected {}", expected[0]),
+        _ => format!("expected one of {}", expected.join(", ")),
+    }
+}
@@ -2,6 +2,7 @@ extern crate clap;
 
 use std;
 
+use super::diagnostic::Diagnostic;
 use super::grammar;
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -13,14 +14,22 @@ pub enum Error {
     Env(std::env::VarError),
     IO(std::io::Error),
     Parse(grammar::ParseError),
+    /// Errors found in glop source.
+    Diagnostics(Vec<Diagnostic>),
+    /// Problems reported by `glop check`, with their number.
+    Problems(usize),
     StringConversion(std::string::FromUtf8Error),
     InvalidArgument(String),
     ErrorResponse(String),
     BadResponse,
-    Exec(i32, String),
+    /// A script failed, with its exit code, or none if it was killed by a signal, and its
+    /// stderr.
+    Exec(Option<i32>, String),
     UnsupportedAction,
     AgentExists(String),
     UndeliverableMessage(String),
+    Eval(String),
+    Schema(String),
     Timeout,
 }
 
@@ -68,14 +77,29 @@ impl std::fmt::Display for Error {
             Error::Env(ref err) => err.fmt(f),
             Error::IO(ref err) => err.fmt(f),
             Error::Parse(ref err) => err.fmt(f),
+            Error::Diagnostics(ref diags) => {
+                for (i, diag) in diags.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, "\n\n")?;
+                    }
+                    write!(f, "{}", diag)?;
+                }
+                Ok(())
+            }
+            Error::Problems(n) => write!(f, "{} problem(s) found", n),
             Error::StringConversion(ref err) => err.fmt(f),
             Error::InvalidArgument(ref msg) => write!(f, "invalid argument: {}", msg),
             Error::BadResponse => write!(f, "bad response"),
             Error::ErrorResponse(ref msg) => write!(f, "{}", msg),
-            Error::Exec(code, ref stderr) => write!(f, "script exit code {}: {}", code, stderr),
+            Error::Exec(Some(code), ref stderr) => {
+                write!(f, "script exit code {}: {}", code, stderr)
+            }
+            Error::Exec(None, ref stderr) => write!(f, "script killed by signal: {}", stderr),
             Error::UnsupportedAction => write!(f, "unsupported action"),
             Error::AgentExists(ref name) => write!(f, "agent {} already added", name),
             Error::UndeliverableMessage(ref dst) => write!(f, "undeliverable message: {}", dst),
+            Error::Eval(ref msg) => write!(f, "evaluation failed: {}", msg),
+            Error::Schema(ref msg) => write!(f, "message does not match schema: {}", msg),
             Error::Timeout => write!(f, "timeout"),
         }
     }
@@ -89,6 +113,8 @@ impl std::error::Error for Error {
             Error::Env(ref err) => err.description(),
             Error::IO(ref err) => err.description(),
             Error::Parse(ref err) => err.description(),
+            Error::Diagnostics(_) => "errors in glop source",
+            Error::Problems(_) => "problems found in glop source",
             Error::StringConversion(ref err) => err.description(),
             Error::InvalidArgument(ref msg) => msg,
             Error::BadResponse => "bad response",
@@ -97,6 +123,8 @@ impl std::error::Error for Error {
             Error::UnsupportedAction => "unsupported action",
             Error::AgentExists(ref name) => name,
             Error::UndeliverableMessage(ref dst) => dst,
+            Error::Eval(ref msg) => msg,
+            Error::Schema(ref msg) => msg,
             Error::Timeout => "timeout",
         }
     }
@@ -0,0 +1,98 @@
+use super::ast;
+use super::diagnostic::{self, Diagnostic};
+use super::error::{Error, Result};
+
+/// Format glop source in the canonical style, keeping its comments.
+///
+/// Comments are kept on the lines before top-level items and actions, at the end of a block
+/// or the source, or at the end of an action's line. Comments anywhere else, such as within
+/// match conditions, are an error rather than being dropped.
+pub fn format(source: &str, src: &str) -> Result<String> {
+    let glop = diagnostic::parse(source, src).map_err(Error::Diagnostics)?;
+    let mut kept = vec![];
+    collect_comments(&glop, &mut kept);
+    kept.retain(|c| !c.is_empty());
+    let found = scan_comments(src);
+    for (i, &(offset, ref text)) in found.iter().enumerate() {
+        if kept.get(i) != Some(text) {
+            return Err(Error::Diagnostics(vec![Diagnostic::new(source,
+                                                               src,
+                                                               offset,
+                                                               "comment cannot be kept here; \
+                                                                move it to a line of its own")]));
+        }
+    }
+    let formatted = format!("{}", glop);
+    Ok(format!("{}\n", formatted.trim_end()))
+}
+
+/// Comments held by the AST, in source order.
+fn collect_comments(glop: &ast::Glop, result: &mut Vec<String>) {
+    result.extend(glop.comments.iter().cloned());
+    for t in &glop.topics {
+        result.extend(t.comments.iter().cloned());
+    }
+    for m in &glop.matches {
+        collect_match_comments(m, result);
+    }
+    result.extend(glop.trailing_comments.iter().cloned());
+}
+
+fn collect_match_comments(m: &ast::Match, result: &mut Vec<String>) {
+    result.extend(m.comments.iter().cloned());
+    for a in m.actions.iter().chain(m.else_actions.iter()) {
+        match a {
+            &ast::Action::Comment { ref text, .. } => result.push(text.to_string()),
+            &ast::Action::Match(ref m) => collect_match_comments(m, result),
+            _ => {}
+        }
+    }
+}
+
+/// Offsets and text of all the comments in the source, skipping over quoted strings, quoted
+/// names and scripts.
+fn scan_comments(src: &str) -> Vec<(usize, String)> {
+    let mut result = vec![];
+    let mut i = 0;
+    while i < src.len() {
+        let rest = &src[i..];
+        let skip = if rest.starts_with("r#\"") {
+            rest[3..].find("\"#").map(|n| n + 5)
+        } else if rest.starts_with("r\"") {
+            rest[2..].find('"').map(|n| n + 3)
+        } else if rest.starts_with('"') || rest.starts_with('`') {
+            quoted_len(rest)
+        } else if rest.starts_with("#!") {
+            rest.find("!#").map(|n| n + 2)
+        } else if rest.starts_with("//") {
+            let n = rest.find(|c| c == '\n' || c == '\r' || c == '\u{2028}' || c == '\u{2029}')
+                .unwrap_or(rest.len());
+            result.push((i, rest[..n].to_string()));
+            Some(n)
+        } else if rest.starts_with("/*") {
+            let n = rest[2..].find("*/").map(|n| n + 4).unwrap_or(rest.len());
+            result.push((i, rest[..n].to_string()));
+            Some(n)
+        } else {
+            rest.chars().next().map(|c| c.len_utf8())
+        };
+        i += skip.unwrap_or(rest.len());
+    }
+    result
+}
+
+/// Length of the string or name quoted at the start of `s`, up to its closing quote.
+fn quoted_len(s: &str) -> Option<usize> {
+    let quote = s.chars().next().unwrap();
+    let mut escaped = false;
+    for (i, c) in s.char_indices().skip(1) {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == quote {
+            return Some(i + 1);
+        }
+    }
+    None
+}
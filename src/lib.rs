@@ -8,14 +8,24 @@ mod ast;
 mod cleanup;
 
 pub mod agent;
+pub mod check;
 pub mod crypto;
+pub mod diagnostic;
 pub mod error;
+pub mod format;
 pub mod grammar {
     include!(concat!(env!("OUT_DIR"), "/glop.rs"));
 }
+pub mod lsp;
 pub mod runtime;
 pub mod signal_fix;
+pub mod source;
 pub mod value;
 
+mod test_check;
+mod test_diagnostic;
+mod test_format;
 mod test_grammar;
+mod test_lsp;
+mod test_source;
 mod test_value;
@@ -0,0 +1,780 @@
+extern crate regex;
+extern crate serde;
+extern crate serde_json;
+
+use std;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::io::{BufRead, Read, Write};
+use std::path::{Path, PathBuf};
+
+use self::serde_json::Value as Json;
+
+use super::ast;
+use super::check;
+use super::diagnostic::{self, Severity};
+use super::error::{Error, Result, to_ioerror};
+use super::source::Unit;
+
+const METHOD_NOT_FOUND: i32 = -32601;
+const INVALID_PARAMS: i32 = -32602;
+
+const COMPLETION_VARIABLE: u8 = 6;
+const COMPLETION_EVENT: u8 = 23;
+
+/// Run a language server speaking JSON-RPC over `input` and `output`, until the client exits
+/// or closes the input.
+pub fn run<R: BufRead, W: Write>(input: &mut R, output: &mut W) -> Result<()> {
+    let mut server = Server::new();
+    while let Some(msg) = read_message(input)? {
+        for out in server.handle(&msg) {
+            write_message(output, &out)?;
+        }
+        if server.exited {
+            break;
+        }
+    }
+    Ok(())
+}
+
+fn read_message<R: BufRead>(input: &mut R) -> Result<Option<Json>> {
+    let mut len = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let mut parts = line.splitn(2, ':');
+        if parts.next().unwrap().trim().eq_ignore_ascii_case("content-length") {
+            len = parts.next().and_then(|n| n.trim().parse::<usize>().ok());
+        }
+    }
+    let len = len.ok_or(Error::InvalidArgument("missing Content-Length header".to_string()))?;
+    let mut body = vec![0; len];
+    input.read_exact(&mut body)?;
+    let msg = serde_json::from_slice(&body).map_err(to_ioerror)?;
+    Ok(Some(msg))
+}
+
+fn write_message<W: Write>(output: &mut W, msg: &Json) -> Result<()> {
+    let body = serde_json::to_string(msg).map_err(to_ioerror)?;
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()?;
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct Response {
+    jsonrpc: &'static str,
+    id: Json,
+    result: Json,
+}
+
+#[derive(Serialize)]
+struct ErrorResponse {
+    jsonrpc: &'static str,
+    id: Json,
+    error: ResponseError,
+}
+
+#[derive(Serialize)]
+struct ResponseError {
+    code: i32,
+    message: String,
+}
+
+#[derive(Serialize)]
+struct Notification {
+    jsonrpc: &'static str,
+    method: &'static str,
+    params: Json,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct InitializeResult {
+    capabilities: ServerCapabilities,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ServerCapabilities {
+    /// Documents are synced by sending their full contents on each change.
+    text_document_sync: u8,
+    definition_provider: bool,
+    hover_provider: bool,
+    completion_provider: Json,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Position {
+    /// Line number, starting from 0.
+    pub line: usize,
+    /// Character offset in the line, starting from 0.
+    pub character: usize,
+}
+
+#[derive(Clone, Debug, Serialize, PartialEq)]
+pub struct Range {
+    pub start: Position,
+    pub end: Position,
+}
+
+#[derive(Clone, Debug, Serialize, PartialEq)]
+pub struct Location {
+    pub uri: String,
+    pub range: Range,
+}
+
+#[derive(Serialize)]
+struct PublishDiagnosticsParams {
+    uri: String,
+    diagnostics: Vec<LspDiagnostic>,
+}
+
+#[derive(Serialize)]
+struct LspDiagnostic {
+    range: Range,
+    severity: u8,
+    source: &'static str,
+    message: String,
+}
+
+#[derive(Serialize)]
+struct Hover {
+    contents: MarkupContent,
+    range: Range,
+}
+
+#[derive(Serialize)]
+struct MarkupContent {
+    kind: &'static str,
+    value: String,
+}
+
+#[derive(Serialize)]
+struct CompletionItem {
+    label: String,
+    kind: u8,
+    detail: &'static str,
+}
+
+/// A glop source in the workspace, which may be open in the editor.
+struct Document {
+    text: String,
+    /// The source as last parsed successfully, which is kept while edits don't parse.
+    unit: Option<Unit>,
+    open: bool,
+}
+
+/// Language server state: the glop sources in the workspace, and what's open in the editor.
+pub struct Server {
+    docs: HashMap<String, Document>,
+    exited: bool,
+}
+
+impl Server {
+    pub fn new() -> Server {
+        Server {
+            docs: HashMap::new(),
+            exited: false,
+        }
+    }
+
+    /// Handle a message from the client, returning the responses and notifications to send
+    /// back.
+    pub fn handle(&mut self, msg: &Json) -> Vec<Json> {
+        let method = match msg["method"].as_str() {
+            Some(method) => method,
+            // Responses to server requests are not expected.
+            None => return vec![],
+        };
+        let params = &msg["params"];
+        let id = msg.get("id").cloned();
+        debug!("lsp: {} {:?}", method, id);
+        let result = match method {
+            "initialize" => {
+                if let Some(root) = params["rootUri"].as_str().and_then(uri_path) {
+                    self.scan_workspace(Path::new(&root));
+                }
+                to_json(InitializeResult {
+                    capabilities: ServerCapabilities {
+                        text_document_sync: 1,
+                        definition_provider: true,
+                        hover_provider: true,
+                        completion_provider: Json::Object(serde_json::Map::new()),
+                    },
+                })
+            }
+            "shutdown" => Json::Null,
+            "exit" => {
+                self.exited = true;
+                return vec![];
+            }
+            "textDocument/didOpen" => {
+                let doc = &params["textDocument"];
+                if let (Some(uri), Some(text)) = (doc["uri"].as_str(), doc["text"].as_str()) {
+                    self.update(&doc_uri(uri), text.to_string(), true);
+                }
+                return self.publish_diagnostics();
+            }
+            "textDocument/didChange" => {
+                let uri = params["textDocument"]["uri"].as_str().map(doc_uri);
+                let text = params["contentChanges"]
+                    .as_array()
+                    .and_then(|changes| changes.last())
+                    .and_then(|change| change["text"].as_str());
+                if let (Some(uri), Some(text)) = (uri, text) {
+                    self.update(&uri, text.to_string(), true);
+                }
+                return self.publish_diagnostics();
+            }
+            "textDocument/didClose" => {
+                let mut result = vec![];
+                if let Some(uri) = params["textDocument"]["uri"].as_str().map(doc_uri) {
+                    // Go back to what's on disk, if anything.
+                    let on_disk = uri_path(&uri)
+                        .and_then(|path| std::fs::File::open(path).ok())
+                        .and_then(|mut f| {
+                                      let mut text = String::new();
+                                      f.read_to_string(&mut text).ok().map(|_| text)
+                                  });
+                    match on_disk {
+                        Some(text) => self.update(&uri, text, false),
+                        None => {
+                            self.docs.remove(&uri);
+                        }
+                    }
+                    result.push(notification("textDocument/publishDiagnostics",
+                                             PublishDiagnosticsParams {
+                                                 uri: uri.to_string(),
+                                                 diagnostics: vec![],
+                                             }));
+                }
+                result.extend(self.publish_diagnostics());
+                return result;
+            }
+            "textDocument/definition" => {
+                match self.position_params(params) {
+                    Some((uri, word, _)) => to_json(self.definition(&uri, &word)),
+                    None => return vec![error_response(id, INVALID_PARAMS, "invalid params")],
+                }
+            }
+            "textDocument/hover" => {
+                match self.position_params(params) {
+                    Some((_, word, range)) => {
+                        self.hover(&word, range).map(to_json).unwrap_or(Json::Null)
+                    }
+                    None => return vec![error_response(id, INVALID_PARAMS, "invalid params")],
+                }
+            }
+            "textDocument/completion" => to_json(self.completion()),
+            _ => {
+                // Unknown notifications are ignored; unknown requests are an error.
+                return match id {
+                           Some(_) => {
+                               vec![error_response(id,
+                                                   METHOD_NOT_FOUND,
+                                                   &format!("unsupported method {}", method))]
+                           }
+                           None => vec![],
+                       };
+            }
+        };
+        match id {
+            Some(id) => {
+                vec![to_json(Response {
+                                 jsonrpc: "2.0",
+                                 id: id,
+                                 result: result,
+                             })]
+            }
+            None => vec![],
+        }
+    }
+
+    /// Load all the glop sources under the workspace root.
+    fn scan_workspace(&mut self, dir: &Path) {
+        let entries = match std::fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+        for entry in entries.filter_map(|e| e.ok()) {
+            let path = entry.path();
+            if entry.file_name().to_string_lossy().starts_with('.') {
+                continue;
+            } else if path.is_dir() {
+                self.scan_workspace(&path);
+            } else if path.extension().map(|ext| ext == "glop").unwrap_or(false) {
+                let mut text = String::new();
+                if std::fs::File::open(&path)
+                       .and_then(|mut f| f.read_to_string(&mut text))
+                       .is_ok() {
+                    let uri = path_uri(&path.to_string_lossy());
+                    self.update(&uri, text, false);
+                }
+            }
+        }
+    }
+
+    fn update(&mut self, uri: &str, text: String, open: bool) {
+        let path = uri_path(uri).unwrap_or(uri.to_string());
+        let unit = match diagnostic::parse(&path, &text) {
+            Ok(glop) => {
+                Some(Unit {
+                         path: path,
+                         src: text.clone(),
+                         glop: glop,
+                     })
+            }
+            Err(_) => self.docs.remove(uri).and_then(|doc| doc.unit),
+        };
+        self.docs.insert(uri.to_string(),
+                         Document {
+                             text: text,
+                             unit: unit,
+                             open: open,
+                         });
+    }
+
+    /// Diagnostics for all open documents, as they may depend on each other.
+    fn publish_diagnostics(&self) -> Vec<Json> {
+        let mut uris = self.docs
+            .iter()
+            .filter(|&(_, doc)| doc.open)
+            .map(|(uri, _)| uri.to_string())
+            .collect::<Vec<_>>();
+        uris.sort();
+        uris.iter()
+            .map(|uri| {
+                     notification("textDocument/publishDiagnostics",
+                                  PublishDiagnosticsParams {
+                                      uri: uri.to_string(),
+                                      diagnostics: self.diagnostics(uri),
+                                  })
+                 })
+            .collect()
+    }
+
+    /// Parse errors in the document, or if it parses, warnings from `check::check` with every
+    /// source that isn't included by another taken as an agent.
+    fn diagnostics(&self, uri: &str) -> Vec<LspDiagnostic> {
+        let doc = &self.docs[uri];
+        let path = uri_path(uri).unwrap_or(uri.to_string());
+        let diags = match diagnostic::parse(&path, &doc.text) {
+            Err(diags) => diags,
+            Ok(_) => {
+                let agents = self.agents()
+                    .iter()
+                    .map(|agent| {
+                             agent.iter()
+                                 .map(|uri| self.docs[uri].unit.clone().unwrap())
+                                 .collect::<Vec<_>>()
+                         })
+                    .collect::<Vec<_>>();
+                let mut seen = HashSet::new();
+                check::check(&agents)
+                    .into_iter()
+                    .filter(|d| {
+                                d.source == path &&
+                                seen.insert((d.line, d.column, d.message.to_string()))
+                            })
+                    .collect()
+            }
+        };
+        diags.iter()
+            .map(|d| {
+                let start = Position {
+                    line: d.line - 1,
+                    character: d.snippet.chars().take(d.column - 1).map(char::len_utf16).sum(),
+                };
+                let len = d.snippet
+                    .chars()
+                    .skip(d.column - 1)
+                    .take_while(|c| is_word_char(*c))
+                    .map(char::len_utf16)
+                    .sum::<usize>();
+                LspDiagnostic {
+                    range: Range {
+                        start: start.clone(),
+                        end: Position {
+                            line: start.line,
+                            character: start.character + len.max(1),
+                        },
+                    },
+                    severity: match d.severity {
+                        Severity::Error => 1,
+                        Severity::Warning => 2,
+                    },
+                    source: "glop",
+                    message: d.message.to_string(),
+                }
+            })
+            .collect()
+    }
+
+    /// Documents that parse, grouped into agents. Each document not included by another is an
+    /// agent, made up of the documents it includes and itself.
+    fn agents(&self) -> Vec<Vec<String>> {
+        let mut included = HashSet::new();
+        for (uri, doc) in &self.docs {
+            if let Some(ref unit) = doc.unit {
+                for include in self.includes(uri, unit) {
+                    included.insert(include);
+                }
+            }
+        }
+        let mut roots = self.docs
+            .iter()
+            .filter(|&(uri, doc)| doc.unit.is_some() && !included.contains(uri))
+            .map(|(uri, _)| uri.to_string())
+            .collect::<Vec<_>>();
+        roots.sort();
+        roots.iter()
+            .map(|root| {
+                     let mut agent = vec![];
+                     self.collect_agent(root, &mut agent);
+                     agent
+                 })
+            .collect()
+    }
+
+    fn collect_agent(&self, uri: &str, result: &mut Vec<String>) {
+        if result.iter().any(|u| u == uri) {
+            return;
+        }
+        if let Some(ref unit) = self.docs.get(uri).and_then(|doc| doc.unit.as_ref()) {
+            result.push(uri.to_string());
+            for include in self.includes(uri, unit) {
+                self.collect_agent(&include, result);
+            }
+        }
+    }
+
+    /// URIs of the documents included by a unit, which are in the workspace.
+    fn includes(&self, uri: &str, unit: &Unit) -> Vec<String> {
+        let dir = match uri_path(uri) {
+            Some(path) => PathBuf::from(path).parent().unwrap().to_path_buf(),
+            None => return vec![],
+        };
+        unit.glop
+            .includes
+            .iter()
+            .map(|include| {
+                     let path = dir.join(include);
+                     let path = path.canonicalize().unwrap_or(path);
+                     path_uri(&path.to_string_lossy())
+                 })
+            .filter(|uri| self.docs.contains_key(uri))
+            .collect()
+    }
+
+    /// The document, and the word and its range at the position given in request params.
+    fn position_params(&self, params: &Json) -> Option<(String, String, Range)> {
+        let uri = doc_uri(params["textDocument"]["uri"].as_str()?);
+        let pos: Position = serde_json::from_value(params["position"].clone()).ok()?;
+        let doc = self.docs.get(&uri)?;
+        let line = doc.text.lines().nth(pos.line)?.chars().collect::<Vec<_>>();
+        // Find the character at the UTF-16 offset given.
+        let mut at = 0;
+        let mut units = 0;
+        while at < line.len() && units + line[at].len_utf16() <= pos.character {
+            units += line[at].len_utf16();
+            at += 1;
+        }
+        let start = at - line[..at].iter().rev().take_while(|c| is_word_char(**c)).count();
+        let end = at + line[at..].iter().take_while(|c| is_word_char(**c)).count();
+        if start == end {
+            return None;
+        }
+        let word = line[start..end].iter().collect::<String>();
+        let utf16 = |i: usize| line[..i].iter().map(|c| c.len_utf16()).sum();
+        Some((uri,
+              word,
+              Range {
+                  start: Position {
+                      line: pos.line,
+                      character: utf16(start),
+                  },
+                  end: Position {
+                      line: pos.line,
+                      character: utf16(end),
+                  },
+              }))
+    }
+
+    /// Locations of the `var set` actions and script commands in the agents containing the
+    /// document, which write the var named by `word`.
+    fn definition(&self, uri: &str, word: &str) -> Vec<Location> {
+        let re = regex::Regex::new(r"glop\s+var\s+set\s+([A-Za-z0-9_.]+)").unwrap();
+        let mut uris = BTreeSet::new();
+        for agent in self.agents() {
+            if agent.iter().any(|u| u == uri) {
+                uris.extend(agent);
+            }
+        }
+        uris.insert(uri.to_string());
+        let mut result = vec![];
+        for uri in uris {
+            let unit = match self.docs[&uri].unit {
+                Some(ref unit) => unit,
+                None => continue,
+            };
+            let mut spans = vec![];
+            for m in &unit.glop.matches {
+                collect_var_sets(&unit.src, m, word, &re, &mut spans);
+            }
+            result.extend(spans.into_iter().map(|span| {
+                Location {
+                    uri: uri.to_string(),
+                    range: Range {
+                        start: position(&unit.src, span.start),
+                        end: position(&unit.src, span.end),
+                    },
+                }
+            }));
+        }
+        result
+    }
+
+    /// Describe a topic by its schema, and the matches in the workspace which consume it.
+    fn hover(&self, word: &str, range: Range) -> Option<Hover> {
+        let mut uris = self.docs.keys().collect::<Vec<_>>();
+        uris.sort();
+        let mut declared = vec![];
+        let mut consumers = vec![];
+        let mut known = false;
+        for uri in uris {
+            let unit = match self.docs[uri].unit {
+                Some(ref unit) => unit,
+                None => continue,
+            };
+            let names = check::names(unit);
+            known = known || names.sent_topics.contains(word);
+            for t in unit.glop.topics.iter().filter(|t| t.name == word) {
+                declared.push(format!("{}", t));
+            }
+            for m in &unit.glop.matches {
+                collect_consumers(unit, m, word, &mut consumers);
+            }
+        }
+        if consumers.is_empty() && declared.is_empty() && !known {
+            return None;
+        }
+        let mut value = format!("**topic {}**\n", word);
+        for t in declared {
+            value.push_str(&format!("\n```glop\n{}\n```\n", t));
+        }
+        if consumers.is_empty() {
+            value.push_str("\nNot consumed by any match.");
+        } else {
+            value.push_str("\nConsumed by:\n");
+            for c in consumers {
+                value.push_str(&format!("\n- {}", c));
+            }
+        }
+        Some(Hover {
+                 contents: MarkupContent {
+                     kind: "markdown",
+                     value: value,
+                 },
+                 range: range,
+             })
+    }
+
+    /// Names of all the topics and vars seen in the workspace.
+    fn completion(&self) -> Vec<CompletionItem> {
+        let mut topics = BTreeSet::new();
+        let mut vars = BTreeSet::new();
+        for doc in self.docs.values() {
+            if let Some(ref unit) = doc.unit {
+                let names = check::names(unit);
+                topics.extend(names.sent_topics);
+                topics.extend(names.matched_topics);
+                topics.extend(names.declared_topics);
+                vars.extend(names.set_vars);
+                vars.extend(names.tested_vars);
+            }
+        }
+        let topics = topics.into_iter().map(|name| {
+            CompletionItem {
+                label: name,
+                kind: COMPLETION_EVENT,
+                detail: "topic",
+            }
+        });
+        let vars = vars.into_iter().map(|name| {
+            CompletionItem {
+                label: name,
+                kind: COMPLETION_VARIABLE,
+                detail: "var",
+            }
+        });
+        topics.chain(vars).collect()
+    }
+}
+
+/// Collect a description of each match, including nested ones, which consumes the topic.
+fn collect_consumers(unit: &Unit, m: &ast::Match, topic: &str, result: &mut Vec<String>) {
+    if m.topics().iter().any(|pattern| ast::topic_matches(pattern, topic)) {
+        let (line, _) = diagnostic::line_col(&unit.src, m.span.start);
+        let first_line = unit.src[m.span.start..].lines().next().unwrap_or("");
+        let name = Path::new(&unit.path)
+            .file_name()
+            .map(|f| f.to_string_lossy().to_string())
+            .unwrap_or(unit.path.to_string());
+        result.push(format!("`{}:{}` `{}`", name, line, first_line.trim()));
+    }
+    for a in m.actions.iter().chain(m.else_actions.iter()) {
+        if let &ast::Action::Match(ref nested) = a {
+            collect_consumers(unit, nested, topic, result);
+        }
+    }
+}
+
+/// Collect the locations of the names written by a match's `var set` actions and its scripts'
+/// `glop var set` commands, including those of nested matches, which may write the var.
+fn collect_var_sets(src: &str,
+                    m: &ast::Match,
+                    var: &str,
+                    set_re: &regex::Regex,
+                    result: &mut Vec<ast::Span>) {
+    let actions = m.actions.iter().chain(m.else_actions.iter()).filter(|a| match *a {
+        &ast::Action::Comment { .. } => false,
+        _ => true,
+    });
+    for (a, span) in actions.zip(m.action_spans.iter()) {
+        let text = &src[span.start..span.end];
+        match a {
+            &ast::Action::SetVar(ref k, _) if check::vars_overlap(&k.join("."), var) => {
+                // The name follows the `set` keyword, unless it's quoted.
+                let name = k.join(".");
+                let after = text[3..].find("set").map(|i| 3 + i + 3).unwrap_or(0);
+                let start = span.start + after + text[after..].len() -
+                            text[after..].trim_start().len();
+                if src[start..].starts_with(&name) {
+                    result.push(ast::Span {
+                                    start: start,
+                                    end: start + name.len(),
+                                });
+                } else {
+                    result.push(*span);
+                }
+            }
+            &ast::Action::Script(_) => {
+                for caps in set_re.captures_iter(text) {
+                    let name = caps.get(1).unwrap();
+                    if check::vars_overlap(name.as_str(), var) {
+                        result.push(ast::Span {
+                                        start: span.start + name.start(),
+                                        end: span.start + name.end(),
+                                    });
+                    }
+                }
+            }
+            &ast::Action::Match(ref nested) => collect_var_sets(src, nested, var, set_re, result),
+            _ => {}
+        }
+    }
+}
+
+/// Position of a byte offset in the source, with the character counted in UTF-16 code units as
+/// LSP expects.
+fn position(src: &str, offset: usize) -> Position {
+    let offset = offset.min(src.len());
+    let line_start = src[..offset].rfind('\n').map(|i| i + 1).unwrap_or(0);
+    Position {
+        line: src[..offset].matches('\n').count(),
+        character: src[line_start..offset].encode_utf16().count(),
+    }
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '.'
+}
+
+/// The file path of a `file://` URI, with percent-encoded bytes decoded.
+fn uri_path(uri: &str) -> Option<String> {
+    if !uri.starts_with("file://") {
+        return None;
+    }
+    let path = &uri["file://".len()..];
+    // A `localhost` authority is the same as none; other hosts are not local files.
+    let path = if path.starts_with("localhost/") {
+        &path["localhost".len()..]
+    } else {
+        path
+    };
+    if !path.starts_with('/') {
+        return None;
+    }
+    let bytes = path.as_bytes();
+    let mut result = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = if bytes[i] == b'%' && i + 2 < bytes.len() {
+            std::str::from_utf8(&bytes[i + 1..i + 3])
+                .ok()
+                .and_then(|h| u8::from_str_radix(h, 16).ok())
+        } else {
+            None
+        };
+        match hex {
+            Some(b) => {
+                result.push(b);
+                i += 3;
+            }
+            None => {
+                result.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(result).ok()
+}
+
+/// The `file://` URI of a path, with all but unreserved characters and `/` percent-encoded.
+fn path_uri(path: &str) -> String {
+    let mut uri = "file://".to_string();
+    for b in path.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                uri.push(b as char)
+            }
+            _ => uri.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    uri
+}
+
+/// The URI a document is kept under. File URIs are encoded the same way, whichever way the
+/// client encoded them, so they match those of the files found in the workspace.
+fn doc_uri(uri: &str) -> String {
+    uri_path(uri).map(|path| path_uri(&path)).unwrap_or(uri.to_string())
+}
+
+fn to_json<T: serde::Serialize>(value: T) -> Json {
+    serde_json::to_value(value).unwrap()
+}
+
+fn notification<T: serde::Serialize>(method: &'static str, params: T) -> Json {
+    to_json(Notification {
+                jsonrpc: "2.0",
+                method: method,
+                params: to_json(params),
+            })
+}
+
+fn error_response(id: Option<Json>, code: i32, message: &str) -> Json {
+    to_json(ErrorResponse {
+                jsonrpc: "2.0",
+                id: id.unwrap_or(Json::Null),
+                error: ResponseError {
+                    code: code,
+                    message: message.to_string(),
+                },
+            })
+}
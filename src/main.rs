@@ -15,7 +15,7 @@ extern crate xdg;
 use std::{thread, time};
 use std::collections::HashMap;
 use std::fs::File;
-use std::io::Read;
+use std::io::{Read, Write};
 use std::process::exit;
 
 use clap::{Arg, ArgMatches, App, SubCommand};
@@ -28,11 +28,15 @@ use tokio_service::Service;
 extern crate glop;
 use glop::agent;
 use glop::agent::TokenStorage;
+use glop::check;
+use glop::diagnostic::Severity;
 use glop::error::{Error, to_ioerror};
-use glop::grammar;
+use glop::format;
+use glop::lsp;
 use glop::runtime;
 use glop::runtime::Storage;
 use glop::signal_fix;
+use glop::source;
 use glop::value;
 
 type AppResult<T> = Result<T, Error>;
@@ -63,6 +67,20 @@ fn main() {
                     .long("addr")
                     .default_value("0.0.0.0:6709"))
                 .about("run the agent server")))
+        .subcommand(SubCommand::with_name("check")
+            .about("check glop source files for likely mistakes")
+            .arg(Arg::with_name("DENY_WARNINGS")
+                .long("deny-warnings")
+                .help("fail if any warnings are found"))
+            .arg(Arg::with_name("FILE").index(1).multiple(true).required(true)))
+        .subcommand(SubCommand::with_name("lsp")
+            .about("run a language server for glop sources, over stdin and stdout"))
+        .subcommand(SubCommand::with_name("fmt")
+            .about("format glop source files")
+            .arg(Arg::with_name("CHECK")
+                .long("check")
+                .help("list files that are not formatted, without changing them"))
+            .arg(Arg::with_name("FILE").index(1).multiple(true).required(true)))
         .subcommand(SubCommand::with_name("run")
             .about("run the agent interpreter")
             .arg(Arg::with_name("GLOPFILE").index(1).multiple(true).required(true)))
@@ -92,6 +110,7 @@ fn main() {
                 .arg(Arg::with_name("CONTENTS").index(3).multiple(true).required(false)))
             .subcommand(SubCommand::with_name("reply")
                 .about("reply to the sender of a topic")
+                .arg(Arg::with_name("ROLE").short("r").long("role").takes_value(true))
                 .arg(Arg::with_name("SRC_TOPIC").index(1).required(true))
                 .arg(Arg::with_name("TOPIC").index(2).required(true))
                 .arg(Arg::with_name("CONTENTS").index(3).multiple(true).required(false))))
@@ -112,12 +131,32 @@ fn main() {
                 .default_value("local"))
             .subcommand(SubCommand::with_name("add")
                 .about("add an agent")
+                .arg(Arg::with_name("TEMPLATE").short("t").long("template").takes_value(true))
                 .arg(Arg::with_name("NAME").index(1).required(true))
-                .arg(Arg::with_name("SOURCE").index(2).required(true)))
+                .arg(Arg::with_name("ARGS")
+                    .index(2)
+                    .multiple(true)
+                    .required(true)
+                    .help("source file, or parameters k=v when adding from a template")))
             .subcommand(SubCommand::with_name("remove")
                 .about("remove an agent")
                 .arg(Arg::with_name("NAME").index(1).required(true)))
             .subcommand(SubCommand::with_name("list").about("list agents"))
+            .subcommand(SubCommand::with_name("matches")
+                .about("show how an agent's matches have fared")
+                .arg(Arg::with_name("NAME").index(1).required(true)))
+            .subcommand(SubCommand::with_name("history")
+                .about("show the transactions an agent has committed")
+                .arg(Arg::with_name("SINCE")
+                    .short("s")
+                    .long("since")
+                    .takes_value(true)
+                    .help("only show transactions from this sequence number on")
+                    .validator(|v| {
+                        v.parse::<i32>().map_err(|e| e.to_string())?;
+                        Ok(())
+                    }))
+                .arg(Arg::with_name("NAME").index(1).required(true)))
             .subcommand(SubCommand::with_name("introduce")
                 .about("introduce agents")
                 .arg(Arg::with_name("NAME:ROLE").index(1).multiple(true).required(true)))
@@ -125,6 +164,15 @@ fn main() {
                 .about("send a message to an agent")
                 .arg(Arg::with_name("SOURCE").short("s").long("src").takes_value(true))
                 .arg(Arg::with_name("ROLE").short("r").long("role").takes_value(true))
+                .arg(Arg::with_name("PRIORITY")
+                    .short("p")
+                    .long("priority")
+                    .takes_value(true)
+                    .help("handle ahead of messages with a lower priority, 0 by default")
+                    .validator(|v| {
+                        v.parse::<i32>().map_err(|e| e.to_string())?;
+                        Ok(())
+                    }))
                 .arg(Arg::with_name("NAME").index(1).required(true))
                 .arg(Arg::with_name("TOPIC").index(2).required(true))
                 .arg(Arg::with_name("CONTENTS").index(3).multiple(true).required(false)))
@@ -145,7 +193,29 @@ fn main() {
                     }))
                 .arg(Arg::with_name("NAME").index(1).required(true))
                 .arg(Arg::with_name("TOPIC").index(2).required(true))
-                .arg(Arg::with_name("CONTENTS").index(3).multiple(true).required(false))));
+                .arg(Arg::with_name("CONTENTS").index(3).multiple(true).required(false))))
+        .subcommand(SubCommand::with_name("template")
+            .about("manage agent templates on an agent server")
+            .arg(Arg::with_name("REMOTE")
+                .short("r")
+                .long("remote")
+                .default_value("local"))
+            .subcommand(SubCommand::with_name("add")
+                .about("add a template")
+                .arg(Arg::with_name("NAME").index(1).required(true))
+                .arg(Arg::with_name("SOURCE").index(2).required(true)))
+            .subcommand(SubCommand::with_name("update")
+                .about("update a template")
+                .arg(Arg::with_name("INSTANCES")
+                    .short("i")
+                    .long("instances")
+                    .help("also restart instances of the template with the new source"))
+                .arg(Arg::with_name("NAME").index(1).required(true))
+                .arg(Arg::with_name("SOURCE").index(2).required(true)))
+            .subcommand(SubCommand::with_name("remove")
+                .about("remove a template")
+                .arg(Arg::with_name("NAME").index(1).required(true)))
+            .subcommand(SubCommand::with_name("list").about("list templates")));
     let app_m = app.get_matches();
     let result = match app_m.subcommand_name() {
         Some("server") => {
@@ -202,6 +272,9 @@ fn main() {
                 }
             }
         }
+        Some("check") => cmd_check(app_m.subcommand_matches("check").unwrap()),
+        Some("lsp") => cmd_lsp(),
+        Some("fmt") => cmd_fmt(app_m.subcommand_matches("fmt").unwrap()),
         Some("run") => cmd_run(app_m.subcommand_matches("run").unwrap()),
         Some("var") => {
             let sub_m = app_m.subcommand_matches("var").unwrap();
@@ -243,6 +316,8 @@ fn main() {
                 Some("add") => cmd_add(sub_m, sub_m.subcommand_matches("add").unwrap()),
                 Some("remove") => cmd_remove(sub_m, sub_m.subcommand_matches("remove").unwrap()),
                 Some("list") => cmd_list(sub_m, sub_m.subcommand_matches("list").unwrap()),
+                Some("matches") => cmd_matches(sub_m, sub_m.subcommand_matches("matches").unwrap()),
+                Some("history") => cmd_history(sub_m, sub_m.subcommand_matches("history").unwrap()),
                 Some("send") => cmd_send_agent(sub_m, sub_m.subcommand_matches("send").unwrap()),
                 Some("recv") => cmd_recv_agent(sub_m, sub_m.subcommand_matches("recv").unwrap()),
                 Some("call") => cmd_call_agent(sub_m, sub_m.subcommand_matches("call").unwrap()),
@@ -260,6 +335,28 @@ fn main() {
                 }
             }
         }
+        Some("template") => {
+            let sub_m = app_m.subcommand_matches("template").unwrap();
+            match sub_m.subcommand_name() {
+                Some("add") => cmd_template_add(sub_m, sub_m.subcommand_matches("add").unwrap()),
+                Some("update") => {
+                    cmd_template_update(sub_m, sub_m.subcommand_matches("update").unwrap())
+                }
+                Some("remove") => {
+                    cmd_template_remove(sub_m, sub_m.subcommand_matches("remove").unwrap())
+                }
+                Some("list") => cmd_template_list(sub_m, sub_m.subcommand_matches("list").unwrap()),
+                Some(subcmd) => {
+                    error!("unsupported command {}", subcmd);
+                    Err(Error::CLI(clap::Error::with_description("unsupported command",
+                                                                 clap::ErrorKind::HelpDisplayed)))
+                }
+                None => {
+                    Err(Error::CLI(clap::Error::with_description("missing subcommand",
+                                                                 clap::ErrorKind::HelpDisplayed)))
+                }
+            }
+        }
         Some(subcmd) => {
             error!("unsupported command {}", subcmd);
             Err(Error::CLI(clap::Error::with_description("unsupported command",
@@ -284,13 +381,6 @@ fn main() {
     };
 }
 
-fn read_file(path: &str) -> AppResult<String> {
-    let mut f = try!(File::open(path));
-    let mut s = String::new();
-    f.read_to_string(&mut s).map_err(Error::IO)?;
-    Ok(s)
-}
-
 fn cmd_server_init<'a>(_app_m: &ArgMatches<'a>) -> AppResult<()> {
     let client_home = client_home().map_err(Error::IO)?;
     let server_home = server_home().map_err(Error::IO)?;
@@ -348,11 +438,55 @@ fn cmd_server_run<'a>(app_m: &ArgMatches<'a>) -> AppResult<()> {
     server.run()
 }
 
+fn cmd_check<'a>(app_m: &ArgMatches<'a>) -> AppResult<()> {
+    let diags = check::check_files(&app_m.values_of("FILE").unwrap().collect())?;
+    for diag in &diags {
+        println!("{}\n", diag);
+    }
+    let deny_warnings = app_m.is_present("DENY_WARNINGS");
+    let problems = diags.iter()
+        .filter(|diag| deny_warnings || diag.severity == Severity::Error)
+        .count();
+    if problems > 0 {
+        return Err(Error::Problems(problems));
+    }
+    Ok(())
+}
+
+fn cmd_lsp() -> AppResult<()> {
+    let stdin = std::io::stdin();
+    let stdout = std::io::stdout();
+    lsp::run(&mut stdin.lock(), &mut stdout.lock())?;
+    Ok(())
+}
+
+fn cmd_fmt<'a>(app_m: &ArgMatches<'a>) -> AppResult<()> {
+    let check = app_m.is_present("CHECK");
+    let mut unformatted = 0;
+    for path in app_m.values_of("FILE").unwrap() {
+        let mut contents = String::new();
+        File::open(path).and_then(|mut f| f.read_to_string(&mut contents))?;
+        let formatted = format::format(path, &contents)?;
+        if formatted == contents {
+            continue;
+        }
+        if check {
+            println!("{}", path);
+            unformatted += 1;
+        } else {
+            File::create(path).and_then(|mut f| f.write_all(formatted.as_bytes()))?;
+        }
+    }
+    if unformatted > 0 {
+        return Err(Error::InvalidArgument(format!("{} file(s) not formatted", unformatted)));
+    }
+    Ok(())
+}
+
 fn cmd_run<'a>(app_m: &ArgMatches<'a>) -> AppResult<()> {
-    let glop_file = app_m.value_of("GLOPFILE").unwrap();
-    let glop_contents = try!(read_file(glop_file));
-    let glop = grammar::glop(&glop_contents).map_err(Error::Parse)?;
+    let glop = source::load(app_m.value_of("GLOPFILE").unwrap())?;
     let mut st = runtime::State::new("main", runtime::MemStorage::new());
+    st.set_schemas(value::Schemas::from_ast(&glop.topics));
     st.mut_storage()
         .push_msg(value::Message::new("init", value::Obj::new())
             .src_agent("user")
@@ -360,7 +494,8 @@ fn cmd_run<'a>(app_m: &ArgMatches<'a>) -> AppResult<()> {
             .dst_agent("main"))?;
     let m_excs = glop.matches
         .iter()
-        .map(|m_ast| runtime::Match::new_from_ast(&m_ast))
+        .enumerate()
+        .map(|(i, m_ast)| runtime::Match::new_at(&m_ast, i))
         .collect::<Vec<_>>();
     loop {
         for m_exc in &m_excs {
@@ -368,13 +503,13 @@ fn cmd_run<'a>(app_m: &ArgMatches<'a>) -> AppResult<()> {
                 Ok(Some(txn)) => txn,
                 Ok(None) => continue,
                 Err(e) => {
-                    error!("eval failed: {}", e);
+                    error!("eval of match {} failed: {}", m_exc, e);
                     continue;
                 }
             };
             match st.commit(&mut txn) {
                 Ok(_seq) => (),
-                Err(e) => error!("commit failed: {}", e),
+                Err(e) => error!("commit of match {} failed: {}", m_exc, e),
             };
             thread::sleep(time::Duration::from_millis(200));
         }
@@ -417,6 +552,7 @@ fn cmd_setvar<'a>(app_m: &ArgMatches<'a>) -> AppResult<()> {
         .map_err(Error::IO)?;
     match resp {
         runtime::ScriptResponse::SetVar { key: _, value: _ } => Ok(()),
+        runtime::ScriptResponse::Error(msg) => Err(Error::ErrorResponse(msg)),
         _ => Err(Error::BadResponse),
     }
 }
@@ -434,6 +570,7 @@ fn cmd_unsetvar<'a>(app_m: &ArgMatches<'a>) -> AppResult<()> {
         .map_err(Error::IO)?;
     match resp {
         runtime::ScriptResponse::UnsetVar { key: _ } => Ok(()),
+        runtime::ScriptResponse::Error(msg) => Err(Error::ErrorResponse(msg)),
         _ => Err(Error::BadResponse),
     }
 }
@@ -464,14 +601,103 @@ fn cmd_getmsg<'a>(app_m: &ArgMatches<'a>) -> AppResult<()> {
 fn cmd_add<'a>(app_m: &ArgMatches<'a>, sub_m: &ArgMatches<'a>) -> AppResult<()> {
     let client_home = client_home()?;
     let client = agent::Client::new(&client_home)?;
-    let contents = read_file(sub_m.value_of("SOURCE").unwrap())?;
+    let name = sub_m.value_of("NAME").unwrap().to_string();
+    let req = match sub_m.value_of("TEMPLATE") {
+        Some(template) => {
+            agent::Request::Instantiate {
+                name: name,
+                template: template.to_string(),
+                params: value::Value::from_flat_map(kv_map(sub_m.values_of("ARGS"))),
+            }
+        }
+        None => {
+            let args = sub_m.values_of("ARGS").unwrap().collect::<Vec<_>>();
+            if args.len() != 1 {
+                return Err(Error::InvalidArgument("expected a single source file".to_string()));
+            }
+            agent::Request::Add {
+                contents: load_bundle(args[0])?,
+                name: name,
+            }
+        }
+    };
+    let resp = client.call(app_m.value_of("REMOTE").unwrap(), req)?;
+    match resp {
+        agent::Response::Add |
+        agent::Response::Instantiate => Ok(()),
+        agent::Response::Error(msg) => Err(Error::ErrorResponse(msg)),
+        _ => Err(Error::BadResponse),
+    }
+}
+
+/// Load a glop source file, bundling included sources so that the server receives a
+/// self-contained definition.
+fn load_bundle(path: &str) -> AppResult<String> {
+    let glop = source::load(path)?;
+    Ok(format!("{}", glop))
+}
+
+fn cmd_template_add<'a>(app_m: &ArgMatches<'a>, sub_m: &ArgMatches<'a>) -> AppResult<()> {
+    let client_home = client_home()?;
+    let client = agent::Client::new(&client_home)?;
     let resp = client.call(app_m.value_of("REMOTE").unwrap(),
-              agent::Request::Add {
-                  contents: contents,
+              agent::Request::AddTemplate {
+                  contents: load_bundle(sub_m.value_of("SOURCE").unwrap())?,
                   name: sub_m.value_of("NAME").unwrap().to_string(),
               })?;
     match resp {
-        agent::Response::Add => Ok(()),
+        agent::Response::AddTemplate => Ok(()),
+        agent::Response::Error(msg) => Err(Error::ErrorResponse(msg)),
+        _ => Err(Error::BadResponse),
+    }
+}
+
+fn cmd_template_update<'a>(app_m: &ArgMatches<'a>, sub_m: &ArgMatches<'a>) -> AppResult<()> {
+    let client_home = client_home()?;
+    let client = agent::Client::new(&client_home)?;
+    let resp = client.call(app_m.value_of("REMOTE").unwrap(),
+              agent::Request::UpdateTemplate {
+                  contents: load_bundle(sub_m.value_of("SOURCE").unwrap())?,
+                  name: sub_m.value_of("NAME").unwrap().to_string(),
+                  update_instances: sub_m.is_present("INSTANCES"),
+              })?;
+    match resp {
+        agent::Response::UpdateTemplate { ref instances } => {
+            for name in instances {
+                println!("{}", name);
+            }
+            Ok(())
+        }
+        agent::Response::Error(msg) => Err(Error::ErrorResponse(msg)),
+        _ => Err(Error::BadResponse),
+    }
+}
+
+fn cmd_template_remove<'a>(app_m: &ArgMatches<'a>, sub_m: &ArgMatches<'a>) -> AppResult<()> {
+    let client_home = client_home()?;
+    let client = agent::Client::new(&client_home)?;
+    let resp = client.call(app_m.value_of("REMOTE").unwrap(),
+              agent::Request::RemoveTemplate {
+                  name: sub_m.value_of("NAME").unwrap().to_string(),
+              })?;
+    match resp {
+        agent::Response::RemoveTemplate => Ok(()),
+        agent::Response::Error(msg) => Err(Error::ErrorResponse(msg)),
+        _ => Err(Error::BadResponse),
+    }
+}
+
+fn cmd_template_list<'a>(app_m: &ArgMatches<'a>, _sub_m: &ArgMatches<'a>) -> AppResult<()> {
+    let client_home = client_home()?;
+    let client = agent::Client::new(&client_home)?;
+    let resp = client.call(app_m.value_of("REMOTE").unwrap(), agent::Request::ListTemplates)?;
+    match resp {
+        agent::Response::ListTemplates { ref names } => {
+            for name in names {
+                println!("{}", name);
+            }
+            Ok(())
+        }
         agent::Response::Error(msg) => Err(Error::ErrorResponse(msg)),
         _ => Err(Error::BadResponse),
     }
@@ -505,6 +731,83 @@ fn cmd_list<'a>(app_m: &ArgMatches<'a>, _sub_m: &ArgMatches<'a>) -> AppResult<()
     }
 }
 
+fn cmd_matches<'a>(app_m: &ArgMatches<'a>, sub_m: &ArgMatches<'a>) -> AppResult<()> {
+    let client_home = client_home()?;
+    let client = agent::Client::new(&client_home)?;
+    let resp = client.call(app_m.value_of("REMOTE").unwrap(),
+              agent::Request::Matches { name: sub_m.value_of("NAME").unwrap().to_string() })?;
+    match resp {
+        agent::Response::Matches { ref matches } => {
+            for m in matches {
+                println!("{}: fired {}, failed {}, evaluated {}",
+                         m.name,
+                         m.fired,
+                         m.failed,
+                         m.evaluated);
+                if let Some(ref description) = m.description {
+                    println!("    {}", description);
+                }
+                if let Some(ref e) = m.last_error {
+                    println!("    last error: {}", e);
+                }
+            }
+            Ok(())
+        }
+        agent::Response::Error(msg) => Err(Error::ErrorResponse(msg)),
+        _ => Err(Error::BadResponse),
+    }
+}
+
+fn cmd_history<'a>(app_m: &ArgMatches<'a>, sub_m: &ArgMatches<'a>) -> AppResult<()> {
+    let client_home = client_home()?;
+    let client = agent::Client::new(&client_home)?;
+    let since_seq = sub_m.value_of("SINCE").map_or(0, |v| v.parse::<i32>().unwrap());
+    let resp = client.call(app_m.value_of("REMOTE").unwrap(),
+                           agent::Request::History {
+                               name: sub_m.value_of("NAME").unwrap().to_string(),
+                               since_seq: since_seq,
+                           })?;
+    match resp {
+        agent::Response::History { ref entries } => {
+            for entry in entries {
+                println!("{} at {}: {}", entry.seq, entry.time, entry.name);
+                for id in &entry.msgs {
+                    println!("    consumed {}", id);
+                }
+                for script in &entry.scripts {
+                    let kind = if script.check { "check" } else { "script" };
+                    match script.exit_status {
+                        Some(code) => {
+                            println!("    {} exited {} in {}ms", kind, code, script.elapsed_ms)
+                        }
+                        None => {
+                            println!("    {} killed or failed to run in {}ms",
+                                     kind,
+                                     script.elapsed_ms)
+                        }
+                    }
+                }
+                for action in &entry.actions {
+                    match action {
+                        &runtime::JournalAction::SetVar { ref name, ref value } => {
+                            println!("    var set {} {}", name, value.to_string())
+                        }
+                        &runtime::JournalAction::UnsetVar { ref name } => {
+                            println!("    var unset {}", name)
+                        }
+                        &runtime::JournalAction::SendMsg { ref id, ref dst_agent, ref topic } => {
+                            println!("    msg send {} {} ({})", dst_agent, topic, id)
+                        }
+                    }
+                }
+            }
+            Ok(())
+        }
+        agent::Response::Error(msg) => Err(Error::ErrorResponse(msg)),
+        _ => Err(Error::BadResponse),
+    }
+}
+
 fn cmd_send_agent<'a>(app_m: &ArgMatches<'a>, sub_m: &ArgMatches<'a>) -> AppResult<()> {
     let client_home = client_home()?;
     let client = agent::Client::new(&client_home)?;
@@ -517,9 +820,16 @@ fn send_agent<'a>(client: &agent::Client,
                   app_m: &ArgMatches<'a>,
                   sub_m: &ArgMatches<'a>)
                   -> AppResult<String> {
-    let contents = kv_map(sub_m.values_of("CONTENTS"));
-    let msg = value::Message::new(sub_m.value_of("TOPIC").unwrap(),
-                                  value::Value::from_flat_map(contents))
+    let remote = app_m.value_of("REMOTE").unwrap();
+    let topic = sub_m.value_of("TOPIC").unwrap();
+    let mut contents = value::Value::from_flat_map(kv_map(sub_m.values_of("CONTENTS")));
+    // Parse arguments into the types the destination agent declares for the topic.
+    let resp = client.call(remote,
+              agent::Request::Schema { name: sub_m.value_of("NAME").unwrap().to_string() })?;
+    if let agent::Response::Schema { ref topics } = resp {
+        contents = value::Schemas::from_ast(topics).coerce(topic, contents)?;
+    }
+    let msg = value::Message::new(topic, contents)
         .src_agent(if let Some(ref src) = sub_m.value_of("SOURCE") {
             src
         } else {
@@ -530,8 +840,8 @@ fn send_agent<'a>(client: &agent::Client,
         } else {
             None
         })
-        .dst_agent(sub_m.value_of("NAME").unwrap());
-    let remote = app_m.value_of("REMOTE").unwrap();
+        .dst_agent(sub_m.value_of("NAME").unwrap())
+        .priority(sub_m.value_of("PRIORITY").map_or(0, |p| p.parse().unwrap()));
     let msg_id = msg.id.clone();
     let resp = client.call(remote, agent::Request::SendTo(msg))?;
     match resp {
@@ -650,6 +960,7 @@ fn cmd_send_script<'a>(app_m: &ArgMatches<'a>) -> AppResult<()> {
         dst_agent: app_m.value_of("NAME").unwrap().to_string(),
         topic: app_m.value_of("TOPIC").unwrap().to_string(),
         contents: value::Value::from_flat_map(contents),
+        src_role: app_m.value_of("ROLE").map(|role| role.to_string()),
     };
     let proto = runtime::ScriptClientProto::new_from_env()?;
     let builder = TcpClient::new(proto);
@@ -658,6 +969,7 @@ fn cmd_send_script<'a>(app_m: &ArgMatches<'a>) -> AppResult<()> {
         .map_err(Error::IO)?;
     match resp {
         runtime::ScriptResponse::SendMsg { dst_remote: _, dst_agent: _, topic: _ } => Ok(()),
+        runtime::ScriptResponse::Error(msg) => Err(Error::ErrorResponse(msg)),
         _ => Err(Error::BadResponse),
     }
 }
@@ -672,6 +984,7 @@ fn cmd_reply_script<'a>(app_m: &ArgMatches<'a>) -> AppResult<()> {
         src_topic: app_m.value_of("SRC_TOPIC").unwrap().to_string(),
         topic: app_m.value_of("TOPIC").unwrap().to_string(),
         contents: value::Value::from_flat_map(contents),
+        src_role: app_m.value_of("ROLE").map(|role| role.to_string()),
     };
     let proto = runtime::ScriptClientProto::new_from_env()?;
     let builder = TcpClient::new(proto);
@@ -680,6 +993,7 @@ fn cmd_reply_script<'a>(app_m: &ArgMatches<'a>) -> AppResult<()> {
         .map_err(Error::IO)?;
     match resp {
         runtime::ScriptResponse::SendMsg { dst_remote: _, dst_agent: _, topic: _ } => Ok(()),
+        runtime::ScriptResponse::Error(msg) => Err(Error::ErrorResponse(msg)),
         _ => Err(Error::BadResponse),
     }
 }
@@ -2,26 +2,35 @@ use std;
 use std::collections::HashMap;
 use std::process::Command;
 
-use super::value::{Identifier, Message, Value};
+use super::value::{Identifier, Message, Schemas, Value};
 
 pub struct Context {
     pub vars: HashMap<String, Value>,
     pub msgs: HashMap<String, Message>,
+    pub timers: HashMap<String, u64>,
+    pub now: u64,
     pub src: String,
     pub workspace: String,
+    pub schemas: Schemas,
 }
 
 impl Context {
     pub fn new(src: &str,
                vars: HashMap<String, Value>,
                msgs: HashMap<String, Message>,
-               workspace: &str)
+               timers: HashMap<String, u64>,
+               now: u64,
+               workspace: &str,
+               schemas: Schemas)
                -> Context {
         Context {
             vars: vars,
             msgs: msgs,
+            timers: timers,
+            now: now,
             src: src.to_string(),
             workspace: workspace.to_string(),
+            schemas: schemas,
         }
     }
 
@@ -30,6 +39,8 @@ impl Context {
             cmd.env(k, v);
         }
         for (topic, msg) in &self.msgs {
+            // The topic the message was sent on, which may be one of several a pattern matches.
+            cmd.env(format!("GLOP_TOPIC__{}", topic), &msg.topic);
             for (k, v) in Value::to_env(&msg.contents) {
                 cmd.env(vec![topic.clone(), k.to_string()].join("__"), v);
             }
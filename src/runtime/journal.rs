@@ -0,0 +1,42 @@
+use super::value::Value;
+
+/// Record of a transaction an agent committed.
+#[derive(Serialize, Deserialize)]
+#[derive(Clone, Debug)]
+pub struct JournalEntry {
+    /// Sequence number of the transaction.
+    pub seq: i32,
+    /// Name of the match, or its conditions if it is unnamed.
+    pub name: String,
+    /// When the match was evaluated, in seconds since the epoch.
+    pub time: u64,
+    /// IDs of the messages consumed.
+    pub msgs: Vec<String>,
+    pub actions: Vec<JournalAction>,
+    /// Scripts run while evaluating and applying the match, in the order they ran.
+    pub scripts: Vec<ScriptRun>,
+}
+
+/// An action taken by a committed transaction.
+#[derive(Serialize, Deserialize)]
+#[derive(Clone, Debug)]
+pub enum JournalAction {
+    SetVar { name: String, value: Value },
+    UnsetVar { name: String },
+    SendMsg {
+        id: String,
+        dst_agent: String,
+        topic: String,
+    },
+}
+
+/// A script run by a transaction.
+#[derive(Serialize, Deserialize)]
+#[derive(Clone, Debug)]
+pub struct ScriptRun {
+    /// Whether the script was that of a check condition, rather than an action.
+    pub check: bool,
+    /// Status the script exited with, or none if it was killed or failed to run.
+    pub exit_status: Option<i32>,
+    pub elapsed_ms: u64,
+}
@@ -5,14 +5,18 @@ use super::error;
 use super::value;
 
 mod context;
+mod journal;
 mod model;
 mod script;
 mod state;
+mod timer;
 mod transaction;
 
 pub use self::error::{Error, Result};
-pub use self::model::{Action, Condition, CmpOpcode, Match, MessageFilter};
-pub use self::state::{DurableStorage, MemStorage, Outbox, State, Storage};
+pub use self::journal::{JournalAction, JournalEntry, ScriptRun};
+pub use self::model::{Action, Condition, CmpOpcode, Expr, ExprOpcode, Match, MessageFilter, Ref};
+pub use self::state::{DurableStorage, MemStorage, Outbox, SaveStep, State, Storage};
+pub use self::timer::{Clock, SystemClock, Timer};
 pub use self::script::Request as ScriptRequest;
 pub use self::script::Response as ScriptResponse;
 pub use self::script::ClientProto as ScriptClientProto;
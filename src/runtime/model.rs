@@ -1,14 +1,30 @@
-use std::collections::HashSet;
+extern crate regex;
+
+use std;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
 
 use super::*;
+use self::context::Context;
+use self::timer::Timer;
 use value::{Identifier, Obj, Value};
 
+/// Seconds a check condition's script may run for, unless the condition sets a timeout.
+const CHECK_TIMEOUT_SECS: u64 = 10;
+
 #[derive(Clone, Debug)]
 pub struct Match {
+    pub name: Option<String>,
+    pub description: Option<String>,
     pub conditions: Vec<Condition>,
     pub actions: Vec<Action>,
-    pub acting_role: Option<String>,
+    /// Role that messages sent from scripts are sent from, unless they name one, or what's
+    /// expected of them when there is more than one to choose from.
+    pub acting_role: std::result::Result<Option<String>, &'static str>,
+    pub else_actions: Vec<Action>,
     msg_filters: HashSet<MessageFilter>,
+    /// Source of the match's conditions, describing the match when it has no name.
+    conditions_src: String,
 }
 
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
@@ -20,46 +36,61 @@ pub struct MessageFilter {
 impl Match {
     fn new() -> Match {
         Match {
+            name: None,
+            description: None,
             conditions: vec![],
             msg_filters: HashSet::new(),
+            conditions_src: String::new(),
             actions: vec![],
-            acting_role: None,
+            acting_role: Ok(None),
+            else_actions: vec![],
         }
     }
 
     pub fn new_from_ast(m_ast: &ast::Match) -> Match {
+        Match::new_at(m_ast, 0)
+    }
+
+    /// Convert the match at an index among the matches of its source.
+    pub fn new_at(m_ast: &ast::Match, index: usize) -> Match {
+        Match::new_in_scope(m_ast, &ast::RoleScope::default(), &format!("#{}", index))
+    }
+
+    /// Convert a match, whose position in its source is described by `position`.
+    fn new_in_scope(m_ast: &ast::Match, parent_scope: &ast::RoleScope, position: &str) -> Match {
+        let scope = parent_scope.enter(m_ast);
         let mut m_exc = Match::new();
+        m_exc.name = m_ast.name.clone();
+        m_exc.description = m_ast.description.clone();
+        m_exc.conditions_src = m_ast
+            .conditions
+            .iter()
+            .map(|c_ast| format!("{}", c_ast))
+            .collect::<Vec<_>>()
+            .join(", ");
+        // Timers are keyed by the name of the match they appear in, or else its position in
+        // the source, so that their last-fired times survive restarts and matches with the
+        // same conditions keep separate times.
+        let timer_key = m_ast.name.clone().unwrap_or(position.to_string());
         m_exc.conditions = m_ast
             .conditions
             .iter()
             .map(|c_ast| {
-                if let &ast::Condition::Message {
-                            ref topic,
-                            ref src_role,
-                            acting_role: _,
-                        } = c_ast {
-                    m_exc
-                        .msg_filters
-                        .insert(MessageFilter {
-                                    topic: topic.to_string(),
-                                    src_role: src_role.clone(),
-                                });
-                }
-                Condition::new(c_ast)
-            })
+                     collect_filters(c_ast, &mut m_exc.msg_filters);
+                     Condition::new(c_ast, &timer_key)
+                 })
             .collect();
-        m_exc.actions = m_ast
-            .actions
-            .iter()
-            .map(|a_ast| Action::new(a_ast))
-            .collect();
-        m_exc.acting_role = m_ast.acting_role.clone();
+        m_exc.actions = Action::new_block(&m_ast.actions, &scope, &format!("{}/", timer_key));
+        m_exc.acting_role = scope.send_role(&None);
+        m_exc.else_actions = Action::new_block(&m_ast.else_actions,
+                                               parent_scope,
+                                               &format!("{}/else/", timer_key));
         m_exc
     }
 
     pub fn filters(&self) -> HashSet<MessageFilter> {
         let mut result = self.msg_filters.clone();
-        for action in &self.actions {
+        for action in self.actions.iter().chain(self.else_actions.iter()) {
             if let &Action::Match(ref m) = action {
                 result.extend(m.filters());
             }
@@ -67,42 +98,160 @@ impl Match {
         result
     }
 
-    pub fn topics(&self) -> HashSet<String> {
-        self.conditions
-            .iter()
-            .filter_map(|c| if let &Condition::Message {
-                                        ref topic,
-                                        src_role: _,
-                                        acting_role: _,
-                                    } = c {
-                            Some(topic.to_string())
-                        } else {
-                            None
-                        })
-            .collect()
+    /// Topics of the messages the match cannot fire without, which may be patterns.
+    pub fn required_topics(&self) -> Vec<&str> {
+        let mut result = vec![];
+        collect_required(&self.conditions, &mut result);
+        result
+    }
+
+    /// Whether a condition of the match, or of a match nested in it, runs a check script.
+    pub fn has_checks(&self) -> bool {
+        self.conditions.iter().any(|c| c.is_costly()) ||
+        self.actions.iter().chain(self.else_actions.iter()).any(|a| match a {
+            &Action::Match(ref m) => m.has_checks(),
+            _ => false,
+        })
+    }
+
+    /// Earliest time at which a timer condition of the match, or of a match nested in it, is
+    /// due, given the times timers last fired and the current time.
+    pub fn due_at(&self, timers: &HashMap<String, u64>, now: u64) -> Option<u64> {
+        let mut result = None;
+        for c in &self.conditions {
+            c.due_at(timers, now, &mut result);
+        }
+        for action in self.actions.iter().chain(self.else_actions.iter()) {
+            if let &Action::Match(ref m) = action {
+                if let Some(t) = m.due_at(timers, now) {
+                    result = Some(result.map_or(t, |r: u64| r.min(t)));
+                }
+            }
+        }
+        result
+    }
+}
+
+fn collect_required<'a>(conditions: &'a Vec<Condition>, result: &mut Vec<&'a str>) {
+    for c in conditions {
+        match c {
+            &Condition::Message { ref topic, .. } => result.push(topic),
+            &Condition::And(ref cs) => collect_required(cs, result),
+            _ => {}
+        }
+    }
+}
+
+impl std::fmt::Display for Match {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.name {
+            Some(ref name) => write!(f, "\"{}\"", name),
+            None => write!(f, "when ({})", self.conditions_src),
+        }
+    }
+}
+
+/// Collect the filters for all messages a condition may refer to, including negated ones.
+fn collect_filters(c_ast: &ast::Condition, result: &mut HashSet<MessageFilter>) {
+    match c_ast {
+        &ast::Condition::Message {
+             ref topic,
+             ref src_role,
+             acting_role: _,
+         } => {
+            result.insert(MessageFilter {
+                              topic: topic.to_string(),
+                              src_role: src_role.clone(),
+                          });
+        }
+        &ast::Condition::Not(ref c) => collect_filters(c, result),
+        &ast::Condition::Or(ref cs) |
+        &ast::Condition::And(ref cs) => {
+            for c in cs {
+                collect_filters(c, result);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// A reference to a var, or to a field of a message in the transaction context.
+#[derive(Clone, Debug)]
+pub enum Ref {
+    Var(Identifier),
+    Msg(String, Identifier),
+}
+
+impl Ref {
+    fn new(r_ast: &ast::Ref) -> Ref {
+        match r_ast {
+            &ast::Ref::Var(ref k) => Ref::Var(Identifier::from_ast(k)),
+            &ast::Ref::Msg(ref topic, ref k) => {
+                Ref::Msg(topic.to_string(), Identifier::from_ast(k))
+            }
+        }
+    }
+
+    pub fn get<'a>(&self, ctx: &'a mut Context) -> Option<&'a Value> {
+        match self {
+            &Ref::Var(ref k) => ctx.get_var(k),
+            &Ref::Msg(ref topic, ref k) => ctx.get_msg(topic, k),
+        }
+    }
+}
+
+impl std::fmt::Display for Ref {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            &Ref::Var(ref k) => write!(f, "{}", k),
+            &Ref::Msg(ref topic, ref k) => write!(f, "{}.{}", topic, k),
+        }
     }
 }
 
 #[derive(Clone, Debug)]
 pub enum Condition {
-    Cmp(Identifier, CmpOpcode, String),
-    IsSet(Identifier),
-    IsUnset(Identifier),
+    Cmp(Ref, CmpOpcode, Value),
+    CmpRef(Ref, CmpOpcode, Ref),
+    /// Match a ref against a regular expression, compiled once when the condition is built.
+    Regex(Ref, regex::Regex),
+    IsSet(Ref),
+    IsUnset(Ref),
     Message {
         topic: String,
         src_role: Option<String>,
         acting_role: Option<String>,
     },
+    Timer { key: String, timer: Timer },
+    Check { script: String, timeout: u64 },
+    Not(Box<Condition>),
+    Or(Vec<Condition>),
+    And(Vec<Condition>),
 }
 
 impl Condition {
-    fn new(c_ast: &ast::Condition) -> Condition {
+    fn new(c_ast: &ast::Condition, timer_key: &str) -> Condition {
         match c_ast {
+            &ast::Condition::Cmp(ref l, ast::CmpOpcode::Match, ast::Literal::Str(ref pattern)) => {
+                // Patterns are checked when parsed, so one that fails to compile here can
+                // only have been stored by an older version; it never matches.
+                match regex::Regex::new(pattern) {
+                    Ok(re) => Condition::Regex(Ref::new(l), re),
+                    Err(_) => {
+                        Condition::Cmp(Ref::new(l),
+                                       CmpOpcode::Match,
+                                       Value::from_literal(&ast::Literal::Str(pattern.to_string())))
+                    }
+                }
+            }
             &ast::Condition::Cmp(ref l, ref op, ref r) => {
-                Condition::Cmp(Identifier::from_ast(l), CmpOpcode::new(op), r.to_string())
+                Condition::Cmp(Ref::new(l), CmpOpcode::new(op), Value::from_literal(r))
             }
-            &ast::Condition::IsSet(ref k) => Condition::IsSet(Identifier::from_ast(k)),
-            &ast::Condition::IsUnset(ref k) => Condition::IsUnset(Identifier::from_ast(k)),
+            &ast::Condition::CmpRef(ref l, ref op, ref r) => {
+                Condition::CmpRef(Ref::new(l), CmpOpcode::new(op), Ref::new(r))
+            }
+            &ast::Condition::IsSet(ref k) => Condition::IsSet(Ref::new(k)),
+            &ast::Condition::IsUnset(ref k) => Condition::IsUnset(Ref::new(k)),
             &ast::Condition::Message {
                  ref topic,
                  ref src_role,
@@ -114,6 +263,56 @@ impl Condition {
                     acting_role: acting_role.clone(),
                 }
             }
+            &ast::Condition::Elapsed(_) |
+            &ast::Condition::Every(_) |
+            &ast::Condition::At(_, _) => {
+                Condition::Timer {
+                    key: timer_key.to_string(),
+                    timer: Timer::new(c_ast).unwrap(),
+                }
+            }
+            &ast::Condition::Check { ref script, ref timeout } => {
+                Condition::Check {
+                    script: script.to_string(),
+                    timeout: timeout.unwrap_or(CHECK_TIMEOUT_SECS),
+                }
+            }
+            &ast::Condition::Not(ref c) => Condition::Not(Box::new(Condition::new(c, timer_key))),
+            &ast::Condition::Or(ref cs) => {
+                Condition::Or(cs.iter().map(|c| Condition::new(c, timer_key)).collect())
+            }
+            &ast::Condition::And(ref cs) => {
+                Condition::And(cs.iter().map(|c| Condition::new(c, timer_key)).collect())
+            }
+        }
+    }
+
+    fn due_at(&self, timers: &HashMap<String, u64>, now: u64, result: &mut Option<u64>) {
+        match self {
+            &Condition::Timer { ref key, ref timer } => {
+                let t = timer.next_due(timers.get(key).cloned(), now);
+                *result = Some(result.map_or(t, |r| r.min(t)));
+            }
+            &Condition::Not(ref c) => c.due_at(timers, now, result),
+            &Condition::Or(ref cs) |
+            &Condition::And(ref cs) => {
+                for c in cs {
+                    c.due_at(timers, now, result);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Whether the condition runs a script to be evaluated, which is best left until the
+    /// cheaper conditions of a match hold.
+    pub fn is_costly(&self) -> bool {
+        match self {
+            &Condition::Check { .. } => true,
+            &Condition::Not(ref c) => c.is_costly(),
+            &Condition::Or(ref cs) |
+            &Condition::And(ref cs) => cs.iter().any(|c| c.is_costly()),
+            _ => false,
         }
     }
 }
@@ -122,6 +321,11 @@ impl Condition {
 pub enum CmpOpcode {
     Equal,
     NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    Match,
 }
 
 impl CmpOpcode {
@@ -129,49 +333,252 @@ impl CmpOpcode {
         match c_ast {
             &ast::CmpOpcode::Equal => CmpOpcode::Equal,
             &ast::CmpOpcode::NotEqual => CmpOpcode::NotEqual,
+            &ast::CmpOpcode::Less => CmpOpcode::Less,
+            &ast::CmpOpcode::LessEqual => CmpOpcode::LessEqual,
+            &ast::CmpOpcode::Greater => CmpOpcode::Greater,
+            &ast::CmpOpcode::GreaterEqual => CmpOpcode::GreaterEqual,
+            &ast::CmpOpcode::Match => CmpOpcode::Match,
         }
     }
 
-    pub fn eval(&self, l: &Value, r: &str) -> bool {
-        let v = match l {
-            &Value::Int(i) => i.to_string(),
-            &Value::Str(ref s) => s.to_string(),
-            _ => {
-                return false;
-            } 
+    /// Compare two values. A `=~` comparison compiles the pattern on each evaluation, so it is
+    /// only used where the pattern is not known until then.
+    ///
+    /// Values are compared as integers when either side is an integer and the other side is
+    /// or parses as one, so a var set from a script compares numerically with an integer
+    /// literal but lexically with a quoted string. Objects do not compare.
+    pub fn eval(&self, l: &Value, r: &Value) -> bool {
+        let (ls, rs) = match (l, r) {
+            (&Value::Object(_), _) |
+            (_, &Value::Object(_)) => return false,
+            _ => (l.to_string(), r.to_string()),
+        };
+        if let &CmpOpcode::Match = self {
+            return match regex::Regex::new(&rs) {
+                Ok(re) => re.is_match(&ls),
+                Err(_) => false,
+            };
+        }
+        let typed_int = match (l, r) {
+            (&Value::Int(_), _) |
+            (_, &Value::Int(_)) => true,
+            _ => false,
+        };
+        let ord = match (l.as_int(), r.as_int()) {
+            (Some(li), Some(ri)) if typed_int => li.cmp(&ri),
+            _ => ls.cmp(&rs),
+        };
+        match self {
+            &CmpOpcode::Equal => ord == Ordering::Equal,
+            &CmpOpcode::NotEqual => ord != Ordering::Equal,
+            &CmpOpcode::Less => ord == Ordering::Less,
+            &CmpOpcode::LessEqual => ord != Ordering::Greater,
+            &CmpOpcode::Greater => ord == Ordering::Greater,
+            &CmpOpcode::GreaterEqual => ord != Ordering::Less,
+            &CmpOpcode::Match => unreachable!(),
+        }
+    }
+
+    /// Compare two values which may be unset. An unset value is equal only to another unset
+    /// value. Objects are equal when all their fields are. Ordering or matching an unset value
+    /// or an object has no answer, and gives none.
+    pub fn eval_maybe(&self, l: Option<&Value>, r: Option<&Value>) -> Option<bool> {
+        let equal = match (l, r) {
+            (Some(&Value::Object(_)), _) |
+            (_, Some(&Value::Object(_))) |
+            (None, _) |
+            (_, None) => l == r,
+            (Some(l), Some(r)) => return Some(self.eval(l, r)),
         };
         match self {
-            &CmpOpcode::Equal => v == r,
-            &CmpOpcode::NotEqual => v != r,
+            &CmpOpcode::Equal => Some(equal),
+            &CmpOpcode::NotEqual => Some(!equal),
+            _ => None,
         }
     }
 }
 
 #[derive(Clone, Debug)]
 pub enum Action {
-    SetVar(Identifier, String),
+    SetVar(Identifier, Value),
     UnsetVar(Identifier),
     Script(String),
     Match(Match),
+    /// Set a var to the value of an expression, evaluated when applied.
+    Set(Identifier, Expr),
     SendMsg {
         dst_remote: Option<String>,
         dst_agent: String,
         topic: String,
         in_reply_to: Option<String>,
         contents: Obj,
+        src_role: Option<String>,
+    },
+    /// Send a message from a role, or fail with what's expected when the role is ambiguous.
+    /// Parsed sources are checked for these; sources saved before may still have them.
+    Send {
+        dst_agent: String,
+        topic: String,
+        contents: Vec<(Identifier, Expr)>,
+        src_role: std::result::Result<Option<String>, &'static str>,
+    },
+    ReplyTo {
+        src_topic: String,
+        topic: String,
+        contents: Vec<(Identifier, Expr)>,
+        src_role: std::result::Result<Option<String>, &'static str>,
     },
-    ReplyTo { topic: String, contents: Obj },
 }
 
 impl Action {
-    fn new(a_ast: &ast::Action) -> Action {
-        match a_ast {
-            &ast::Action::SetVar(ref k, ref v) => {
-                Action::SetVar(Identifier::from_ast(k), v.to_string())
-            }
+    /// Convert a block of actions, nested matches being positioned by `prefix` and their index
+    /// in the block.
+    fn new_block(actions: &Vec<ast::Action>, scope: &ast::RoleScope, prefix: &str) -> Vec<Action> {
+        actions.iter()
+            .enumerate()
+            .filter_map(|(i, a_ast)| Action::new(a_ast, scope, &format!("{}{}", prefix, i)))
+            .collect()
+    }
+
+    /// Convert an action from glop source, resolving the roles messages are sent from.
+    /// Comments have no effect, so there is nothing to convert them into.
+    fn new(a_ast: &ast::Action, scope: &ast::RoleScope, position: &str) -> Option<Action> {
+        Some(match a_ast {
+            &ast::Action::SetVar(ref k, ref v) => Action::Set(Identifier::from_ast(k), Expr::new(v)),
             &ast::Action::UnsetVar(ref k) => Action::UnsetVar(Identifier::from_ast(k)),
             &ast::Action::Script(ref contents) => Action::Script(contents.to_string()),
-            &ast::Action::Match(ref m) => Action::Match(Match::new_from_ast(m)),
+            &ast::Action::Match(ref m) => Action::Match(Match::new_in_scope(m, scope, position)),
+            &ast::Action::SendMsg {
+                 ref dst_agent,
+                 ref topic,
+                 ref contents,
+                 ref src_role,
+             } => {
+                Action::Send {
+                    dst_agent: dst_agent.to_string(),
+                    topic: topic.to_string(),
+                    contents: Expr::new_contents(contents),
+                    src_role: scope.send_role(src_role),
+                }
+            }
+            &ast::Action::ReplyMsg {
+                 ref src_topic,
+                 ref topic,
+                 ref contents,
+                 ref src_role,
+             } => {
+                Action::ReplyTo {
+                    src_topic: src_topic.to_string(),
+                    topic: topic.to_string(),
+                    contents: Expr::new_contents(contents),
+                    src_role: scope.reply_role(src_topic, src_role),
+                }
+            }
+            &ast::Action::Comment { .. } => return None,
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Expr {
+    Value(Value),
+    Ref(Ref),
+    BinOp(Box<Expr>, ExprOpcode, Box<Expr>),
+}
+
+impl Expr {
+    fn new(e_ast: &ast::Expr) -> Expr {
+        match e_ast {
+            &ast::Expr::Literal(ref l) => Expr::Value(Value::from_literal(l)),
+            &ast::Expr::Ref(ref r) => Expr::Ref(Ref::new(r)),
+            &ast::Expr::BinOp(ref l, ref op, ref r) => {
+                Expr::BinOp(Box::new(Expr::new(l)),
+                            ExprOpcode::new(op),
+                            Box::new(Expr::new(r)))
+            }
+        }
+    }
+
+    fn new_contents(contents: &Vec<(ast::Identifier, ast::Expr)>) -> Vec<(Identifier, Expr)> {
+        contents.iter()
+            .map(|&(ref k, ref v)| (Identifier::from_ast(k), Expr::new(v)))
+            .collect()
+    }
+
+    pub fn eval(&self, ctx: &mut Context) -> Result<Value> {
+        match self {
+            &Expr::Value(ref v) => Ok(v.clone()),
+            &Expr::Ref(ref r) => {
+                match r.get(ctx) {
+                    Some(v) => Ok(v.clone()),
+                    None => Err(Error::Eval(format!("{} is not set", r))),
+                }
+            }
+            &Expr::BinOp(ref l, ref op, ref r) => {
+                let l = l.eval(ctx)?;
+                let r = r.eval(ctx)?;
+                op.eval(&l, &r)
+            }
+        }
+    }
+
+    /// Evaluate message contents into an object.
+    pub fn eval_contents(contents: &Vec<(Identifier, Expr)>, ctx: &mut Context) -> Result<Obj> {
+        let mut result = Obj::new();
+        for &(ref k, ref v) in contents {
+            k.set(&mut result, v.eval(ctx)?);
+        }
+        Ok(result)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum ExprOpcode {
+    Add,
+    Subtract,
+}
+
+impl ExprOpcode {
+    fn new(op_ast: &ast::ExprOpcode) -> ExprOpcode {
+        match op_ast {
+            &ast::ExprOpcode::Add => ExprOpcode::Add,
+            &ast::ExprOpcode::Subtract => ExprOpcode::Subtract,
+        }
+    }
+
+    /// Integers are added and subtracted when either operand is an integer and both have an
+    /// integer value, as with comparisons. Otherwise `+` concatenates strings.
+    fn eval(&self, l: &Value, r: &Value) -> Result<Value> {
+        if let (&Value::Object(_), _) | (_, &Value::Object(_)) = (l, r) {
+            return Err(Error::Eval("object operand".to_string()));
+        }
+        let numeric = match (l, r) {
+            (&Value::Int(_), _) | (_, &Value::Int(_)) => true,
+            _ => false,
+        };
+        match (numeric, l.as_int(), r.as_int()) {
+            (true, Some(li), Some(ri)) => {
+                let result = match self {
+                    &ExprOpcode::Add => li + ri,
+                    &ExprOpcode::Subtract => li - ri,
+                };
+                if result < std::i32::MIN as i64 || result > std::i32::MAX as i64 {
+                    return Err(Error::Eval("integer overflow".to_string()));
+                }
+                Ok(Value::Int(result as i32))
+            }
+            _ => {
+                match self {
+                    &ExprOpcode::Add => {
+                        Ok(Value::Str(format!("{}{}", l.to_string(), r.to_string())))
+                    }
+                    &ExprOpcode::Subtract => {
+                        Err(Error::Eval(format!("cannot subtract {} from {}",
+                                                r.to_string(),
+                                                l.to_string())))
+                    }
+                }
+            }
         }
     }
 }
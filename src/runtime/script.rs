@@ -37,11 +37,13 @@ pub enum Request {
         dst_agent: String,
         topic: String,
         contents: Obj,
+        src_role: Option<String>,
     },
     ReplyMsg {
         src_topic: String,
         topic: String,
         contents: Obj,
+        src_role: Option<String>,
     },
 }
 
@@ -207,13 +209,19 @@ impl<T: AsyncRead + AsyncWrite + 'static> tokio_proto::pipeline::ClientProto<T>
 pub struct ScriptService {
     ctx: Arc<Mutex<Context>>,
     actions: Arc<Mutex<Vec<Action>>>,
+    /// Whether the script may only read vars and messages.
+    read_only: bool,
 }
 
 impl ScriptService {
-    fn new(ctx: Arc<Mutex<Context>>, actions: Arc<Mutex<Vec<Action>>>) -> ScriptService {
+    fn new(ctx: Arc<Mutex<Context>>,
+           actions: Arc<Mutex<Vec<Action>>>,
+           read_only: bool)
+           -> ScriptService {
         ScriptService {
             ctx: ctx,
             actions: actions,
+            read_only: read_only,
         }
     }
 }
@@ -231,6 +239,16 @@ impl Service for ScriptService {
 
     // Produce a future for computing a response from a request.
     fn call(&self, req: Self::Request) -> Self::Future {
+        if self.read_only {
+            match req {
+                Request::GetVar { .. } |
+                Request::GetMsg { .. } => {}
+                _ => {
+                    let msg = "check scripts may not change vars or send messages".to_string();
+                    return future::ok(Response::Error(msg)).boxed();
+                }
+            }
+        }
         let mut ctx = self.ctx.lock().unwrap();
         let res = match req {
             Request::GetVar { ref key } => {
@@ -254,7 +272,7 @@ impl Service for ScriptService {
                 ctx.set_var(&id, Value::from_str(value));
                 drop(ctx);
                 let mut actions = self.actions.lock().unwrap();
-                actions.push(Action::SetVar(id, value.to_string()));
+                actions.push(Action::SetVar(id, Value::from_str(value)));
                 drop(actions);
                 Response::SetVar {
                     key: key.to_string(),
@@ -292,7 +310,12 @@ impl Service for ScriptService {
                 ref dst_agent,
                 ref topic,
                 ref contents,
+                ref src_role,
             } => {
+                let contents = match ctx.schemas.coerce(topic, contents.clone()) {
+                    Ok(contents) => contents,
+                    Err(e) => return future::ok(Response::Error(format!("{}", e))).boxed(),
+                };
                 drop(ctx);
                 let mut actions = self.actions.lock().unwrap();
                 actions.push(Action::SendMsg {
@@ -300,7 +323,8 @@ impl Service for ScriptService {
                                  dst_agent: dst_agent.to_string(),
                                  topic: topic.to_string(),
                                  in_reply_to: None,
-                                 contents: contents.clone(),
+                                 contents: contents,
+                                 src_role: src_role.clone(),
                              });
                 drop(actions);
                 Response::SendMsg {
@@ -313,7 +337,12 @@ impl Service for ScriptService {
                 ref src_topic,
                 ref topic,
                 ref contents,
+                ref src_role,
             } => {
+                let contents = match ctx.schemas.coerce(topic, contents.clone()) {
+                    Ok(contents) => contents,
+                    Err(e) => return future::ok(Response::Error(format!("{}", e))).boxed(),
+                };
                 if let Some(ref src_msg) = ctx.msgs.get(src_topic) {
                     let mut actions = self.actions.lock().unwrap();
                     actions.push(Action::SendMsg {
@@ -321,7 +350,8 @@ impl Service for ScriptService {
                                      dst_agent: src_msg.src_agent.to_string(),
                                      topic: topic.to_string(),
                                      in_reply_to: Some(src_msg.id.to_string()),
-                                     contents: contents.clone(),
+                                     contents: contents,
+                                     src_role: src_role.clone(),
                                  });
                     drop(actions);
                     Response::SendMsg {
@@ -338,7 +368,25 @@ impl Service for ScriptService {
     }
 }
 
+/// Run a script, returning the actions it takes through the script service.
 pub fn run_script(ctx: Arc<Mutex<Context>>, script_path: &str) -> Result<Vec<Action>> {
+    run(ctx, script_path, false, None)
+}
+
+/// Run the script of a check condition, which holds if the script exits successfully. The
+/// script may only read vars and messages, and fails if it runs past the timeout.
+pub fn run_check(ctx: Arc<Mutex<Context>>,
+                 script_path: &str,
+                 timeout: std::time::Duration)
+                 -> Result<()> {
+    run(ctx, script_path, true, Some(timeout)).map(|_| ())
+}
+
+fn run(ctx: Arc<Mutex<Context>>,
+       script_path: &str,
+       read_only: bool,
+       timeout: Option<std::time::Duration>)
+       -> Result<Vec<Action>> {
     let mut core = tokio_core::reactor::Core::new()
         .map_err(error::Error::IO)?;
     let handle = core.handle();
@@ -375,21 +423,27 @@ pub fn run_script(ctx: Arc<Mutex<Context>>, script_path: &str) -> Result<Vec<Act
             if output.status.success() {
                 Ok(())
             } else {
-                let code = match output.status.code() {
-                    Some(value) => value,
-                    None => 0,
-                };
-                Err(Error::Exec(code, stderr))
+                Err(Error::Exec(output.status.code(), stderr))
             }
         }
                   Err(e) => Err(Error::IO(e)),
               });
+    // The child is killed when dropped, should it still be running at the deadline.
+    let deadline: Box<Future<Item = (), Error = Error>> = match timeout {
+        Some(timeout) => {
+            Box::new(tokio_core::reactor::Timeout::new(timeout, &handle)
+                         .map_err(Error::IO)?
+                         .then(|_| Err(Error::Timeout)))
+        }
+        None => Box::new(future::empty()),
+    };
+    let child = child.select(deadline).map(|_| ()).map_err(|(e, _)| e);
     let server = connections
         .for_each(move |(socket, _peer_addr)| {
             let (wr, rd) = socket
                 .framed(crypto::SecretBoxCodec::new(ServiceCodec, key.clone()))
                 .split();
-            let service = ScriptService::new(ctx.clone(), server_actions.clone());
+            let service = ScriptService::new(ctx.clone(), server_actions.clone(), read_only);
             let responses = rd.and_then(move |req| service.call(req));
             let responder = wr.send_all(responses).then(|_| Ok(()));
             handle.spawn(responder);
@@ -1,27 +1,57 @@
 extern crate serde_json;
-extern crate spoolq;
 
 use std;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::io::{BufRead, Write};
 use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::sync::{Arc, Mutex};
 
 use super::*;
 use self::context::Context;
+use self::journal::{JournalAction, JournalEntry};
+use self::timer::{Clock, SystemClock};
 use self::transaction::Transaction;
-use self::value::{Message, Value};
+use self::value::{Message, Schemas, Value};
+
+/// Most sets of pending messages a match is tried with, each time it is evaluated.
+const MAX_EVAL_CANDIDATES: usize = 100;
 
 pub trait Storage {
     fn load(&mut self) -> Result<(i32, HashMap<String, Value>)>;
     fn save(&mut self, seq: i32, vars: HashMap<String, Value>) -> Result<()>;
 
-    fn next_messages(&mut self,
-                     filters: &HashSet<MessageFilter>)
+    /// The next message pending for each filter, by filter topic, passing over the messages
+    /// with the given IDs. Each message is returned for at most one filter. Messages stay
+    /// pending until they are acknowledged.
+    fn peek_messages(&mut self,
+                     filters: &HashSet<MessageFilter>,
+                     skip: &HashSet<String>)
                      -> Result<HashMap<String, Message>>;
     fn push_msg(&mut self, msg: Message) -> Result<()>;
+    /// Remove a pending message, once it has been consumed. The removal is saved with the
+    /// next save, or undone if it fails. Acknowledging a message that is no longer pending does
+    /// nothing.
+    fn ack_msg(&mut self, msg: &Message) -> Result<()>;
+    /// Push a message with the next save, so that it is only pushed if the save succeeds.
+    fn stage_msg(&mut self, msg: Message);
+    /// Move a pending message behind the others pending on its topic, so that a message which
+    /// failed to commit doesn't hold them up. Durable storage keeps this order across restarts.
+    fn defer_msg(&mut self, msg: &Message) -> Result<()>;
+    /// Whether a message is pending under the topic, which may be a pattern.
+    fn has_pending(&self, topic: &str) -> bool;
+    /// Record a committed transaction in the agent's journal.
+    fn append_journal(&mut self, entry: JournalEntry) -> Result<()>;
+    /// Journal entries of the transactions committed from a sequence number on.
+    fn journal(&self, since_seq: i32) -> Result<Vec<JournalEntry>>;
 
     fn vars(&self) -> &HashMap<String, Value>;
     fn seq(&self) -> i32;
 
+    /// Last-fired times of timer conditions, in seconds since the Unix epoch.
+    fn timers(&self) -> &HashMap<String, u64>;
+    /// Record when a timer last fired. Durable storage persists it on the next save.
+    fn set_timer(&mut self, key: &str, fired: u64);
+
     fn workspace(&self) -> &str;
 }
 
@@ -33,6 +63,11 @@ pub struct State<S: Storage> {
     name: String,
     storage: S,
     outbox: Box<Outbox + Send + 'static>,
+    clock: Box<Clock + Send + 'static>,
+    schemas: Schemas,
+    /// Messages already tried by each match whose last evaluation stopped at
+    /// `MAX_EVAL_CANDIDATES`, so that its next evaluation carries on from there.
+    untried: HashMap<String, HashSet<String>>,
 }
 
 impl<S: Storage> State<S> {
@@ -41,6 +76,9 @@ impl<S: Storage> State<S> {
             name: name.to_string(),
             storage: storage,
             outbox: Box::new(UndeliverableOutbox) as Box<Outbox + Send>,
+            clock: Box::new(SystemClock) as Box<Clock + Send>,
+            schemas: Schemas::default(),
+            untried: HashMap::new(),
         }
     }
 
@@ -49,9 +87,30 @@ impl<S: Storage> State<S> {
             name: name.to_string(),
             storage: storage,
             outbox: outbox,
+            clock: Box::new(SystemClock) as Box<Clock + Send>,
+            schemas: Schemas::default(),
+            untried: HashMap::new(),
         }
     }
 
+    pub fn set_clock(&mut self, clock: Box<Clock + Send + 'static>) {
+        self.clock = clock;
+    }
+
+    pub fn set_outbox(&mut self, outbox: Box<Outbox + Send + 'static>) {
+        self.outbox = outbox;
+    }
+
+    /// Set the topic schemas that messages sent by this agent must conform to.
+    pub fn set_schemas(&mut self, schemas: Schemas) {
+        self.schemas = schemas;
+    }
+
+    /// The current time, according to the state's clock.
+    pub fn now(&self) -> u64 {
+        self.clock.now()
+    }
+
     pub fn name(&self) -> &str {
         &self.name
     }
@@ -64,41 +123,103 @@ impl<S: Storage> State<S> {
         &mut self.storage
     }
 
+    /// Evaluate a match, returning a transaction to commit if it holds.
+    ///
+    /// When the match misses, it is evaluated again with the next messages pending on the
+    /// topics it requires, until it holds, they run out, or `MAX_EVAL_CANDIDATES` have been
+    /// tried. In the last case, `has_untried` tells so, and the next evaluation carries on
+    /// with the messages after those tried, before starting over from the first pending.
+    /// Check scripts are run once with each set of messages tried, and not again on commit.
+    ///
+    /// The topics a match requires all move on to their next message together: the first
+    /// message of one topic is only tried with the first of each other topic, the second with
+    /// the second, and so on.
     pub fn eval(&mut self, m: Match) -> Result<Option<Transaction>> {
-        debug!("State.eval: {:?}", &m);
+        debug!("State.eval: {}", &m);
         let (seq, vars) = self.storage.load()?;
-        let msgs = self.storage.next_messages(&m.filters())?;
-        let ctx = Context::new(&self.name, vars, msgs, self.storage.workspace());
-        let txn = Transaction::new(m, seq, ctx);
-        if txn.eval() {
-            debug!("State.eval: MATCHED");
-            Ok(Some(txn))
-        } else {
-            // Re-enqueue messages that didn't match.
-            debug!("State.eval: MISSED");
-            let mut ctx = txn.ctx.lock().unwrap();
-            for (_topic, msg) in ctx.msgs.drain() {
-                self.storage.push_msg(msg)?;
+        let filters = m.filters();
+        let required = m.required_topics().iter().map(|t| t.to_string()).collect::<Vec<_>>();
+        let key = m.to_string();
+        let mut skip = self.untried.remove(&key).unwrap_or_default();
+        let mut resumed = !skip.is_empty();
+        let mut prev: Option<Transaction> = None;
+        let mut tried = 0;
+        loop {
+            let msgs = self.storage.peek_messages(&filters, &skip)?;
+            // Without a message for each required topic, the match can't hold now or with
+            // any later messages.
+            let last = required.is_empty() || required.iter().any(|t| !msgs.contains_key(t));
+            if last && resumed {
+                // Past the messages left untried last time, start over from the first.
+                skip.clear();
+                resumed = false;
+                continue;
+            }
+            tried += 1;
+            for topic in &required {
+                if let Some(msg) = msgs.get(topic) {
+                    skip.insert(msg.id.to_string());
+                }
+            }
+            let ctx = Context::new(&self.name,
+                                   vars.clone(),
+                                   msgs,
+                                   self.storage.timers().clone(),
+                                   self.clock.now(),
+                                   self.storage.workspace(),
+                                   self.schemas.clone());
+            let mut txn = Transaction::new(m.clone(), seq, ctx);
+            if let Some(prev) = prev.take() {
+                txn.carry_scripts(prev);
             }
-            Ok(None)
+            if txn.eval() {
+                debug!("State.eval: MATCHED {}", &txn.m);
+                return Ok(Some(txn));
+            }
+            if last || tried >= MAX_EVAL_CANDIDATES {
+                debug!("State.eval: MISSED {}", &txn.m);
+                if !last {
+                    self.untried.insert(key, skip);
+                }
+                return Ok(None);
+            }
+            prev = Some(txn);
         }
     }
 
+    /// Whether the last evaluation of a match stopped before trying all the messages pending
+    /// for it, so that it should be evaluated again even if nothing changes.
+    pub fn has_untried(&self, m: &Match) -> bool {
+        self.untried.contains_key(&m.to_string())
+    }
+
     pub fn commit(&mut self, txn: &mut Transaction) -> Result<i32> {
-        debug!("State.commit: BEGIN transaction seq={}", txn.seq);
+        debug!("State.commit: BEGIN transaction seq={} match={}", txn.seq, &txn.m);
         let mut txn = txn;
         let mut vars = self.storage.vars().clone();
         let mut self_msgs = Vec::new();
+        let mut journal_actions = vec![];
         let actions = txn.apply()?;
         let matched_topics = txn.matched_topics();
+        // Reject the transaction before anything is sent, if any message does not conform.
+        for action in &actions {
+            if let &Action::SendMsg { ref topic, ref contents, .. } = action {
+                self.schemas.check(topic, contents)?;
+            }
+        }
         for action in actions {
             debug!(target: "State.commit", "action {:?}", action);
             match &action {
                 &Action::SetVar(ref k, ref v) => {
-                    k.set(&mut vars, Value::Str(v.to_string()));
+                    k.set(&mut vars, v.clone());
+                    journal_actions.push(JournalAction::SetVar {
+                                             name: k.to_string(),
+                                             value: v.clone(),
+                                         });
                 }
                 &Action::UnsetVar(ref k) => {
                     k.unset(&mut vars);
+                    journal_actions.push(JournalAction::UnsetVar { name: k.to_string() });
                 }
                 &Action::SendMsg {
                      ref dst_remote,
@@ -106,14 +227,20 @@ impl<S: Storage> State<S> {
                      ref topic,
                      ref in_reply_to,
                      ref contents,
+                     ref src_role,
                  } => {
                     let msg = Message::new(topic, contents.clone())
                         .src_agent(&self.name)
-                        .src_role(txn.m.acting_role.clone())
+                        .src_role(src_role.clone())
                         .dst_agent(dst_agent)
                         .dst_remote(dst_remote.clone())
                         .in_reply_to(in_reply_to.clone());
                     debug!("send {:?}", msg);
+                    journal_actions.push(JournalAction::SendMsg {
+                                             id: msg.id.to_string(),
+                                             dst_agent: dst_agent.to_string(),
+                                             topic: topic.to_string(),
+                                         });
                     if dst_agent == "self" {
                         self_msgs.push(msg);
                     } else {
@@ -123,25 +250,48 @@ impl<S: Storage> State<S> {
                 _ => return Err(Error::UnsupportedAction),
             };
         }
+        for msg in self_msgs {
+            self.storage.stage_msg(msg);
+        }
+        let now = txn.with_context(|ctx| ctx.now);
+        for key in txn.fired_timers() {
+            self.storage.set_timer(&key, now);
+        }
+        // Matched messages are consumed, and messages sent to self pushed, along with the
+        // vars the transaction saves.
         let msgs = txn.with_context(|ctx| ctx.msgs.clone());
+        let mut consumed = vec![];
         for (topic, msg) in msgs {
-            if !matched_topics.contains(&topic) {
-                self.storage.push_msg(msg)?;
+            if matched_topics.contains(&topic) {
+                self.storage.ack_msg(&msg)?;
+                consumed.push(msg.id.to_string());
             }
         }
-        for msg in self_msgs {
-            self.storage.push_msg(msg)?;
-        }
+        consumed.sort();
         self.storage.save(txn.seq, vars)?;
-        debug!("State.commit: OK transaction seq={}", txn.seq);
+        debug!("State.commit: OK transaction seq={} match={}", txn.seq, &txn.m);
+        // The transaction has committed by now, so failing to journal it is not an error.
+        let entry = JournalEntry {
+            seq: txn.seq,
+            name: txn.m.to_string(),
+            time: now,
+            msgs: consumed,
+            actions: journal_actions,
+            scripts: txn.scripts(),
+        };
+        if let Err(e) = self.storage.append_journal(entry) {
+            warn!("failed to journal transaction seq={}: {}", txn.seq, e);
+        }
         Ok(txn.seq)
     }
 
+    /// Abandon a transaction which failed to commit. Its messages were never acknowledged,
+    /// so they are still pending, but are deferred behind any others.
     pub fn rollback(&mut self, txn: Transaction) -> Result<()> {
         let mut txn = txn;
         let msgs = txn.with_context(|ctx| ctx.msgs.clone());
         for (_topic, msg) in msgs {
-            self.storage.push_msg(msg)?;
+            self.storage.defer_msg(&msg)?;
         }
         Ok(())
     }
@@ -155,19 +305,121 @@ impl Outbox for UndeliverableOutbox {
     }
 }
 
+/// Counts of the messages stored under each topic, along with the topics known to match each
+/// topic pattern polled for, so that patterns are served without scanning every topic.
+#[derive(Default)]
+struct TopicIndex {
+    pending: HashMap<String, usize>,
+    patterns: HashMap<String, BTreeSet<String>>,
+}
+
+impl TopicIndex {
+    fn add(&mut self, topic: &str, n: usize) {
+        if !self.pending.contains_key(topic) {
+            for (pattern, topics) in self.patterns.iter_mut() {
+                if ast::topic_matches(pattern, topic) {
+                    topics.insert(topic.to_string());
+                }
+            }
+        }
+        *self.pending.entry(topic.to_string()).or_insert(0) += n;
+    }
+
+    fn remove(&mut self, topic: &str) {
+        if let Some(n) = self.pending.get_mut(topic) {
+            *n = n.saturating_sub(1);
+        }
+    }
+
+    fn has_pending(&self, filter_topic: &str) -> bool {
+        if !ast::is_topic_pattern(filter_topic) {
+            return self.pending.get(filter_topic).map_or(false, |n| *n > 0);
+        }
+        self.pending.iter().any(|(topic, n)| *n > 0 && ast::topic_matches(filter_topic, topic))
+    }
+
+    /// Topics to poll for a message matching the filter topic, which may be a pattern.
+    fn topics(&mut self, filter_topic: &str) -> Vec<String> {
+        if !ast::is_topic_pattern(filter_topic) {
+            return vec![filter_topic.to_string()];
+        }
+        if !self.patterns.contains_key(filter_topic) {
+            let topics = self.pending
+                .keys()
+                .filter(|topic| ast::topic_matches(filter_topic, topic))
+                .cloned()
+                .collect();
+            self.patterns.insert(filter_topic.to_string(), topics);
+        }
+        let pending = &self.pending;
+        self.patterns[filter_topic]
+            .iter()
+            .filter(|topic| pending[*topic] > 0)
+            .cloned()
+            .collect()
+    }
+}
+
+/// A message pending in storage.
+struct Pending {
+    msg: Message,
+    /// Order in which the message was stored.
+    seq: u64,
+    /// Order in which the message was deferred after failing to commit, or 0 if it wasn't.
+    deferred: u64,
+    /// File the message is stored in, if it is stored durably.
+    path: Option<String>,
+}
+
+impl Pending {
+    /// Pending messages are peeked in order of priority, and first in, first out among
+    /// those of the same priority. Deferred messages come after all others.
+    fn peek_order(&self) -> (u64, std::cmp::Reverse<i32>, u64) {
+        (self.deferred, std::cmp::Reverse(self.msg.priority), self.seq)
+    }
+}
+
+/// The next message to peek among candidates, passing over those already taken.
+fn peek_next<'a, I>(candidates: I, taken: &HashSet<String>) -> Option<&'a Pending>
+    where I: Iterator<Item = &'a Pending>
+{
+    candidates.filter(|p| !taken.contains(&p.msg.id)).min_by_key(|p| p.peek_order())
+}
+
 pub struct MemStorage {
     seq: i32,
     vars: HashMap<String, Value>,
-    msgs: HashMap<MessageFilter, Vec<Message>>,
+    msgs: HashMap<MessageFilter, Vec<Pending>>,
+    msg_seq: u64,
+    defer_seq: u64,
+    /// Messages acknowledged and staged since the last save.
+    acked: Vec<Pending>,
+    staged: Vec<Message>,
+    index: TopicIndex,
+    /// Journal entries, which may be shared with whoever else reads them.
+    journal: Arc<Mutex<Vec<JournalEntry>>>,
+    timers: HashMap<String, u64>,
     workspace: String,
 }
 
 impl MemStorage {
     pub fn new() -> MemStorage {
+        MemStorage::new_journal(Arc::new(Mutex::new(vec![])))
+    }
+
+    /// In-memory storage which records its journal entries in the journal given.
+    pub fn new_journal(journal: Arc<Mutex<Vec<JournalEntry>>>) -> MemStorage {
         MemStorage {
             seq: 0,
             vars: HashMap::new(),
             msgs: HashMap::new(),
+            msg_seq: 0,
+            defer_seq: 0,
+            acked: vec![],
+            staged: vec![],
+            index: TopicIndex::default(),
+            journal: journal,
+            timers: HashMap::new(),
             workspace: std::env::current_dir()
                 .unwrap()
                 .to_str()
@@ -184,45 +436,120 @@ impl Storage for MemStorage {
 
     fn save(&mut self, seq: i32, vars: HashMap<String, Value>) -> Result<()> {
         if seq < self.seq {
+            self.staged.clear();
+            for p in self.acked.drain(..).collect::<Vec<_>>() {
+                self.index.add(&p.msg.topic, 1);
+                let k = MessageFilter {
+                    topic: p.msg.topic.to_string(),
+                    src_role: p.msg.src_role.clone(),
+                };
+                self.msgs.entry(k).or_insert_with(Vec::new).push(p);
+            }
             return Err(error::Error::InvalidArgument("stale transaction".to_string()));
         }
         debug!(target: "MemStorage.save", "vars before={:?} after={:?}", &self.vars, &vars);
         self.vars = vars;
         self.seq = seq + 1;
+        self.acked.clear();
+        for msg in self.staged.drain(..).collect::<Vec<_>>() {
+            self.push_msg(msg)?;
+        }
         Ok(())
     }
 
-    fn next_messages(&mut self,
-                     filters: &HashSet<MessageFilter>)
+    fn peek_messages(&mut self,
+                     filters: &HashSet<MessageFilter>,
+                     skip: &HashSet<String>)
                      -> Result<HashMap<String, Message>> {
         let mut next: HashMap<String, Message> = HashMap::new();
-        for (k, v) in &mut self.msgs {
-            if !filters.contains(k) {
-                continue;
-            }
-            match v.pop() {
-                Some(msg) => {
-                    next.insert(k.topic.to_string(), msg.clone());
-                }
-                None => (),
+        let mut taken = skip.clone();
+        for k in filters {
+            let msgs = &self.msgs;
+            let found = {
+                let candidates = self.index
+                    .topics(&k.topic)
+                    .into_iter()
+                    .filter_map(|topic| {
+                                    msgs.get(&MessageFilter {
+                                                  topic: topic,
+                                                  src_role: k.src_role.clone(),
+                                              })
+                                })
+                    .flat_map(|v| v.iter());
+                peek_next(candidates, &taken).map(|p| p.msg.clone())
+            };
+            if let Some(msg) = found {
+                taken.insert(msg.id.to_string());
+                next.insert(k.topic.to_string(), msg);
             }
         }
         Ok(next)
     }
 
     fn push_msg(&mut self, msg: Message) -> Result<()> {
+        self.index.add(&msg.topic, 1);
+        let k = MessageFilter {
+            topic: msg.topic.to_string(),
+            src_role: msg.src_role.clone(),
+        };
+        self.msgs
+            .entry(k)
+            .or_insert_with(Vec::new)
+            .push(Pending {
+                      msg: msg,
+                      seq: self.msg_seq,
+                      deferred: 0,
+                      path: None,
+                  });
+        self.msg_seq += 1;
+        Ok(())
+    }
+
+    fn ack_msg(&mut self, msg: &Message) -> Result<()> {
         let k = MessageFilter {
             topic: msg.topic.to_string(),
             src_role: msg.src_role.clone(),
         };
         if let Some(v) = self.msgs.get_mut(&k) {
-            v.push(msg);
-            return Ok(());
+            if let Some(pos) = v.iter().position(|p| p.msg.id == msg.id) {
+                self.acked.push(v.remove(pos));
+                self.index.remove(&msg.topic);
+            }
         }
-        self.msgs.insert(k, vec![msg]);
         Ok(())
     }
 
+    fn stage_msg(&mut self, msg: Message) {
+        self.staged.push(msg);
+    }
+
+    fn defer_msg(&mut self, msg: &Message) -> Result<()> {
+        let k = MessageFilter {
+            topic: msg.topic.to_string(),
+            src_role: msg.src_role.clone(),
+        };
+        if let Some(p) = self.msgs
+               .get_mut(&k)
+               .and_then(|v| v.iter_mut().find(|p| p.msg.id == msg.id)) {
+            self.defer_seq += 1;
+            p.deferred = self.defer_seq;
+        }
+        Ok(())
+    }
+
+    fn has_pending(&self, topic: &str) -> bool {
+        self.index.has_pending(topic)
+    }
+
+    fn append_journal(&mut self, entry: JournalEntry) -> Result<()> {
+        self.journal.lock().unwrap().push(entry);
+        Ok(())
+    }
+
+    fn journal(&self, since_seq: i32) -> Result<Vec<JournalEntry>> {
+        Ok(self.journal.lock().unwrap().iter().filter(|e| e.seq >= since_seq).cloned().collect())
+    }
+
     fn vars(&self) -> &HashMap<String, Value> {
         &self.vars
     }
@@ -231,6 +558,14 @@ impl Storage for MemStorage {
         self.seq
     }
 
+    fn timers(&self) -> &HashMap<String, u64> {
+        &self.timers
+    }
+
+    fn set_timer(&mut self, key: &str, fired: u64) {
+        self.timers.insert(key.to_string(), fired);
+    }
+
     fn workspace(&self) -> &str {
         &self.workspace
     }
@@ -241,14 +576,50 @@ impl Storage for MemStorage {
 struct DurableCheckpoint {
     seq: i32,
     vars: HashMap<String, Value>,
+    #[serde(default)]
+    timers: HashMap<String, u64>,
+    /// IDs of the messages consumed by the transaction saved, whose files are removed once the
+    /// checkpoint is in place.
+    #[serde(default)]
+    acked: Vec<String>,
+    /// Messages pushed by the transaction saved, which are spooled once the checkpoint is in
+    /// place.
+    #[serde(default)]
+    staged: Vec<Message>,
+}
+
+/// Steps of saving a durable checkpoint, at which a save may be made to fail as if the agent
+/// had crashed there.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SaveStep {
+    /// Partway through writing the new checkpoint.
+    Write,
+    /// Once the new checkpoint is written, before it replaces the old one.
+    Rename,
+    /// Once the new checkpoint is in place, before its messages are pushed.
+    Push,
+    /// Once its messages are pushed, before the messages it consumed are removed.
+    Ack,
 }
 
 pub struct DurableStorage {
     checkpoint_path: String,
     checkpoint: DurableCheckpoint,
     topics_path: String,
-    topics: HashMap<String, spoolq::Queue<Message>>,
+    journal_path: String,
+    /// Messages pending under each topic, as stored in its spool directory.
+    topics: HashMap<String, Vec<Pending>>,
+    /// Sequence number of the next message stored, which orders its file in the spool.
+    msg_seq: u64,
+    /// Number of messages deferred, which orders them behind the others. A deferred message's
+    /// file is renamed to record it, so the order survives a restart.
+    defer_seq: u64,
+    /// Messages acknowledged and staged since the last save.
+    acked: Vec<Pending>,
+    staged: Vec<Message>,
+    index: TopicIndex,
     workspace: String,
+    crash_at: Option<SaveStep>,
 }
 
 impl DurableStorage {
@@ -278,29 +649,197 @@ impl DurableStorage {
             .mode(0o700)
             .create(&workspace)
             .map_err(error::Error::IO)?;
-        DurableStorage::recover_all(&topics_path)?;
-        Ok(DurableStorage {
-               checkpoint: DurableCheckpoint {
-                   seq: 0,
-                   vars: HashMap::new(),
-               },
-               checkpoint_path: checkpoint_path,
-               topics: HashMap::new(),
-               topics_path: topics_path,
-               workspace: workspace,
-           })
-    }
-
-    fn recover_all(path: &str) -> Result<()> {
+        // A checkpoint left half-written by a crash is discarded.
+        let tmp_path = format!("{}.tmp", checkpoint_path);
+        if std::path::Path::new(&tmp_path).exists() {
+            std::fs::remove_file(&tmp_path)?;
+        }
+        let checkpoint = match read_checkpoint(&checkpoint_path)? {
+            Some(chk) => chk,
+            None => {
+                DurableCheckpoint {
+                    seq: 0,
+                    vars: HashMap::new(),
+                    timers: HashMap::new(),
+                    acked: vec![],
+                    staged: vec![],
+                }
+            }
+        };
+        let mut topics = DurableStorage::recover_all(&topics_path)?;
+        // Messages the last checkpoint consumed may not have been removed yet.
+        let mut acked = vec![];
+        for msgs in topics.values_mut() {
+            let (consumed, pending) = msgs.drain(..)
+                .partition(|p| checkpoint.acked.contains(&p.msg.id));
+            acked.extend::<Vec<_>>(consumed);
+            *msgs = pending;
+        }
+        let mut index = TopicIndex::default();
+        for (topic, msgs) in topics.iter() {
+            index.add(topic, msgs.len());
+        }
+        let msg_seq = topics.values()
+            .flat_map(|msgs| msgs.iter())
+            .map(|p| p.seq + 1)
+            .max()
+            .unwrap_or(0);
+        let defer_seq = topics.values()
+            .flat_map(|msgs| msgs.iter())
+            .map(|p| p.deferred)
+            .max()
+            .unwrap_or(0);
+        let mut storage = DurableStorage {
+            checkpoint: checkpoint,
+            checkpoint_path: checkpoint_path,
+            topics: topics,
+            msg_seq: msg_seq,
+            defer_seq: defer_seq,
+            acked: acked,
+            staged: vec![],
+            index: index,
+            topics_path: topics_path,
+            journal_path: journal_path(path),
+            workspace: workspace,
+            crash_at: None,
+        };
+        // Finish applying the last checkpoint, in case the agent stopped partway through.
+        if let Err(e) = storage.apply_checkpoint() {
+            warn!("failed to apply checkpoint seq={}, will retry: {}",
+                  storage.checkpoint.seq,
+                  e);
+        }
+        Ok(storage)
+    }
+
+    /// Journal entries of the transactions committed from a sequence number on, by the agent
+    /// whose storage is at the path given, without opening its storage.
+    pub fn read_journal(path: &str, since_seq: i32) -> Result<Vec<JournalEntry>> {
+        read_journal_file(&journal_path(path), since_seq)
+    }
+
+    /// Make saves fail at a step, as if the agent crashed there, or stop them failing.
+    #[cfg(test)]
+    pub fn crash_at(&mut self, step: Option<SaveStep>) {
+        self.crash_at = step;
+    }
+
+    fn crash_point(&self, step: SaveStep) -> Result<()> {
+        if self.crash_at == Some(step) {
+            return Err(error::Error::IO(std::io::Error::new(std::io::ErrorKind::Other,
+                                                            format!("crashed at {:?}", step))));
+        }
+        Ok(())
+    }
+
+    /// Write a checkpoint in place of the current one. A crash at any point leaves either the
+    /// old checkpoint or the new one.
+    fn write_checkpoint(&self, chk: &DurableCheckpoint) -> Result<()> {
+        let tmp_path = format!("{}.tmp", self.checkpoint_path);
+        let buf = serde_json::to_vec(chk).map_err(to_ioerror).map_err(error::Error::IO)?;
+        {
+            let mut chk_file = std::fs::OpenOptions::new()
+                .write(true)
+                .mode(0o600)
+                .create(true)
+                .truncate(true)
+                .open(&tmp_path)?;
+            if self.crash_at == Some(SaveStep::Write) {
+                chk_file.write_all(&buf[..buf.len() / 2])?;
+            }
+            self.crash_point(SaveStep::Write)?;
+            chk_file.write_all(&buf)?;
+            chk_file.sync_all()?;
+        }
+        self.crash_point(SaveStep::Rename)?;
+        std::fs::rename(&tmp_path, &self.checkpoint_path)?;
+        if let Some(dir) = std::path::Path::new(&self.checkpoint_path).parent() {
+            std::fs::File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    /// Push the messages staged by the current checkpoint, and remove those it consumed.
+    ///
+    /// Messages left unpushed or unremoved by a failure are staged and acknowledged again, so
+    /// that the next save tries them again.
+    fn apply_checkpoint(&mut self) -> Result<()> {
+        let staged = self.checkpoint.staged.clone();
+        let mut pushed = 0;
+        let mut result = self.crash_point(SaveStep::Push);
+        if result.is_ok() {
+            for msg in staged.iter() {
+                let spooled = self.topics
+                    .get(&msg.topic)
+                    .map_or(false, |v| v.iter().any(|p| p.msg.id == msg.id));
+                if !spooled {
+                    if let Err(e) = self.push_msg(msg.clone()) {
+                        result = Err(e);
+                        break;
+                    }
+                }
+                pushed += 1;
+            }
+        }
+        if result.is_err() {
+            let mut unpushed = staged[pushed..].to_vec();
+            unpushed.extend(self.staged.drain(..));
+            self.staged = unpushed;
+            return result;
+        }
+        self.crash_point(SaveStep::Ack)?;
+        let mut result = Ok(());
+        for p in self.acked.drain(..).collect::<Vec<_>>() {
+            let removed = match p.path {
+                Some(ref path) => std::fs::remove_file(path),
+                None => Ok(()),
+            };
+            match removed {
+                Ok(_) => self.checkpoint.acked.retain(|id| id != &p.msg.id),
+                Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    self.checkpoint.acked.retain(|id| id != &p.msg.id)
+                }
+                Err(e) => {
+                    result = Err(error::Error::IO(e));
+                    self.acked.push(p);
+                }
+            }
+        }
+        result
+    }
+
+    /// Return messages acknowledged since the last save to the pending messages, and drop
+    /// those staged, after a save fails. Messages the last checkpoint consumed stay consumed,
+    /// and those it staged stay staged.
+    fn undo_unsaved(&mut self) {
+        let saved = &self.checkpoint.staged;
+        self.staged.retain(|msg| saved.iter().any(|s| s.id == msg.id));
+        let saved = &self.checkpoint.acked;
+        let (consumed, unsaved): (Vec<_>, Vec<_>) = self.acked
+            .drain(..)
+            .partition(|p| saved.contains(&p.msg.id));
+        self.acked = consumed;
+        for p in unsaved {
+            self.index.add(&p.msg.topic, 1);
+            self.topics.entry(p.msg.topic.to_string()).or_insert_with(Vec::new).push(p);
+        }
+    }
+
+    /// Recover the messages pending in each topic's spool directory.
+    fn recover_all(path: &str) -> Result<HashMap<String, Vec<Pending>>> {
+        let mut topics = HashMap::new();
         let dirh = std::fs::read_dir(path)?;
         for maybe_dirent in dirh {
             match maybe_dirent {
                 Ok(dirent) => {
                     if let Ok(ftype) = dirent.file_type() {
                         if ftype.is_dir() {
+                            let topic = dirent.file_name().to_string_lossy().to_string();
                             let topic_path = dirent.path().to_str().unwrap().to_string();
                             match DurableStorage::recover_topic(&topic_path) {
-                                Ok(_) => {}
+                                Ok(msgs) => {
+                                    topics.insert(topic, msgs);
+                                }
                                 Err(e) => {
                                     warn!("failed to recover topic queue {}: {}", &topic_path, e)
                                 }
@@ -311,52 +850,130 @@ impl DurableStorage {
                 Err(e) => warn!("error recovering topic queues: {}", e),
             }
         }
-        Ok(())
+        Ok(topics)
     }
 
-    fn recover_topic(path: &str) -> Result<()> {
-        let q = spoolq::Queue::<Message>::new(path)?;
-        q.recover()?;
-        Ok(())
+    /// Recover the messages in a topic's spool directory, ordered by their file names.
+    /// Messages deferred before the restart stay deferred, in the same order.
+    ///
+    /// Files marked `.pop` were consumed by an earlier version of glop, but possibly not
+    /// committed, so they are restored to be consumed again.
+    fn recover_topic(path: &str) -> Result<Vec<Pending>> {
+        let mut paths = vec![];
+        for dirent in std::fs::read_dir(path)? {
+            let p = dirent?.path();
+            match p.extension().map(|ext| ext.to_string_lossy().to_string()) {
+                None => paths.push(p),
+                Some(ref ext) if ext == "pop" => {
+                    let unmarked = p.with_extension("");
+                    std::fs::rename(&p, &unmarked)?;
+                    paths.push(unmarked);
+                }
+                Some(_) => {}
+            }
+        }
+        paths.sort();
+        let mut msgs = vec![];
+        for p in paths {
+            let f = std::fs::OpenOptions::new().read(true).open(&p)?;
+            match serde_json::from_reader(f) {
+                Ok(msg) => {
+                    let path = p.to_str().unwrap().to_string();
+                    msgs.push(Pending {
+                                  msg: msg,
+                                  seq: spool_seq(&path).unwrap_or(0),
+                                  deferred: spool_deferred(&path).unwrap_or(0),
+                                  path: Some(path),
+                              })
+                }
+                Err(e) => warn!("discarding unreadable message {}: {}", p.display(), e),
+            }
+        }
+        Ok(msgs)
     }
+}
 
-    fn new_queue(&self, topic: &str) -> Result<spoolq::Queue<Message>> {
-        let q_path = std::path::PathBuf::from(&self.topics_path)
-            .join(topic)
-            .to_str()
-            .unwrap()
-            .to_string();
-        spoolq::Queue::<Message>::new(&q_path).map_err(error::Error::IO)
+fn journal_path(path: &str) -> String {
+    std::path::PathBuf::from(path)
+        .join("journal")
+        .to_str()
+        .unwrap()
+        .to_string()
+}
+
+/// Read journal entries, skipping any left partly written by a crash.
+fn read_journal_file(path: &str, since_seq: i32) -> Result<Vec<JournalEntry>> {
+    if !std::path::Path::new(path).exists() {
+        return Ok(vec![]);
+    }
+    let f = std::fs::OpenOptions::new().read(true).open(path)?;
+    let mut result = vec![];
+    for line in std::io::BufReader::new(f).lines() {
+        match serde_json::from_str::<JournalEntry>(&line?) {
+            Ok(entry) => {
+                if entry.seq >= since_seq {
+                    result.push(entry);
+                }
+            }
+            Err(e) => warn!("skipping unreadable journal entry in {}: {}", path, e),
+        }
     }
+    Ok(result)
+}
+
+fn read_checkpoint(path: &str) -> Result<Option<DurableCheckpoint>> {
+    if !std::path::Path::new(path).exists() {
+        return Ok(None);
+    }
+    let chk_file = std::fs::OpenOptions::new().read(true).open(path)?;
+    let chk = serde_json::from_reader(chk_file).map_err(to_ioerror).map_err(error::Error::IO)?;
+    Ok(Some(chk))
+}
+
+/// Sequence number of a spooled message, from the hexadecimal prefix of its file name.
+fn spool_seq(path: &str) -> Option<u64> {
+    let name = std::path::Path::new(path).file_name()?.to_str()?;
+    u64::from_str_radix(name.get(..16)?, 16).ok()
+}
+
+/// Order in which a spooled message was deferred, from the hexadecimal suffix its file name is
+/// given when it is deferred.
+fn spool_deferred(path: &str) -> Option<u64> {
+    let name = std::path::Path::new(path).file_name()?.to_str()?;
+    if name.get(16..17)? != "-" {
+        return None;
+    }
+    u64::from_str_radix(name.get(17..)?, 16).ok()
 }
 
 impl Storage for DurableStorage {
     fn load(&mut self) -> Result<(i32, HashMap<String, Value>)> {
         debug!("DurableStorage load: path={}", &self.checkpoint_path);
-        if !std::path::Path::new(&self.checkpoint_path).exists() {
-            self.checkpoint = DurableCheckpoint {
-                seq: 0,
-                vars: HashMap::new(),
-            };
-            debug!("DurableStorage.load: no checkpoint file! vars={:?}",
-                   &self.checkpoint.vars);
-            return Ok((self.checkpoint.seq, self.checkpoint.vars.clone()));
-        }
-        {
-            let chk_file = std::fs::OpenOptions::new()
-                .read(true)
-                .open(&self.checkpoint_path)?;
-            self.checkpoint = serde_json::from_reader(chk_file)
-                .map_err(to_ioerror)
-                .map_err(error::Error::IO)?;
-            debug!("DurableStorage.load: loaded checkpoint: {:?}",
-                   &self.checkpoint);
-            Ok((self.checkpoint.seq, self.checkpoint.vars.clone()))
+        match read_checkpoint(&self.checkpoint_path)? {
+            Some(chk) => {
+                self.checkpoint = chk;
+                debug!("DurableStorage.load: loaded checkpoint: {:?}",
+                       &self.checkpoint);
+            }
+            None => {
+                let timers = self.checkpoint.timers.clone();
+                self.checkpoint = DurableCheckpoint {
+                    seq: 0,
+                    vars: HashMap::new(),
+                    timers: timers,
+                    acked: vec![],
+                    staged: vec![],
+                };
+                debug!("DurableStorage.load: no checkpoint file! vars={:?}",
+                       &self.checkpoint.vars);
+            }
         }
+        Ok((self.checkpoint.seq, self.checkpoint.vars.clone()))
     }
 
     fn save(&mut self, seq: i32, vars: HashMap<String, Value>) -> Result<()> {
         if seq < self.checkpoint.seq {
+            self.undo_unsaved();
             return Err(error::Error::InvalidArgument("stale transaction".to_string()));
         }
         debug!("DurableStorage.save: saving vars before={:?} after={:?}",
@@ -365,40 +982,41 @@ impl Storage for DurableStorage {
         let chk = DurableCheckpoint {
             vars: vars,
             seq: seq + 1,
+            timers: self.checkpoint.timers.clone(),
+            acked: self.acked.iter().map(|p| p.msg.id.to_string()).collect(),
+            staged: self.staged.drain(..).collect(),
         };
-        {
-            let mut chk_file = std::fs::OpenOptions::new()
-                .write(true)
-                .mode(0o600)
-                .create(true)
-                .truncate(true)
-                .open(&self.checkpoint_path)?;
-            serde_json::to_writer(&mut chk_file, &chk)
-                .map_err(to_ioerror)
-                .map_err(error::Error::IO)?;
-        }
-        if !std::path::Path::new(&self.checkpoint_path).exists() {
-            panic!("where is the checkpoint file? {}", &self.checkpoint_path);
-        }
-        for q in self.topics.values_mut() {
-            q.flush()?;
+        if let Err(e) = self.write_checkpoint(&chk) {
+            self.undo_unsaved();
+            return Err(e);
         }
         self.checkpoint = chk;
+        // The save is committed once the checkpoint is in place. Whatever is left of applying
+        // it is carried over to the next save, or finished on startup.
+        if let Err(e) = self.apply_checkpoint() {
+            warn!("failed to apply checkpoint seq={}, will retry: {}", self.checkpoint.seq, e);
+        }
         Ok(())
     }
 
-    fn next_messages(&mut self,
-                     filters: &HashSet<MessageFilter>)
+    fn peek_messages(&mut self,
+                     filters: &HashSet<MessageFilter>,
+                     skip: &HashSet<String>)
                      -> Result<HashMap<String, Message>> {
         let mut next: HashMap<String, Message> = HashMap::new();
+        let mut taken = skip.clone();
         for k in filters {
-            if !self.topics.contains_key(&k.topic) {
-                let q = self.new_queue(&k.topic)?;
-                self.topics.insert(k.topic.to_string(), q);
-            }
-            let q = self.topics.get_mut(&k.topic).unwrap();
-            let maybe_msg = q.pop().map_err(error::Error::IO)?;
-            if let Some(msg) = maybe_msg {
+            let topics = &self.topics;
+            let found = {
+                let candidates = self.index
+                    .topics(&k.topic)
+                    .into_iter()
+                    .filter_map(|topic| topics.get(&topic))
+                    .flat_map(|v| v.iter());
+                peek_next(candidates, &taken).map(|p| p.msg.clone())
+            };
+            if let Some(msg) = found {
+                taken.insert(msg.id.to_string());
                 next.insert(k.topic.to_string(), msg);
             }
         }
@@ -406,12 +1024,92 @@ impl Storage for DurableStorage {
     }
 
     fn push_msg(&mut self, msg: Message) -> Result<()> {
-        if !self.topics.contains_key(&msg.topic) {
-            let q = self.new_queue(&msg.topic)?;
-            self.topics.insert(msg.topic.to_string(), q);
+        let topic_path = std::path::PathBuf::from(&self.topics_path).join(&msg.topic);
+        std::fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&topic_path)?;
+        let path = topic_path.join(format!("{:016x}", self.msg_seq));
+        let incomplete_path = path.with_extension("inc");
+        {
+            let mut f = std::fs::OpenOptions::new()
+                .write(true)
+                .mode(0o600)
+                .create_new(true)
+                .open(&incomplete_path)?;
+            serde_json::to_writer(&mut f, &msg)
+                .map_err(to_ioerror)
+                .map_err(error::Error::IO)?;
+            f.sync_all()?;
+        }
+        std::fs::rename(&incomplete_path, &path)?;
+        self.index.add(&msg.topic, 1);
+        self.topics
+            .entry(msg.topic.to_string())
+            .or_insert_with(Vec::new)
+            .push(Pending {
+                      msg: msg,
+                      seq: self.msg_seq,
+                      deferred: 0,
+                      path: Some(path.to_str().unwrap().to_string()),
+                  });
+        self.msg_seq += 1;
+        Ok(())
+    }
+
+    fn ack_msg(&mut self, msg: &Message) -> Result<()> {
+        if let Some(v) = self.topics.get_mut(&msg.topic) {
+            if let Some(pos) = v.iter().position(|p| p.msg.id == msg.id) {
+                self.acked.push(v.remove(pos));
+                self.index.remove(&msg.topic);
+            }
         }
-        let q = self.topics.get_mut(&msg.topic).unwrap();
-        q.push(msg).map_err(error::Error::IO)
+        Ok(())
+    }
+
+    fn stage_msg(&mut self, msg: Message) {
+        self.staged.push(msg);
+    }
+
+    fn defer_msg(&mut self, msg: &Message) -> Result<()> {
+        if let Some(p) = self.topics
+               .get_mut(&msg.topic)
+               .and_then(|v| v.iter_mut().find(|p| p.msg.id == msg.id)) {
+            let deferred = self.defer_seq + 1;
+            if let Some(ref mut path) = p.path {
+                let renamed = std::path::PathBuf::from(path.as_str())
+                    .with_file_name(format!("{:016x}-{:016x}", p.seq, deferred))
+                    .to_str()
+                    .unwrap()
+                    .to_string();
+                std::fs::rename(path.as_str(), &renamed)?;
+                *path = renamed;
+            }
+            self.defer_seq = deferred;
+            p.deferred = deferred;
+        }
+        Ok(())
+    }
+
+    fn has_pending(&self, topic: &str) -> bool {
+        self.index.has_pending(topic)
+    }
+
+    fn append_journal(&mut self, entry: JournalEntry) -> Result<()> {
+        let mut line = serde_json::to_vec(&entry).map_err(to_ioerror).map_err(error::Error::IO)?;
+        line.push(b'\n');
+        let mut f = std::fs::OpenOptions::new()
+            .append(true)
+            .mode(0o600)
+            .create(true)
+            .open(&self.journal_path)?;
+        f.write_all(&line)?;
+        f.sync_data()?;
+        Ok(())
+    }
+
+    fn journal(&self, since_seq: i32) -> Result<Vec<JournalEntry>> {
+        read_journal_file(&self.journal_path, since_seq)
     }
 
     fn vars(&self) -> &HashMap<String, Value> {
@@ -422,6 +1120,14 @@ impl Storage for DurableStorage {
         self.checkpoint.seq
     }
 
+    fn timers(&self) -> &HashMap<String, u64> {
+        &self.checkpoint.timers
+    }
+
+    fn set_timer(&mut self, key: &str, fired: u64) {
+        self.checkpoint.timers.insert(key.to_string(), fired);
+    }
+
     fn workspace(&self) -> &str {
         &self.workspace
     }
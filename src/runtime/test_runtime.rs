@@ -4,11 +4,12 @@ extern crate env_logger;
 extern crate textnonce;
 
 use std;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
 
 use super::*;
 use super::super::grammar;
-use self::value::{Message, Obj, Value};
+use self::value::{Identifier, Message, Obj, Schemas, Value};
 
 const SIMPLE_INIT: &'static str = r#"when (message init) { }"#;
 const TWO_MSGS: &'static str = r#"when (message foo, message bar) { }"#;
@@ -34,6 +35,95 @@ const NESTED_TWO_MSGS: &'static str = r#"when (message foo) {
     }
 }"#;
 
+const MSG_FIELD_EQUAL: &'static str = r#"when (message ping, ping.text == hello) {
+    var set greeted true;
+}"#;
+const NESTED_MSG_FIELD: &'static str = r#"when (message config) {
+    when (config.mode != debug, is_set config.level) {
+        var set quiet true;
+    }
+    when (is_unset config.level) {
+        var set quiet false;
+    }
+}"#;
+const NUMERIC_GREATER: &'static str = r#"when (usage > 80) { var set alert true; }"#;
+const STRING_GREATER: &'static str = r#"when (usage > "80") { var set alert true; }"#;
+const REGEX_MATCH: &'static str = r##"when (version =~ r"^1\.[0-9]+$") { var set supported true; }"##;
+const CMP_REFS: &'static str = r#"when (message desired, desired.replicas != actual.replicas) {
+    var set actual.replicas ${desired.replicas};
+}"#;
+const CMP_REFS_ORDERED: &'static str = r#"when (usage > ${limit}) { var set alert true; }"#;
+const EITHER_MSG: &'static str = r#"when (message foo or message bar) { }"#;
+const NOT_MSG: &'static str = r#"when (message foo, not message bar) { }"#;
+const GROUPED_OR: &'static str = r#"when ((message foo, is_set ready) or not is_unset force) {
+    var set fired true;
+}"#;
+const NESTED_ELSE: &'static str = r#"when (message foo) {
+    when (is_set bar) {
+        var set found true;
+    } else {
+        var set found false;
+    }
+}"#;
+const REPLY_MSG: &'static str = r#"when (message ping) {
+    msg reply ping pong { text = ${ping.text}, count = 1 };
+    msg send self log { from = ${ping.text} };
+}"#;
+const TOPIC_PATTERNS: &'static str = r#"when (message config.*, message alert.**) { }"#;
+const SEND_ROLES: &'static str = r#"when (message query as server, message sync as primary) {
+    msg reply query result;
+    msg send replica sync as primary;
+    msg send monitor status as observer;
+}"#;
+const SEND_UNSET: &'static str = r#"when (message ping) {
+    msg send other pong { text = ${missing} };
+}"#;
+const SEND_SCHEMA: &'static str = r#"topic pong { text: string, count: int }
+
+when (message ping) {
+    msg send other pong { text = hello, count = ${ping.count} };
+}"#;
+const SET_EXPR: &'static str = r#"when (message peer) {
+    var set backend ${peer.addr};
+    var set count ${count + 1};
+    var set label ${"node-" + count};
+}"#;
+const SET_EXPR_ERROR: &'static str = r#"when (message peer) {
+    var set count ${count - peer.addr};
+}"#;
+const ELAPSED_TIMER: &'static str = r#"when (elapsed 15s) { var set ticked true; }"#;
+const EVERY_TIMER: &'static str = r#"when (every 1h) { }"#;
+const AT_TIMER: &'static str = r#"when (at 03:00) { }"#;
+
+struct TestClock(Arc<Mutex<u64>>);
+
+impl Clock for TestClock {
+    fn now(&self) -> u64 {
+        *self.0.lock().unwrap()
+    }
+}
+
+fn set_test_clock<T: Storage>(st: &mut State<T>, now: u64) -> Arc<Mutex<u64>> {
+    let now = Arc::new(Mutex::new(now));
+    st.set_clock(Box::new(TestClock(now.clone())));
+    now
+}
+
+struct TestOutbox(Arc<Mutex<Vec<Message>>>);
+
+impl Outbox for TestOutbox {
+    fn send_msg(&self, msg: Message) -> Result<()> {
+        self.0.lock().unwrap().push(msg);
+        Ok(())
+    }
+}
+
+fn set_test_outbox<T: Storage>(st: &mut State<T>) -> Arc<Mutex<Vec<Message>>> {
+    let sent = Arc::new(Mutex::new(vec![]));
+    st.set_outbox(Box::new(TestOutbox(sent.clone())));
+    sent
+}
+
 fn test_msg(topic: &str, contents: Obj) -> Message {
     Message::new(topic, contents)
         .src_agent("test_src")
@@ -707,7 +797,7 @@ fn nested_two_msgs<T: Storage>(f: StateFactory<T>) {
                Some(&Value::from_str("false")));
     // foo message was matched; bar message was unmatched
     let msgs = st.mut_storage()
-        .next_messages(&[MessageFilter {
+        .peek_messages(&[MessageFilter {
                              topic: "bar".to_string(),
                              src_role: None,
                          },
@@ -717,8 +807,1308 @@ fn nested_two_msgs<T: Storage>(f: StateFactory<T>) {
                          }]
                                 .iter()
                                 .cloned()
-                                .collect())
+                                .collect(), &HashSet::new())
         .unwrap();
     assert!(msgs.contains_key("bar"));
     assert!(!msgs.contains_key("foo"));
 }
+
+#[test]
+fn mem_timer_elapsed() {
+    timer_elapsed(mem_state)
+}
+
+#[test]
+fn durable_timer_elapsed() {
+    timer_elapsed(durable_state)
+}
+
+fn timer_elapsed<T: Storage>(f: StateFactory<T>) {
+    setup();
+    let m_exc = Match::new_from_ast(&parse_one_match(ELAPSED_TIMER));
+    let (st, _cleanup) = f();
+    let mut st = st;
+    let now = set_test_clock(&mut st, 1000);
+    // Timers that have never fired are due immediately.
+    let mut txn = match st.eval(m_exc.clone()).unwrap() {
+        Some(txn) => txn,
+        None => panic!("expected match"),
+    };
+    assert!(st.commit(&mut txn).is_ok());
+    assert_eq!(st.storage().vars().get("ticked"),
+               Some(&Value::from_str("true")));
+    assert_eq!(st.storage().timers().values().cloned().collect::<Vec<_>>(),
+               vec![1000]);
+    match st.eval(m_exc.clone()).unwrap() {
+        Some(_) => panic!("unexpected match"),
+        None => {}
+    }
+    *now.lock().unwrap() = 1014;
+    match st.eval(m_exc.clone()).unwrap() {
+        Some(_) => panic!("unexpected match"),
+        None => {}
+    }
+    *now.lock().unwrap() = 1015;
+    let mut txn = match st.eval(m_exc.clone()).unwrap() {
+        Some(txn) => txn,
+        None => panic!("expected match"),
+    };
+    assert!(st.commit(&mut txn).is_ok());
+    assert_eq!(st.storage().timers().values().cloned().collect::<Vec<_>>(),
+               vec![1015]);
+}
+
+#[test]
+fn durable_timer_survives_restart() {
+    setup();
+    let m_exc = Match::new_from_ast(&parse_one_match(ELAPSED_TIMER));
+    let mut storage_path_buf = std::env::temp_dir();
+    storage_path_buf.push(rand_string());
+    let storage_path = storage_path_buf.to_str().unwrap();
+    let _cleanup = cleanup::Cleanup::Dir(storage_path.to_string());
+    {
+        let mut st = State::new("test", DurableStorage::new(storage_path).unwrap());
+        set_test_clock(&mut st, 1000);
+        let mut txn = match st.eval(m_exc.clone()).unwrap() {
+            Some(txn) => txn,
+            None => panic!("expected match"),
+        };
+        assert!(st.commit(&mut txn).is_ok());
+    }
+    let mut st = State::new("test", DurableStorage::new(storage_path).unwrap());
+    let now = set_test_clock(&mut st, 1010);
+    match st.eval(m_exc.clone()).unwrap() {
+        Some(_) => panic!("unexpected match"),
+        None => {}
+    }
+    *now.lock().unwrap() = 1020;
+    match st.eval(m_exc.clone()).unwrap() {
+        Some(_) => {}
+        None => panic!("expected match"),
+    }
+}
+
+#[test]
+fn mem_peek_ack_messages() {
+    peek_ack_messages(mem_state)
+}
+
+#[test]
+fn durable_peek_ack_messages() {
+    peek_ack_messages(durable_state)
+}
+
+fn peek_ack_messages<T: Storage>(f: StateFactory<T>) {
+    setup();
+    let m_exc = Match::new_from_ast(&parse_one_match("when (message foo, foo.n == 2) { }"));
+    let (st, _cleanup) = f();
+    let mut st = st;
+    for n in 1..4 {
+        st.mut_storage()
+            .push_msg(test_msg("foo",
+                               [("n".to_string(), Value::from_int(n))].iter().cloned().collect()))
+            .unwrap();
+    }
+    // The match holds on whichever message it can, without consuming any until committed.
+    for _ in 0..2 {
+        let mut txn = st.eval(m_exc.clone()).unwrap().expect("expected match");
+        let n = txn.with_context(|ctx| ctx.msgs["foo"].contents.get("n").cloned());
+        assert_eq!(n, Some(Value::from_int(2)));
+    }
+    let mut txn = st.eval(m_exc.clone()).unwrap().expect("expected match");
+    assert!(st.commit(&mut txn).is_ok());
+    assert!(st.eval(m_exc.clone()).unwrap().is_none());
+
+    let filters = m_exc.filters();
+    let mut seen = vec![];
+    let mut skip = HashSet::new();
+    loop {
+        let msgs = st.mut_storage().peek_messages(&filters, &skip).unwrap();
+        match msgs.get("foo") {
+            Some(msg) => {
+                skip.insert(msg.id.to_string());
+                seen.push(msg.clone());
+            }
+            None => break,
+        }
+    }
+    let mut ns = seen.iter().filter_map(|msg| msg.contents["n"].as_int()).collect::<Vec<_>>();
+    ns.sort();
+    assert_eq!(ns, vec![1, 3]);
+    for msg in &seen {
+        st.mut_storage().ack_msg(msg).unwrap();
+        st.mut_storage().ack_msg(msg).unwrap();
+    }
+    assert!(!st.storage().has_pending("foo"));
+}
+
+#[test]
+fn mem_fifo_priority() {
+    fifo_priority(mem_state)
+}
+
+#[test]
+fn durable_fifo_priority() {
+    fifo_priority(durable_state)
+}
+
+fn fifo_priority<T: Storage>(f: StateFactory<T>) {
+    setup();
+    let (st, _cleanup) = f();
+    let mut st = st;
+    let push = |st: &mut State<T>, topic: &str, n: i32, priority: i32| {
+        let contents = [("n".to_string(), Value::from_int(n))].iter().cloned().collect();
+        st.mut_storage().push_msg(test_msg(topic, contents).priority(priority)).unwrap();
+    };
+    // Consume all the messages a match holds on, returning them in the order consumed.
+    let consume = |st: &mut State<T>, src: &str| {
+        let m_exc = Match::new_from_ast(&parse_one_match(src));
+        let mut result = vec![];
+        while let Some(mut txn) = st.eval(m_exc.clone()).unwrap() {
+            let n = txn.with_context(|ctx| ctx.msgs.values().next().unwrap().contents["n"].as_int());
+            result.push(n.unwrap());
+            assert!(st.commit(&mut txn).is_ok());
+        }
+        result
+    };
+
+    for n in 1..4 {
+        push(&mut st, "ping", n, 0);
+    }
+    push(&mut st, "ping", 4, 10);
+    push(&mut st, "ping", 5, -1);
+    push(&mut st, "ping", 6, 10);
+    assert_eq!(consume(&mut st, "when (message ping) { }"), vec![4, 6, 1, 2, 3, 5]);
+
+    // Messages on all the topics a pattern matches are consumed in the order they arrived.
+    push(&mut st, "work.b", 1, 0);
+    push(&mut st, "work.a", 2, 0);
+    push(&mut st, "work.b", 3, 0);
+    assert_eq!(consume(&mut st, "when (message work.*) { }"), vec![1, 2, 3]);
+
+    // A message which fails to commit is retried after the others.
+    push(&mut st, "ping", 1, 0);
+    push(&mut st, "ping", 2, 0);
+    let m_exc = Match::new_from_ast(&parse_one_match("when (message ping) { }"));
+    let txn = st.eval(m_exc.clone()).unwrap().expect("expected match");
+    assert!(st.rollback(txn).is_ok());
+    push(&mut st, "ping", 3, 0);
+    assert_eq!(consume(&mut st, "when (message ping) { }"), vec![2, 3, 1]);
+}
+
+#[test]
+fn durable_messages_survive_restart() {
+    setup();
+    let m_exc = Match::new_from_ast(&parse_one_match("when (message foo, is_set ready) { }"));
+    let mut storage_path_buf = std::env::temp_dir();
+    storage_path_buf.push(rand_string());
+    let storage_path = storage_path_buf.to_str().unwrap();
+    let _cleanup = cleanup::Cleanup::Dir(storage_path.to_string());
+    let spool_files = || {
+        let mut names = std::fs::read_dir(format!("{}/topics/foo", storage_path))
+            .unwrap()
+            .map(|dirent| dirent.unwrap().file_name().to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    };
+    {
+        let mut st = State::new("test", DurableStorage::new(storage_path).unwrap());
+        st.mut_storage().push_msg(test_msg("foo", Obj::new())).unwrap();
+        let files = spool_files();
+        assert_eq!(files.len(), 1);
+        // Missing leaves the spool as it was.
+        assert!(st.eval(m_exc.clone()).unwrap().is_none());
+        assert_eq!(spool_files(), files);
+        // Matching without committing doesn't consume the message.
+        st.mut_storage()
+            .save(0,
+                  [("ready".to_string(), Value::from_str("true"))].iter().cloned().collect())
+            .unwrap();
+        assert!(st.eval(m_exc.clone()).unwrap().is_some());
+        assert_eq!(spool_files(), files);
+    }
+    let mut st = State::new("test", DurableStorage::new(storage_path).unwrap());
+    st.mut_storage().push_msg(test_msg("foo", Obj::new())).unwrap();
+    // Messages stored after a restart are ordered after those recovered.
+    assert_eq!(spool_files(), vec!["0000000000000000", "0000000000000001"]);
+    let mut txn = st.eval(m_exc.clone()).unwrap().expect("expected match");
+    assert!(st.commit(&mut txn).is_ok());
+    assert_eq!(spool_files().len(), 1);
+}
+
+#[test]
+fn durable_deferred_survive_restart() {
+    setup();
+    let m_exc = Match::new_from_ast(&parse_one_match("when (message ping) { }"));
+    let mut storage_path_buf = std::env::temp_dir();
+    storage_path_buf.push(rand_string());
+    let storage_path = storage_path_buf.to_str().unwrap();
+    let _cleanup = cleanup::Cleanup::Dir(storage_path.to_string());
+    let push = |st: &mut State<DurableStorage>, n: i32| {
+        let contents = [("n".to_string(), Value::from_int(n))].iter().cloned().collect();
+        st.mut_storage().push_msg(test_msg("ping", contents)).unwrap();
+    };
+    let defer_next = |st: &mut State<DurableStorage>| {
+        let txn = st.eval(m_exc.clone()).unwrap().expect("expected match");
+        assert!(st.rollback(txn).is_ok());
+    };
+    {
+        let mut st = State::new("test", DurableStorage::new(storage_path).unwrap());
+        for n in 1..4 {
+            push(&mut st, n);
+        }
+        defer_next(&mut st);
+        defer_next(&mut st);
+    }
+    // Messages deferred before a restart are still peeked after the others, in the order
+    // they were deferred, and after those deferred since.
+    let mut st = State::new("test", DurableStorage::new(storage_path).unwrap());
+    push(&mut st, 4);
+    defer_next(&mut st);
+    let mut consumed = vec![];
+    while let Some(mut txn) = st.eval(m_exc.clone()).unwrap() {
+        let n = txn.with_context(|ctx| ctx.msgs.values().next().unwrap().contents["n"].as_int());
+        consumed.push(n.unwrap());
+        assert!(st.commit(&mut txn).is_ok());
+    }
+    assert_eq!(consumed, vec![4, 1, 2, 3]);
+}
+
+#[test]
+fn mem_journal() {
+    journal(mem_state);
+}
+
+#[test]
+fn durable_journal() {
+    journal(durable_state);
+}
+
+fn journal<T: Storage>(f: StateFactory<T>) {
+    setup();
+    let m_exc = Match::new_from_ast(&parse_one_match(
+        r#"when "pinged" (message ping) { var set count 1; var unset idle; msg send self pong {}; }"#));
+    let (mut st, _cleanup) = f();
+    assert!(st.storage().journal(0).unwrap().is_empty());
+    for _ in 0..2 {
+        let ping = test_msg("ping", Obj::new());
+        let ping_id = ping.id.to_string();
+        st.mut_storage().push_msg(ping).unwrap();
+        let mut txn = st.eval(m_exc.clone()).unwrap().expect("expected match");
+        assert!(st.commit(&mut txn).is_ok());
+        let journal = st.storage().journal(0).unwrap();
+        let entry = journal.last().unwrap();
+        assert_eq!(entry.name, "\"pinged\"");
+        assert_eq!(entry.msgs, vec![ping_id]);
+        assert!(entry.scripts.is_empty());
+        assert_eq!(entry.actions.len(), 3);
+        match &entry.actions[0] {
+            &JournalAction::SetVar { ref name, ref value } => {
+                assert_eq!(name, "count");
+                assert_eq!(value, &Value::Int(1));
+            }
+            a => panic!("unexpected action {:?}", a),
+        }
+        match &entry.actions[1] {
+            &JournalAction::UnsetVar { ref name } => assert_eq!(name, "idle"),
+            a => panic!("unexpected action {:?}", a),
+        }
+        match &entry.actions[2] {
+            &JournalAction::SendMsg { ref dst_agent, ref topic, .. } => {
+                assert_eq!(dst_agent, "self");
+                assert_eq!(topic, "pong");
+            }
+            a => panic!("unexpected action {:?}", a),
+        }
+    }
+    let seqs = st.storage().journal(0).unwrap().iter().map(|e| e.seq).collect::<Vec<_>>();
+    assert_eq!(seqs, vec![0, 1]);
+    let seqs = st.storage().journal(1).unwrap().iter().map(|e| e.seq).collect::<Vec<_>>();
+    assert_eq!(seqs, vec![1]);
+}
+
+#[test]
+fn durable_commit_crash() {
+    setup();
+    let m_exc = Match::new_from_ast(&parse_one_match(
+        "when (message ping) { var set count 2; msg send self pong {}; }"));
+    for &(step, committed) in &[(SaveStep::Write, false),
+                                (SaveStep::Rename, false),
+                                (SaveStep::Push, true),
+                                (SaveStep::Ack, true)] {
+        let mut storage_path_buf = std::env::temp_dir();
+        storage_path_buf.push(rand_string());
+        let storage_path = storage_path_buf.to_str().unwrap();
+        let _cleanup = cleanup::Cleanup::Dir(storage_path.to_string());
+        {
+            let mut st = State::new("test", DurableStorage::new(storage_path).unwrap());
+            st.mut_storage()
+                .save(0, [("count".to_string(), Value::Int(1))].iter().cloned().collect())
+                .unwrap();
+            st.mut_storage().push_msg(test_msg("ping", Obj::new())).unwrap();
+            let mut txn = st.eval(m_exc.clone()).unwrap().expect("expected match");
+            st.mut_storage().crash_at(Some(step));
+            // Once the checkpoint is in place, the commit stands.
+            assert_eq!(st.commit(&mut txn).is_ok(), committed, "{:?}", step);
+        }
+        // Reopening sees either the state before the commit or after it, never a mix.
+        let mut storage = DurableStorage::new(storage_path).unwrap();
+        let (seq, vars) = storage.load().unwrap();
+        assert_eq!(seq, if committed { 2 } else { 1 }, "{:?}", step);
+        assert_eq!(vars.get("count"),
+                   Some(&Value::Int(if committed { 2 } else { 1 })),
+                   "{:?}",
+                   step);
+        assert_eq!(storage.has_pending("ping"), !committed, "{:?}", step);
+        assert_eq!(storage.has_pending("pong"), committed, "{:?}", step);
+    }
+}
+
+#[test]
+fn durable_apply_checkpoint_retried() {
+    setup();
+    let m_exc = Match::new_from_ast(&parse_one_match(
+        "when (message ping) { msg send self pong {}; }"));
+    for &step in &[SaveStep::Push, SaveStep::Ack] {
+        let mut storage_path_buf = std::env::temp_dir();
+        storage_path_buf.push(rand_string());
+        let storage_path = storage_path_buf.to_str().unwrap();
+        let _cleanup = cleanup::Cleanup::Dir(storage_path.to_string());
+        let spool_len = |topic: &str| {
+            std::fs::read_dir(format!("{}/topics/{}", storage_path, topic))
+                .map(|dirh| dirh.count())
+                .unwrap_or(0)
+        };
+        let mut st = State::new("test", DurableStorage::new(storage_path).unwrap());
+        st.mut_storage().push_msg(test_msg("ping", Obj::new())).unwrap();
+        let mut txn = st.eval(m_exc.clone()).unwrap().expect("expected match");
+        st.mut_storage().crash_at(Some(step));
+        assert!(st.commit(&mut txn).is_ok(), "{:?}", step);
+        assert!(!st.storage().has_pending("ping"), "{:?}", step);
+        assert_eq!(spool_len("ping"), 1, "{:?}", step);
+        // The next save finishes applying the checkpoint left unfinished.
+        st.mut_storage().crash_at(None);
+        let mut txn = st.eval(Match::new_from_ast(&parse_one_match("when (message pong) { }")))
+            .unwrap();
+        if step == SaveStep::Push {
+            assert!(txn.is_none());
+            st.mut_storage().save(1, HashMap::new()).unwrap();
+        } else {
+            assert!(st.commit(txn.as_mut().expect("expected match")).is_ok());
+        }
+        assert_eq!(spool_len("ping"), 0, "{:?}", step);
+        assert_eq!(spool_len("pong"), if step == SaveStep::Push { 1 } else { 0 }, "{:?}", step);
+    }
+}
+
+#[test]
+fn timer_every() {
+    setup();
+    let m_exc = Match::new_from_ast(&parse_one_match(EVERY_TIMER));
+    let (mut st, _cleanup) = mem_state();
+    let now = set_test_clock(&mut st, 10 * 3600 + 100);
+    let mut txn = st.eval(m_exc.clone()).unwrap().expect("expected match");
+    assert!(st.commit(&mut txn).is_ok());
+    *now.lock().unwrap() = 10 * 3600 + 3599;
+    assert!(st.eval(m_exc.clone()).unwrap().is_none());
+    // Periods are aligned to the epoch, not to the last firing.
+    *now.lock().unwrap() = 11 * 3600;
+    let mut txn = st.eval(m_exc.clone()).unwrap().expect("expected match");
+    assert!(st.commit(&mut txn).is_ok());
+    assert!(st.eval(m_exc.clone()).unwrap().is_none());
+}
+
+#[test]
+fn timer_at() {
+    setup();
+    let m_exc = Match::new_from_ast(&parse_one_match(AT_TIMER));
+    let (mut st, _cleanup) = mem_state();
+    let day = 17000 * 86400;
+    // A time of day that has never fired waits for that time to come.
+    let now = set_test_clock(&mut st, day + 2 * 3600);
+    assert!(st.eval(m_exc.clone()).unwrap().is_none());
+    *now.lock().unwrap() = day + 2 * 3600 + 59 * 60;
+    assert!(st.eval(m_exc.clone()).unwrap().is_none());
+    *now.lock().unwrap() = day + 3 * 3600;
+    let mut txn = st.eval(m_exc.clone()).unwrap().expect("expected match");
+    assert!(st.commit(&mut txn).is_ok());
+    *now.lock().unwrap() = day + 23 * 3600;
+    assert!(st.eval(m_exc.clone()).unwrap().is_none());
+    *now.lock().unwrap() = day + 86400 + 2 * 3600;
+    assert!(st.eval(m_exc.clone()).unwrap().is_none());
+    *now.lock().unwrap() = day + 86400 + 3 * 3600 + 60;
+    assert!(st.eval(m_exc.clone()).unwrap().is_some());
+}
+
+#[test]
+fn timer_named_match() {
+    setup();
+    // Timers of a named match are keyed by its name, so they carry over when its conditions
+    // change.
+    let m_exc = Match::new_from_ast(&parse_one_match(r#"when "rotate" (every 1h) { }"#));
+    assert_eq!(format!("{}", m_exc), "\"rotate\"");
+    let (mut st, _cleanup) = mem_state();
+    let now = set_test_clock(&mut st, 10 * 3600 + 100);
+    let mut txn = st.eval(m_exc.clone()).unwrap().expect("expected match");
+    assert!(st.commit(&mut txn).is_ok());
+    assert!(st.storage().timers().contains_key("rotate"));
+    *now.lock().unwrap() = 10 * 3600 + 200;
+    let m_exc = Match::new_from_ast(&parse_one_match(r#"when "rotate" (every 2h) { }"#));
+    assert!(st.eval(m_exc).unwrap().is_none());
+
+    let m_exc = Match::new_from_ast(&parse_one_match(EVERY_TIMER));
+    assert_eq!(format!("{}", m_exc), "when (every 1h)");
+}
+
+#[test]
+fn timer_unnamed_matches() {
+    setup();
+    // Unnamed matches with the same conditions keep separate timers, keyed by position.
+    let g = grammar::glop("when (every 1h) { var set a 1; }\nwhen (every 1h) { var set b 1; }")
+        .unwrap();
+    let m_excs = g.matches
+        .iter()
+        .enumerate()
+        .map(|(i, m_ast)| Match::new_at(m_ast, i))
+        .collect::<Vec<_>>();
+    let (mut st, _cleanup) = mem_state();
+    set_test_clock(&mut st, 10 * 3600 + 100);
+    for m_exc in &m_excs {
+        let mut txn = st.eval(m_exc.clone()).unwrap().expect("expected match");
+        assert!(st.commit(&mut txn).is_ok());
+    }
+    assert!(st.storage().timers().contains_key("#0"));
+    assert!(st.storage().timers().contains_key("#1"));
+    assert_eq!(st.storage().vars().get("a"), Some(&Value::Int(1)));
+    assert_eq!(st.storage().vars().get("b"), Some(&Value::Int(1)));
+}
+
+#[test]
+fn timer_next_due() {
+    let day = 17000 * 86400;
+    assert_eq!(Timer::Elapsed(15).next_due(None, day), 0);
+    assert_eq!(Timer::Elapsed(15).next_due(Some(1000), day), 1015);
+    assert_eq!(Timer::Elapsed(u64::max_value()).next_due(Some(1000), day), u64::max_value());
+    assert_eq!(Timer::Every(3600).next_due(Some(10 * 3600 + 100), day), 11 * 3600);
+    assert_eq!(Timer::At(3, 0).next_due(Some(day + 2 * 3600), day), day + 3 * 3600);
+    assert_eq!(Timer::At(3, 0).next_due(Some(day + 3 * 3600), day), day + 86400 + 3 * 3600);
+    assert_eq!(Timer::At(3, 0).next_due(None, day + 3600), day + 3 * 3600);
+    assert!(!Timer::At(3, 0).is_due(None, day + 3600));
+    assert!(Timer::At(3, 0).is_due(None, day + 4 * 3600));
+    assert!(!Timer::Elapsed(u64::max_value()).is_due(Some(1000), day));
+
+    let m_exc = Match::new_from_ast(&parse_one_match(r#"when (message foo, (elapsed 15s or every 1m)) {
+    when (message bar, at 03:00) { }
+}"#));
+    assert_eq!(m_exc.required_topics(), vec!["foo"]);
+    let mut timers = HashMap::new();
+    assert_eq!(m_exc.due_at(&timers, day), Some(0));
+    timers.insert("#0".to_string(), day + 50);
+    timers.insert("#0/0".to_string(), day + 50);
+    assert_eq!(m_exc.due_at(&timers, day), Some(day + 60));
+    assert_eq!(Match::new_from_ast(&parse_one_match(SIMPLE_INIT)).due_at(&timers, day), None);
+}
+
+#[test]
+fn mem_pending_topics() {
+    pending_topics(mem_state)
+}
+
+#[test]
+fn durable_pending_topics() {
+    pending_topics(durable_state)
+}
+
+fn pending_topics<T: Storage>(f: StateFactory<T>) {
+    setup();
+    let (st, _cleanup) = f();
+    let mut st = st;
+    st.mut_storage().push_msg(test_msg("config.db", Obj::new())).unwrap();
+    for topic in &["config.db", "config.*", "**"] {
+        assert!(st.storage().has_pending(topic));
+    }
+    for topic in &["config", "config.db.*", "alert.**"] {
+        assert!(!st.storage().has_pending(topic));
+    }
+    let m_exc = Match::new_from_ast(&parse_one_match("when (message config.*) { }"));
+    let mut txn = st.eval(m_exc).unwrap().expect("expected match");
+    assert!(st.commit(&mut txn).is_ok());
+    assert!(!st.storage().has_pending("config.db"));
+}
+
+#[test]
+fn mem_match_msg_field() {
+    match_msg_field(mem_state)
+}
+
+#[test]
+fn durable_match_msg_field() {
+    match_msg_field(durable_state)
+}
+
+fn match_msg_field<T: Storage>(f: StateFactory<T>) {
+    setup();
+    let m_exc = Match::new_from_ast(&parse_one_match(MSG_FIELD_EQUAL));
+    let (st, _cleanup) = f();
+    let mut st = st;
+    st.mut_storage()
+        .push_msg(test_msg("ping",
+                           [("text".to_string(), Value::from_str("goodbye"))]
+                               .iter()
+                               .cloned()
+                               .collect()))
+        .unwrap();
+    // Message contents don't match; the message stays queued.
+    match st.eval(m_exc.clone()).unwrap() {
+        Some(_) => panic!("unexpected match"),
+        None => {}
+    }
+    let msgs = st.mut_storage().peek_messages(&m_exc.filters(), &HashSet::new()).unwrap();
+    assert_eq!(msgs.get("ping").unwrap().contents.get("text"),
+               Some(&Value::from_str("goodbye")));
+    st.mut_storage()
+        .push_msg(test_msg("ping",
+                           [("text".to_string(), Value::from_str("hello"))]
+                               .iter()
+                               .cloned()
+                               .collect()))
+        .unwrap();
+    let mut txn = st.eval(m_exc.clone()).unwrap().expect("expected match");
+    txn.with_context(|ctx| {
+        assert_eq!(ctx.get_msg("ping", &Identifier::from_str("text")),
+                   Some(&Value::from_str("hello")));
+    });
+    assert!(st.commit(&mut txn).is_ok());
+    assert!(st.eval(m_exc.clone()).unwrap().is_none());
+    assert_eq!(st.storage().vars().get("greeted"),
+               Some(&Value::from_str("true")));
+}
+
+#[test]
+fn mem_eval_candidates_bounded() {
+    eval_candidates_bounded(mem_state)
+}
+
+#[test]
+fn durable_eval_candidates_bounded() {
+    eval_candidates_bounded(durable_state)
+}
+
+fn eval_candidates_bounded<T: Storage>(f: StateFactory<T>) {
+    setup();
+    let m_exc = Match::new_from_ast(&parse_one_match(MSG_FIELD_EQUAL));
+    let ping = |text: &str| {
+        test_msg("ping",
+                 [("text".to_string(), Value::from_str(text))].iter().cloned().collect())
+    };
+    // Up to MAX_EVAL_CANDIDATES messages are tried in one evaluation.
+    for &(ahead, found) in &[(99, true), (100, false)] {
+        let (st, _cleanup) = f();
+        let mut st = st;
+        for _ in 0..ahead {
+            st.mut_storage().push_msg(ping("goodbye")).unwrap();
+        }
+        st.mut_storage().push_msg(ping("hello")).unwrap();
+        assert_eq!(st.eval(m_exc.clone()).unwrap().is_some(), found);
+        assert_eq!(st.has_untried(&m_exc), !found);
+        // The next evaluation carries on from the messages left untried.
+        assert!(st.eval(m_exc.clone()).unwrap().is_some());
+        assert!(!st.has_untried(&m_exc));
+    }
+
+    // Once past the messages left untried, evaluation starts over from the first pending.
+    let (st, _cleanup) = f();
+    let mut st = st;
+    for _ in 0..150 {
+        st.mut_storage().push_msg(ping("goodbye")).unwrap();
+    }
+    assert!(st.eval(m_exc.clone()).unwrap().is_none());
+    assert!(st.has_untried(&m_exc));
+    st.mut_storage().push_msg(ping("hello").priority(1)).unwrap();
+    assert!(st.eval(m_exc.clone()).unwrap().is_some());
+}
+
+#[test]
+fn mem_eval_check_once() {
+    eval_check_once(mem_state)
+}
+
+#[test]
+fn durable_eval_check_once() {
+    eval_check_once(durable_state)
+}
+
+fn eval_check_once<T: Storage>(f: StateFactory<T>) {
+    setup();
+    let mut dir_buf = std::env::temp_dir();
+    dir_buf.push(rand_string());
+    let dir = dir_buf.to_str().unwrap().to_string();
+    std::fs::create_dir_all(&dir).unwrap();
+    let _dir_cleanup = cleanup::Cleanup::Dir(dir.to_string());
+    let runs = || {
+        std::fs::read_to_string(format!("{}/runs", dir)).unwrap_or(String::new()).lines().count()
+    };
+    let src = |status: i32| {
+        format!("when (message ping, check #!/bin/sh\necho run >> {}/runs\nexit {}\n!#) {{ }}",
+                dir,
+                status)
+    };
+
+    // A check that fails is run once with each of the pending messages.
+    let (st, _cleanup) = f();
+    let mut st = st;
+    for _ in 0..3 {
+        st.mut_storage().push_msg(test_msg("ping", Obj::new())).unwrap();
+    }
+    assert!(st.eval(Match::new_from_ast(&parse_one_match(&src(1)))).unwrap().is_none());
+    assert_eq!(runs(), 3);
+
+    // One that holds is not run again when the transaction is applied.
+    let mut txn = st.eval(Match::new_from_ast(&parse_one_match(&src(0))))
+        .unwrap()
+        .expect("expected match");
+    assert!(st.commit(&mut txn).is_ok());
+    assert_eq!(runs(), 4);
+    let journal = st.storage().journal(0).unwrap();
+    assert_eq!(journal[0].scripts.len(), 1);
+}
+
+#[test]
+fn mem_eval_check_msg_field() {
+    eval_check_msg_field(mem_state)
+}
+
+#[test]
+fn durable_eval_check_msg_field() {
+    eval_check_msg_field(durable_state)
+}
+
+fn eval_check_msg_field<T: Storage>(f: StateFactory<T>) {
+    setup();
+    let m_exc = Match::new_from_ast(&parse_one_match(
+        "when (message ping, check #!/bin/sh\n[ \"$ping__text\" = hello ]\n!#) { }"));
+    let (st, _cleanup) = f();
+    let mut st = st;
+    for text in &["goodbye", "hello"] {
+        let contents = [("text".to_string(), Value::from_str(text))].iter().cloned().collect();
+        st.mut_storage().push_msg(test_msg("ping", contents)).unwrap();
+    }
+    // A check that fails with one message is run again with the next.
+    let mut txn = st.eval(m_exc.clone()).unwrap().expect("expected match");
+    let text = txn.with_context(|ctx| ctx.msgs["ping"].contents["text"].to_string());
+    assert_eq!(text, "hello");
+    assert!(st.commit(&mut txn).is_ok());
+    assert_eq!(st.storage().journal(0).unwrap()[0].scripts.len(), 2);
+}
+
+#[test]
+fn mem_nested_msg_field() {
+    nested_msg_field(mem_state)
+}
+
+#[test]
+fn durable_nested_msg_field() {
+    nested_msg_field(durable_state)
+}
+
+fn nested_msg_field<T: Storage>(f: StateFactory<T>) {
+    setup();
+    let m_exc = Match::new_from_ast(&parse_one_match(NESTED_MSG_FIELD));
+    let (st, _cleanup) = f();
+    let mut st = st;
+    st.mut_storage()
+        .push_msg(test_msg("config",
+                           [("mode".to_string(), Value::from_str("prod")),
+                            ("level".to_string(), Value::from_int(3))]
+                               .iter()
+                               .cloned()
+                               .collect()))
+        .unwrap();
+    let mut txn = st.eval(m_exc.clone()).unwrap().expect("expected match");
+    assert!(st.commit(&mut txn).is_ok());
+    assert_eq!(st.storage().vars().get("quiet"),
+               Some(&Value::from_str("true")));
+
+    st.mut_storage()
+        .push_msg(test_msg("config",
+                           [("mode".to_string(), Value::from_str("debug"))]
+                               .iter()
+                               .cloned()
+                               .collect()))
+        .unwrap();
+    let mut txn = st.eval(m_exc.clone()).unwrap().expect("expected match");
+    assert!(st.commit(&mut txn).is_ok());
+    assert_eq!(st.storage().vars().get("quiet"),
+               Some(&Value::from_str("false")));
+}
+
+fn eval_with_var<T: Storage>(f: StateFactory<T>, src: &str, k: &str, v: Value) -> bool {
+    let m_exc = Match::new_from_ast(&parse_one_match(src));
+    let (st, _cleanup) = f();
+    let mut st = st;
+    st.mut_storage()
+        .save(0, [(k.to_string(), v)].iter().cloned().collect())
+        .unwrap();
+    st.eval(m_exc).unwrap().is_some()
+}
+
+#[test]
+fn mem_match_ordered() {
+    match_ordered(mem_state)
+}
+
+#[test]
+fn durable_match_ordered() {
+    match_ordered(durable_state)
+}
+
+fn match_ordered<T: Storage>(f: StateFactory<T>) {
+    setup();
+    // Integer literals compare numerically, with ints or integer strings from scripts.
+    assert!(eval_with_var(f, NUMERIC_GREATER, "usage", Value::from_int(100)));
+    assert!(eval_with_var(f, NUMERIC_GREATER, "usage", Value::from_str("100")));
+    assert!(!eval_with_var(f, NUMERIC_GREATER, "usage", Value::from_str("9")));
+    assert!(!eval_with_var(f, NUMERIC_GREATER, "usage", Value::from_int(80)));
+    // Quoted literals compare as strings.
+    assert!(eval_with_var(f, STRING_GREATER, "usage", Value::from_str("9")));
+    assert!(!eval_with_var(f, STRING_GREATER, "usage", Value::from_str("100")));
+    // Unset vars never compare.
+    assert!(!eval_with_var(f, NUMERIC_GREATER, "other", Value::from_int(100)));
+}
+
+#[test]
+fn mem_match_regex() {
+    match_regex(mem_state)
+}
+
+#[test]
+fn durable_match_regex() {
+    match_regex(durable_state)
+}
+
+fn match_regex<T: Storage>(f: StateFactory<T>) {
+    setup();
+    // Literal patterns are compiled once, when the match is built.
+    match Match::new_from_ast(&parse_one_match(REGEX_MATCH)).conditions[0] {
+        Condition::Regex(_, ref re) => assert_eq!(re.as_str(), r"^1\.[0-9]+$"),
+        ref c => panic!("unexpected condition {:?}", c),
+    }
+    assert!(eval_with_var(f, REGEX_MATCH, "version", Value::from_str("1.12")));
+    assert!(!eval_with_var(f, REGEX_MATCH, "version", Value::from_str("2.0")));
+    assert!(!eval_with_var(f, REGEX_MATCH, "version", Value::from_str("1.x")));
+}
+
+#[test]
+fn mem_match_cmp_refs() {
+    match_cmp_refs(mem_state)
+}
+
+#[test]
+fn durable_match_cmp_refs() {
+    match_cmp_refs(durable_state)
+}
+
+fn match_cmp_refs<T: Storage>(f: StateFactory<T>) {
+    setup();
+    let m_exc = Match::new_from_ast(&parse_one_match(CMP_REFS));
+    let (st, _cleanup) = f();
+    let mut st = st;
+    let desired = |n: i32| test_msg("desired",
+                                    [("replicas".to_string(), Value::from_int(n))]
+                                        .iter()
+                                        .cloned()
+                                        .collect());
+    // An unset var differs from any value.
+    st.mut_storage().push_msg(desired(3)).unwrap();
+    let mut txn = st.eval(m_exc.clone()).unwrap().expect("expected match");
+    assert!(st.commit(&mut txn).is_ok());
+    assert_eq!(st.storage().vars().get("actual"),
+               Some(&Value::from_obj([("replicas".to_string(), Value::from_int(3))]
+                                         .iter()
+                                         .cloned()
+                                         .collect())));
+    // Converged; the message is left unconsumed.
+    st.mut_storage().push_msg(desired(3)).unwrap();
+    assert!(st.eval(m_exc.clone()).unwrap().is_none());
+}
+
+#[test]
+fn mem_match_cmp_refs_unset() {
+    match_cmp_refs_unset(mem_state)
+}
+
+#[test]
+fn durable_match_cmp_refs_unset() {
+    match_cmp_refs_unset(durable_state)
+}
+
+fn match_cmp_refs_unset<T: Storage>(f: StateFactory<T>) {
+    setup();
+    // Ordering with an unset side does not hold either way round, rather than failing.
+    assert!(!eval_with_var(f, CMP_REFS_ORDERED, "usage", Value::from_int(90)));
+    assert!(!eval_with_var(f, CMP_REFS_ORDERED, "limit", Value::from_int(80)));
+    let m_exc = Match::new_from_ast(&parse_one_match(CMP_REFS_ORDERED));
+    let (st, _cleanup) = f();
+    let mut st = st;
+    st.mut_storage()
+        .save(0,
+              [("usage".to_string(), Value::from_int(90)),
+               ("limit".to_string(), Value::from_int(80))]
+                  .iter()
+                  .cloned()
+                  .collect())
+        .unwrap();
+    assert!(st.eval(m_exc).unwrap().is_some());
+}
+
+#[test]
+fn cmp_maybe_unset() {
+    let one = Value::from_int(1);
+    let two = Value::from_str("2");
+    assert_eq!(CmpOpcode::Less.eval_maybe(Some(&one), Some(&two)), Some(true));
+    assert_eq!(CmpOpcode::NotEqual.eval_maybe(Some(&one), None), Some(true));
+    assert_eq!(CmpOpcode::Equal.eval_maybe(None, Some(&one)), Some(false));
+    assert_eq!(CmpOpcode::Equal.eval_maybe(None, None), Some(true));
+    // Unset values are not ordered, nor matched, with anything.
+    assert_eq!(CmpOpcode::LessEqual.eval_maybe(None, None), None);
+    assert_eq!(CmpOpcode::Greater.eval_maybe(Some(&one), None), None);
+    assert_eq!(CmpOpcode::Match.eval_maybe(None, Some(&one)), None);
+    let obj = Value::from_obj([("a".to_string(), one.clone())].iter().cloned().collect());
+    assert_eq!(CmpOpcode::Equal.eval_maybe(Some(&obj), Some(&obj.clone())), Some(true));
+    assert_eq!(CmpOpcode::NotEqual.eval_maybe(Some(&obj), Some(&one)), Some(true));
+    assert_eq!(CmpOpcode::Less.eval_maybe(Some(&obj), Some(&obj)), None);
+}
+
+#[test]
+fn mem_match_or() {
+    match_or(mem_state)
+}
+
+#[test]
+fn durable_match_or() {
+    match_or(durable_state)
+}
+
+fn match_or<T: Storage>(f: StateFactory<T>) {
+    setup();
+    let m_exc = Match::new_from_ast(&parse_one_match(EITHER_MSG));
+    let (st, _cleanup) = f();
+    let mut st = st;
+    assert!(st.eval(m_exc.clone()).unwrap().is_none());
+    st.mut_storage().push_msg(test_msg("bar", Obj::new())).unwrap();
+    st.mut_storage().push_msg(test_msg("foo", Obj::new())).unwrap();
+    // Only the first branch that holds consumes its message; bar is left for the next round.
+    let mut txn = st.eval(m_exc.clone()).unwrap().expect("expected match");
+    assert!(st.commit(&mut txn).is_ok());
+    assert_eq!(txn.matched_topics(),
+               ["foo".to_string()].iter().cloned().collect());
+    let mut txn = st.eval(m_exc.clone()).unwrap().expect("expected match");
+    assert!(st.commit(&mut txn).is_ok());
+    assert_eq!(txn.matched_topics(),
+               ["bar".to_string()].iter().cloned().collect());
+    assert!(st.eval(m_exc.clone()).unwrap().is_none());
+}
+
+#[test]
+fn mem_match_not() {
+    match_not(mem_state)
+}
+
+#[test]
+fn durable_match_not() {
+    match_not(durable_state)
+}
+
+fn match_not<T: Storage>(f: StateFactory<T>) {
+    setup();
+    let m_exc = Match::new_from_ast(&parse_one_match(NOT_MSG));
+    let (st, _cleanup) = f();
+    let mut st = st;
+    st.mut_storage().push_msg(test_msg("foo", Obj::new())).unwrap();
+    st.mut_storage().push_msg(test_msg("bar", Obj::new())).unwrap();
+    assert!(st.eval(m_exc.clone()).unwrap().is_none());
+    // A negated message is never consumed, so it keeps blocking the match.
+    assert!(st.eval(m_exc.clone()).unwrap().is_none());
+    let (st, _cleanup) = f();
+    let mut st = st;
+    st.mut_storage().push_msg(test_msg("foo", Obj::new())).unwrap();
+    let mut txn = st.eval(m_exc.clone()).unwrap().expect("expected match");
+    assert!(st.commit(&mut txn).is_ok());
+    assert_eq!(txn.matched_topics(),
+               ["foo".to_string()].iter().cloned().collect());
+}
+
+#[test]
+fn mem_match_grouped() {
+    match_grouped(mem_state)
+}
+
+#[test]
+fn durable_match_grouped() {
+    match_grouped(durable_state)
+}
+
+fn match_grouped<T: Storage>(f: StateFactory<T>) {
+    setup();
+    let m_exc = Match::new_from_ast(&parse_one_match(GROUPED_OR));
+    let (st, _cleanup) = f();
+    let mut st = st;
+    st.mut_storage().push_msg(test_msg("foo", Obj::new())).unwrap();
+    // foo alone isn't enough; the group also needs ready.
+    assert!(st.eval(m_exc.clone()).unwrap().is_none());
+    st.mut_storage()
+        .save(0, [("force".to_string(), Value::from_str("true"))].iter().cloned().collect())
+        .unwrap();
+    // The second branch holds, so foo is not consumed.
+    let mut txn = st.eval(m_exc.clone()).unwrap().expect("expected match");
+    assert!(st.commit(&mut txn).is_ok());
+    assert!(txn.matched_topics().is_empty());
+    let seq = st.storage().seq();
+    st.mut_storage()
+        .save(seq, [("ready".to_string(), Value::from_str("true"))].iter().cloned().collect())
+        .unwrap();
+    let mut txn = st.eval(m_exc.clone()).unwrap().expect("expected match");
+    assert!(st.commit(&mut txn).is_ok());
+    assert_eq!(txn.matched_topics(),
+               ["foo".to_string()].iter().cloned().collect());
+    assert!(st.eval(m_exc.clone()).unwrap().is_none());
+}
+
+#[test]
+fn mem_nested_else() {
+    nested_else(mem_state)
+}
+
+#[test]
+fn durable_nested_else() {
+    nested_else(durable_state)
+}
+
+fn nested_else<T: Storage>(f: StateFactory<T>) {
+    setup();
+    let m_exc = Match::new_from_ast(&parse_one_match(NESTED_ELSE));
+    let (st, _cleanup) = f();
+    let mut st = st;
+    st.mut_storage().push_msg(test_msg("foo", Obj::new())).unwrap();
+    let mut txn = st.eval(m_exc.clone()).unwrap().expect("expected match");
+    assert!(st.commit(&mut txn).is_ok());
+    assert_eq!(st.storage().vars().get("found"),
+               Some(&Value::from_str("false")));
+
+    let seq = st.storage().seq();
+    st.mut_storage()
+        .save(seq, [("bar".to_string(), Value::from_str("true"))].iter().cloned().collect())
+        .unwrap();
+    st.mut_storage().push_msg(test_msg("foo", Obj::new())).unwrap();
+    let mut txn = st.eval(m_exc.clone()).unwrap().expect("expected match");
+    assert!(st.commit(&mut txn).is_ok());
+    assert_eq!(st.storage().vars().get("found"),
+               Some(&Value::from_str("true")));
+}
+
+#[test]
+fn mem_reply_msg() {
+    reply_msg(mem_state)
+}
+
+#[test]
+fn durable_reply_msg() {
+    reply_msg(durable_state)
+}
+
+fn reply_msg<T: Storage>(f: StateFactory<T>) {
+    setup();
+    let m_exc = Match::new_from_ast(&parse_one_match(REPLY_MSG));
+    let (st, _cleanup) = f();
+    let mut st = st;
+    let sent = set_test_outbox(&mut st);
+    let ping = test_msg("ping",
+                        [("text".to_string(), Value::from_str("hello"))]
+                            .iter()
+                            .cloned()
+                            .collect());
+    let ping_id = ping.id.to_string();
+    st.mut_storage().push_msg(ping).unwrap();
+    let mut txn = st.eval(m_exc.clone()).unwrap().expect("expected match");
+    assert!(st.commit(&mut txn).is_ok());
+
+    let sent = sent.lock().unwrap();
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0].topic, "pong");
+    assert_eq!(sent[0].src_agent, "test");
+    assert_eq!(sent[0].dst_agent, "test_src");
+    assert_eq!(sent[0].in_reply_to, Some(ping_id));
+    assert_eq!(sent[0].contents.get("text"), Some(&Value::from_str("hello")));
+    assert_eq!(sent[0].contents.get("count"), Some(&Value::from_int(1)));
+
+    // Messages sent to self are queued locally.
+    let msgs = st.mut_storage()
+        .peek_messages(&[MessageFilter {
+                             topic: "log".to_string(),
+                             src_role: None,
+                         }]
+                                .iter()
+                                .cloned()
+                                .collect(), &HashSet::new())
+        .unwrap();
+    assert_eq!(msgs.get("log").unwrap().contents.get("from"),
+               Some(&Value::from_str("hello")));
+}
+
+#[test]
+fn mem_match_topic_patterns() {
+    match_topic_patterns(mem_state)
+}
+
+#[test]
+fn durable_match_topic_patterns() {
+    match_topic_patterns(durable_state)
+}
+
+fn match_topic_patterns<T: Storage>(f: StateFactory<T>) {
+    setup();
+    let m_exc = Match::new_from_ast(&parse_one_match(TOPIC_PATTERNS));
+    let (st, _cleanup) = f();
+    let mut st = st;
+    st.mut_storage().push_msg(test_msg("config", Obj::new())).unwrap();
+    st.mut_storage().push_msg(test_msg("config.db.port", Obj::new())).unwrap();
+    st.mut_storage().push_msg(test_msg("alert", Obj::new())).unwrap();
+    st.mut_storage().push_msg(test_msg("alert.disk.full", Obj::new())).unwrap();
+    assert!(st.eval(m_exc.clone()).unwrap().is_none());
+
+    st.mut_storage().push_msg(test_msg("config.db", Obj::new())).unwrap();
+    let mut txn = st.eval(m_exc.clone()).unwrap().expect("expected match");
+    let topics = txn.with_context(|ctx| {
+        let mut topics = ctx.msgs
+            .iter()
+            .map(|(k, msg)| format!("{} {}", k, msg.topic))
+            .collect::<Vec<_>>();
+        topics.sort();
+        topics
+    });
+    assert_eq!(topics, vec!["alert.** alert.disk.full", "config.* config.db"]);
+    assert!(st.commit(&mut txn).is_ok());
+    assert_eq!(txn.matched_topics(),
+               ["config.*".to_string(), "alert.**".to_string()].iter().cloned().collect());
+    // Messages on topics the patterns don't match are left as they were.
+    assert!(st.eval(m_exc.clone()).unwrap().is_none());
+    for topic in &["config", "config.db.port", "alert"] {
+        let m_exc = Match::new_from_ast(&parse_one_match(&format!("when (message {}) {{ }}",
+                                                                  topic)));
+        assert!(st.eval(m_exc).unwrap().is_some());
+    }
+}
+
+#[test]
+fn mem_send_roles() {
+    send_roles(mem_state)
+}
+
+#[test]
+fn durable_send_roles() {
+    send_roles(durable_state)
+}
+
+fn send_roles<T: Storage>(f: StateFactory<T>) {
+    setup();
+    let m_exc = Match::new_from_ast(&parse_one_match(SEND_ROLES));
+    let (st, _cleanup) = f();
+    let mut st = st;
+    let sent = set_test_outbox(&mut st);
+    st.mut_storage().push_msg(test_msg("query", Obj::new())).unwrap();
+    st.mut_storage().push_msg(test_msg("sync", Obj::new())).unwrap();
+    let mut txn = st.eval(m_exc.clone()).unwrap().expect("expected match");
+    assert!(st.commit(&mut txn).is_ok());
+
+    let sent = sent.lock().unwrap();
+    let roles = sent.iter()
+        .map(|msg| (msg.topic.to_string(), msg.src_role.clone()))
+        .collect::<Vec<_>>();
+    assert_eq!(roles,
+               vec![("result".to_string(), Some("server".to_string())),
+                    ("sync".to_string(), Some("primary".to_string())),
+                    ("status".to_string(), Some("observer".to_string()))]);
+}
+
+#[test]
+fn mem_send_ambiguous_role() {
+    send_ambiguous_role(mem_state)
+}
+
+#[test]
+fn durable_send_ambiguous_role() {
+    send_ambiguous_role(durable_state)
+}
+
+fn send_ambiguous_role<T: Storage>(f: StateFactory<T>) {
+    setup();
+    // Parsing rejects a send without a role among several, but a saved match may have one.
+    let mut m_ast = parse_one_match(SEND_ROLES);
+    if let ast::Action::SendMsg { ref mut src_role, .. } = m_ast.actions[1] {
+        *src_role = None;
+    }
+    let m_exc = Match::new_from_ast(&m_ast);
+    assert!(m_exc.acting_role.is_err());
+    let (st, _cleanup) = f();
+    let mut st = st;
+    let sent = set_test_outbox(&mut st);
+    st.mut_storage().push_msg(test_msg("query", Obj::new())).unwrap();
+    st.mut_storage().push_msg(test_msg("sync", Obj::new())).unwrap();
+    let mut txn = st.eval(m_exc.clone()).unwrap().expect("expected match");
+    match st.commit(&mut txn) {
+        Err(Error::Eval(ref msg)) => assert!(msg.contains("`as` role"), "{}", msg),
+        _ => panic!("expected evaluation error"),
+    }
+    assert!(st.rollback(txn).is_ok());
+    assert!(sent.lock().unwrap().is_empty());
+}
+
+#[test]
+fn mem_send_unset_ref() {
+    send_unset_ref(mem_state)
+}
+
+#[test]
+fn durable_send_unset_ref() {
+    send_unset_ref(durable_state)
+}
+
+fn send_unset_ref<T: Storage>(f: StateFactory<T>) {
+    setup();
+    let m_exc = Match::new_from_ast(&parse_one_match(SEND_UNSET));
+    let (st, _cleanup) = f();
+    let mut st = st;
+    let sent = set_test_outbox(&mut st);
+    st.mut_storage().push_msg(test_msg("ping", Obj::new())).unwrap();
+    let mut txn = st.eval(m_exc.clone()).unwrap().expect("expected match");
+    match st.commit(&mut txn) {
+        Err(Error::Eval(_)) => {}
+        _ => panic!("expected evaluation error"),
+    }
+    assert!(st.rollback(txn).is_ok());
+    assert!(sent.lock().unwrap().is_empty());
+    // The message is still there to be matched again.
+    assert!(st.eval(m_exc.clone()).unwrap().is_some());
+}
+
+#[test]
+fn mem_send_schema() {
+    send_schema(mem_state)
+}
+
+#[test]
+fn durable_send_schema() {
+    send_schema(durable_state)
+}
+
+fn send_schema<T: Storage>(f: StateFactory<T>) {
+    setup();
+    let g = grammar::glop(SEND_SCHEMA).unwrap();
+    let m_exc = Match::new_from_ast(&g.matches[0]);
+    let (st, _cleanup) = f();
+    let mut st = st;
+    st.set_schemas(Schemas::from_ast(&g.topics));
+    let sent = set_test_outbox(&mut st);
+    st.mut_storage()
+        .push_msg(test_msg("ping",
+                           [("count".to_string(), Value::from_str("one"))]
+                               .iter()
+                               .cloned()
+                               .collect()))
+        .unwrap();
+    let mut txn = st.eval(m_exc.clone()).unwrap().expect("expected match");
+    match st.commit(&mut txn) {
+        Err(Error::Schema(_)) => {}
+        _ => panic!("expected schema error"),
+    }
+    assert!(st.rollback(txn).is_ok());
+    assert!(sent.lock().unwrap().is_empty());
+
+    let filters = m_exc.filters();
+    assert!(st.mut_storage().peek_messages(&filters, &HashSet::new()).unwrap().contains_key("ping"));
+    st.mut_storage()
+        .push_msg(test_msg("ping",
+                           [("count".to_string(), Value::from_int(1))]
+                               .iter()
+                               .cloned()
+                               .collect()))
+        .unwrap();
+    let mut txn = st.eval(m_exc.clone()).unwrap().expect("expected match");
+    assert!(st.commit(&mut txn).is_ok());
+    let sent = sent.lock().unwrap();
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0].contents.get("count"), Some(&Value::from_int(1)));
+}
+
+#[test]
+fn mem_set_expr() {
+    set_expr(mem_state)
+}
+
+#[test]
+fn durable_set_expr() {
+    set_expr(durable_state)
+}
+
+fn set_expr<T: Storage>(f: StateFactory<T>) {
+    setup();
+    let m_exc = Match::new_from_ast(&parse_one_match(SET_EXPR));
+    let (st, _cleanup) = f();
+    let mut st = st;
+    let seq = st.storage().seq();
+    st.mut_storage()
+        .save(seq, [("count".to_string(), Value::from_str("41"))].iter().cloned().collect())
+        .unwrap();
+    st.mut_storage()
+        .push_msg(test_msg("peer",
+                           [("addr".to_string(), Value::from_str("10.0.0.1:80"))]
+                               .iter()
+                               .cloned()
+                               .collect()))
+        .unwrap();
+    let mut txn = st.eval(m_exc.clone()).unwrap().expect("expected match");
+    assert!(st.commit(&mut txn).is_ok());
+    let vars = st.storage().vars();
+    assert_eq!(vars.get("backend"), Some(&Value::from_str("10.0.0.1:80")));
+    assert_eq!(vars.get("count"), Some(&Value::from_int(42)));
+    // Later actions see vars set by earlier ones.
+    assert_eq!(vars.get("label"), Some(&Value::from_str("node-42")));
+}
+
+#[test]
+fn mem_set_expr_error() {
+    set_expr_error(mem_state)
+}
+
+#[test]
+fn durable_set_expr_error() {
+    set_expr_error(durable_state)
+}
+
+fn set_expr_error<T: Storage>(f: StateFactory<T>) {
+    setup();
+    let m_exc = Match::new_from_ast(&parse_one_match(SET_EXPR_ERROR));
+    let (st, _cleanup) = f();
+    let mut st = st;
+    let seq = st.storage().seq();
+    st.mut_storage()
+        .save(seq, [("count".to_string(), Value::from_int(1))].iter().cloned().collect())
+        .unwrap();
+    st.mut_storage()
+        .push_msg(test_msg("peer",
+                           [("addr".to_string(), Value::from_str("somewhere"))]
+                               .iter()
+                               .cloned()
+                               .collect()))
+        .unwrap();
+    let mut txn = st.eval(m_exc.clone()).unwrap().expect("expected match");
+    match st.commit(&mut txn) {
+        Err(Error::Eval(_)) => {}
+        _ => panic!("expected evaluation error"),
+    }
+    assert!(st.rollback(txn).is_ok());
+    // Nothing was saved; the message remains queued.
+    assert_eq!(st.storage().vars().get("count"), Some(&Value::from_int(1)));
+    assert!(st.eval(m_exc.clone()).unwrap().is_some());
+}
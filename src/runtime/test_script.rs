@@ -23,6 +23,13 @@ exit 1
 !#
 }
 "###;
+const SIMPLE_SCRIPT_KILLED: &'static str = r###"
+when (message init) {
+    script #!/bin/bash
+kill -KILL $$
+!#
+}
+"###;
 const ENV_CHECK_SCRIPT: &'static str = r###"
 when (message test) {
     var set foo bar;
@@ -32,6 +39,33 @@ env
 !#
 }
 "###;
+const TOPIC_ENV_SCRIPT: &'static str = r###"
+when (message config.*) {
+    script #!/bin/bash
+set -e
+[ "$(printenv 'GLOP_TOPIC__config.*')" = "config.db" ]
+!#
+}
+"###;
+const CHECK_SCRIPT: &'static str = r###"
+when (message test, check #!/bin/bash
+set -e
+[ "$(cargo run msg get test status)" = "up" ]
+[ "$(cargo run var get mode)" = "on" ]
+!#) {
+    var set healthy true;
+}
+"###;
+const CHECK_SCRIPT_READ_ONLY: &'static str = r###"
+when (message test, check #!/bin/bash
+cargo run var set mode off
+!#) { }
+"###;
+const CHECK_SCRIPT_TIMEOUT: &'static str = r###"
+when (message test, check 1s #!/bin/bash
+sleep 10
+!#) { }
+"###;
 const HELLO_SCRIPT_SERVER: &'static str = r###"
 when (message init) {
     var set foo bar;
@@ -126,7 +160,7 @@ fn simple_script_err() {
         Err(e) => {
             match e {
                 Error::Exec(rc, ref stderr) => {
-                    assert_eq!(rc, 1);
+                    assert_eq!(rc, Some(1));
                     assert_eq!(stderr, "crash and burn");
                 }
                 _ => {
@@ -165,6 +199,16 @@ fn env_check_script_ok() {
     assert!(st.commit(&mut txn).is_ok());
 }
 
+#[test]
+fn topic_env_script_ok() {
+    let _lock = signal_fix::lock();
+    let m_exc = Match::new_from_ast(&parse_one_match(TOPIC_ENV_SCRIPT));
+    let mut st = State::new("test", MemStorage::new());
+    st.mut_storage().push_msg(test_msg("config.db", Obj::new())).unwrap();
+    let mut txn = st.eval(m_exc).unwrap().expect("expected match");
+    assert!(st.commit(&mut txn).is_ok());
+}
+
 #[test]
 fn hello_script_server() {
     let _lock = signal_fix::lock();
@@ -217,3 +261,92 @@ fn script_server_access_msg() {
     assert_eq!(st.storage().vars().get("all"),
                Some(&Value::from_str("good")));
 }
+
+#[test]
+fn simple_script_killed() {
+    let _lock = signal_fix::lock();
+
+    let m_exc = Match::new_from_ast(&parse_one_match(SIMPLE_SCRIPT_KILLED));
+    let mut st = State::new("test", MemStorage::new());
+    st.mut_storage()
+        .push_msg(test_msg("init", Obj::new()))
+        .unwrap();
+    let mut txn = st.eval(m_exc).unwrap().expect("expected match");
+    // A script killed by a signal has no exit code.
+    match st.commit(&mut txn) {
+        Err(Error::Exec(None, _)) => {}
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("expected script to error"),
+    }
+    assert_eq!(txn.scripts().iter().map(|s| s.exit_status).collect::<Vec<_>>(), vec![None]);
+}
+
+fn eval_check<F>(src: &str, status: &str, f: F) -> bool
+    where F: FnOnce(&mut State<MemStorage>)
+{
+    let m_exc = Match::new_from_ast(&parse_one_match(src));
+    let mut st = State::new("test", MemStorage::new());
+    st.mut_storage()
+        .save(0, [("mode".to_string(), Value::from_str("on"))].iter().cloned().collect())
+        .unwrap();
+    st.mut_storage()
+        .push_msg(test_msg("test",
+                           [("status".to_string(), Value::from_str(status))]
+                               .iter()
+                               .cloned()
+                               .collect()))
+        .unwrap();
+    match st.eval(m_exc).unwrap() {
+        Some(mut txn) => {
+            assert!(st.commit(&mut txn).is_ok());
+            f(&mut st);
+            true
+        }
+        None => {
+            f(&mut st);
+            false
+        }
+    }
+}
+
+#[test]
+fn check_script() {
+    let _lock = signal_fix::lock();
+    assert!(eval_check(CHECK_SCRIPT, "up", |st| {
+        assert_eq!(st.storage().vars().get("healthy"), Some(&Value::from_str("true")));
+        // The journal records the check script having run.
+        let journal = st.storage().journal(0).unwrap();
+        assert_eq!(journal.len(), 1);
+        assert!(!journal[0].scripts.is_empty());
+        assert!(journal[0].scripts.iter().all(|s| s.check && s.exit_status == Some(0)));
+    }));
+    assert!(!eval_check(CHECK_SCRIPT, "down", |_| {}));
+}
+
+#[test]
+fn check_script_read_only() {
+    let _lock = signal_fix::lock();
+    assert!(!eval_check(CHECK_SCRIPT_READ_ONLY, "up", |st| {
+        assert_eq!(st.storage().vars().get("mode"), Some(&Value::from_str("on")));
+    }));
+}
+
+#[test]
+fn check_script_timeout() {
+    let _lock = signal_fix::lock();
+    let start = std::time::Instant::now();
+    assert!(!eval_check(CHECK_SCRIPT_TIMEOUT, "up", |_| {}));
+    assert!(start.elapsed() < std::time::Duration::from_secs(5));
+}
+
+#[test]
+fn check_script_last() {
+    let _lock = signal_fix::lock();
+    // Scripts only run once the other conditions hold.
+    let mut marker = std::env::temp_dir();
+    marker.push(format!("glop-check-{}", std::process::id()));
+    let marker = marker.to_str().unwrap().to_string();
+    let src = format!("when (check #!/bin/bash\ntouch {}\n!#, message other) {{ }}", marker);
+    assert!(!eval_check(&src, "up", |_| {}));
+    assert!(!std::path::Path::new(&marker).exists());
+}
@@ -0,0 +1,94 @@
+use std;
+
+use super::*;
+
+const SECS_PER_DAY: u64 = 86400;
+
+/// Source of the current time, in seconds since the Unix epoch.
+pub trait Clock {
+    fn now(&self) -> u64;
+}
+
+/// Clock backed by the system's wall clock.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        match std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH) {
+            Ok(d) => d.as_secs(),
+            Err(_) => 0,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Timer {
+    Elapsed(u64),
+    Every(u64),
+    At(u32, u32),
+}
+
+impl Timer {
+    pub fn new(c_ast: &ast::Condition) -> Option<Timer> {
+        match c_ast {
+            &ast::Condition::Elapsed(secs) => Some(Timer::Elapsed(secs)),
+            &ast::Condition::Every(secs) => Some(Timer::Every(secs)),
+            &ast::Condition::At(hour, minute) => Some(Timer::At(hour, minute)),
+            _ => None,
+        }
+    }
+
+    /// Earliest time at which the timer is due, given the time it last fired and the current
+    /// time.
+    pub fn next_due(&self, last: Option<u64>, now: u64) -> u64 {
+        let last = match (last, self) {
+            (Some(last), _) => last,
+            (None, &Timer::At(hour, minute)) => return at_on_day(hour, minute, now),
+            (None, _) => return 0,
+        };
+        match self {
+            &Timer::Elapsed(secs) => last.saturating_add(secs),
+            &Timer::Every(secs) => (last / secs + 1).saturating_mul(secs),
+            &Timer::At(hour, minute) => {
+                let scheduled = at_on_day(hour, minute, last);
+                if scheduled > last {
+                    scheduled
+                } else {
+                    scheduled + SECS_PER_DAY
+                }
+            }
+        }
+    }
+
+    /// Whether the timer is due at time `now`, given the time it last fired.
+    ///
+    /// A timer that has never fired is due at once, except for a time of day, which is due
+    /// once that time has come on the current day.
+    pub fn is_due(&self, last: Option<u64>, now: u64) -> bool {
+        let last = match (last, self) {
+            (Some(last), _) => last,
+            (None, &Timer::At(hour, minute)) => return now >= at_on_day(hour, minute, now),
+            (None, _) => return true,
+        };
+        match self {
+            &Timer::Elapsed(secs) => now >= last.saturating_add(secs),
+            &Timer::Every(secs) => now / secs > last / secs,
+            &Timer::At(hour, minute) => {
+                let today = at_on_day(hour, minute, now);
+                let scheduled = if today <= now {
+                    today
+                } else if today >= SECS_PER_DAY {
+                    today - SECS_PER_DAY
+                } else {
+                    return false;
+                };
+                last < scheduled
+            }
+        }
+    }
+}
+
+/// Time of day `hour:minute` on the (UTC) day of time `t`.
+fn at_on_day(hour: u32, minute: u32, t: u64) -> u64 {
+    t - t % SECS_PER_DAY + (hour as u64) * 3600 + (minute as u64) * 60
+}
@@ -1,7 +1,7 @@
 extern crate textnonce;
 
 use std;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::fs::OpenOptions;
 use std::io::Write;
 use std::os::unix::fs::OpenOptionsExt;
@@ -9,6 +9,7 @@ use std::sync::{Arc, Mutex};
 
 use super::*;
 use self::context::Context;
+use self::journal::ScriptRun;
 use self::value::Value;
 
 pub struct Transaction {
@@ -17,6 +18,29 @@ pub struct Transaction {
     pub ctx: Arc<Mutex<Context>>,
     pub applied: Vec<Action>,
     matched_topics: HashSet<String>,
+    fired_timers: HashSet<String>,
+    /// Scripts run so far, for the journal.
+    scripts: Mutex<Vec<ScriptRun>>,
+    /// Whether each check script run so far held, by script and the IDs of the messages it
+    /// was run with, so that it is not run again when the transaction is applied.
+    checks: Mutex<HashMap<(String, Vec<String>), bool>>,
+}
+
+/// Messages and timers that a match consumes when it fires.
+///
+/// Only conditions that contributed to the match holding are consumed: the first branch of an
+/// `or` that holds, and nothing under a `not`.
+#[derive(Default)]
+struct Consumed {
+    topics: HashSet<String>,
+    timers: HashSet<String>,
+}
+
+impl Consumed {
+    fn extend(&mut self, other: Consumed) {
+        self.topics.extend(other.topics);
+        self.timers.extend(other.timers);
+    }
 }
 
 impl Transaction {
@@ -27,11 +51,29 @@ impl Transaction {
             ctx: Arc::new(Mutex::new(ctx)),
             applied: vec![],
             matched_topics: HashSet::new(),
+            fired_timers: HashSet::new(),
+            scripts: Mutex::new(vec![]),
+            checks: Mutex::new(HashMap::new()),
         }
     }
 
+    /// Take over the record of scripts run while evaluating the same match with other
+    /// messages, so that the journal shows all the scripts evaluation ran.
+    pub fn carry_scripts(&mut self, other: Transaction) {
+        self.scripts = other.scripts;
+    }
+
     pub fn apply(&mut self) -> Result<Vec<Action>> {
-        self.matched_topics = self.m.topics();
+        match self.eval_match(&self.m) {
+            Some(consumed) => {
+                self.matched_topics = consumed.topics;
+                self.fired_timers = consumed.timers;
+            }
+            None => {
+                self.matched_topics = HashSet::new();
+                self.fired_timers = HashSet::new();
+            }
+        }
         let mut actions = self.m.actions.clone();
         let mut applied: Vec<Action> = vec![];
         loop {
@@ -42,44 +84,101 @@ impl Transaction {
             let mut resulting_actions = match action {
                 Action::SetVar(ref k, ref v) => {
                     let mut ctx = self.ctx.lock().unwrap();
-                    ctx.set_var(k, Value::Str(v.to_string()));
+                    ctx.set_var(k, v.clone());
                     vec![action.clone()]
                 }
+                Action::Set(ref k, ref e) => {
+                    let mut ctx = self.ctx.lock().unwrap();
+                    let v = e.eval(&mut ctx)?;
+                    ctx.set_var(k, v.clone());
+                    vec![Action::SetVar(k.clone(), v)]
+                }
                 Action::UnsetVar(ref k) => {
                     let mut ctx = self.ctx.lock().unwrap();
                     ctx.unset_var(k);
                     vec![action.clone()]
                 }
-                Action::Script(ref contents) => self.exec_script(contents)?,
-                Action::SendMsg {
-                    dst_remote: _,
-                    dst_agent: _,
-                    topic: _,
-                    in_reply_to: _,
-                    contents: _,
-                } => vec![action],
+                Action::Script(ref contents) => {
+                    // Scripts send from the match's acting role, unless they name another.
+                    let default_role = self.m.acting_role.clone();
+                    self.exec_script(contents)?
+                        .into_iter()
+                        .map(|a| match a {
+                                 Action::SendMsg { dst_remote,
+                                                   dst_agent,
+                                                   topic,
+                                                   in_reply_to,
+                                                   contents,
+                                                   src_role } => {
+                                     let src_role = match src_role {
+                                         Some(role) => Some(role),
+                                         None => {
+                                             default_role.clone().map_err(|_| {
+                                                 role_error("`--role` on each script send, \
+                                                             in a match with more than one \
+                                                             acting role")
+                                             })?
+                                         }
+                                     };
+                                     Ok(Action::SendMsg {
+                                            dst_remote: dst_remote,
+                                            dst_agent: dst_agent,
+                                            topic: topic,
+                                            in_reply_to: in_reply_to,
+                                            contents: contents,
+                                            src_role: src_role,
+                                        })
+                                 }
+                                 _ => Ok(a),
+                             })
+                        .collect::<Result<Vec<_>>>()?
+                }
+                Action::SendMsg { .. } => vec![action],
+                Action::Send {
+                    ref dst_agent,
+                    ref topic,
+                    ref contents,
+                    ref src_role,
+                } => {
+                    let mut ctx = self.ctx.lock().unwrap();
+                    vec![Action::SendMsg {
+                             dst_remote: None,
+                             dst_agent: dst_agent.to_string(),
+                             topic: topic.to_string(),
+                             in_reply_to: None,
+                             contents: Expr::eval_contents(contents, &mut ctx)?,
+                             src_role: src_role.clone().map_err(role_error)?,
+                         }]
+                }
                 Action::ReplyTo {
+                    ref src_topic,
                     ref topic,
                     ref contents,
+                    ref src_role,
                 } => {
-                    let ctx = self.ctx.lock().unwrap();
-                    match ctx.msgs.get(topic) {
+                    let mut ctx = self.ctx.lock().unwrap();
+                    let contents = Expr::eval_contents(contents, &mut ctx)?;
+                    match ctx.msgs.get(src_topic) {
                         Some(ref subject) => {
                             vec![Action::SendMsg {
                                      dst_agent: subject.src_agent.to_string(),
                                      dst_remote: subject.src_remote.clone(),
                                      topic: topic.to_string(),
-                                     in_reply_to: subject.in_reply_to.clone(),
-                                     contents: contents.clone(),
+                                     in_reply_to: Some(subject.id.to_string()),
+                                     contents: contents,
+                                     src_role: src_role.clone().map_err(role_error)?,
                                  }]
                         }
-                        None => return Err(Error::UndeliverableMessage(topic.to_string())),
+                        None => return Err(Error::UndeliverableMessage(src_topic.to_string())),
                     }
                 }
                 Action::Match(ref m) => {
-                    if self.eval_match(m) {
-                        self.matched_topics.extend(m.topics());
+                    if let Some(consumed) = self.eval_match(m) {
+                        self.matched_topics.extend(consumed.topics);
+                        self.fired_timers.extend(consumed.timers);
                         actions.append(&mut m.actions.clone())
+                    } else {
+                        actions.append(&mut m.else_actions.clone())
                     }
                     continue;
                 }
@@ -92,38 +191,133 @@ impl Transaction {
         self.matched_topics.clone()
     }
 
+    pub fn fired_timers(&self) -> HashSet<String> {
+        self.fired_timers.clone()
+    }
+
+    pub fn scripts(&self) -> Vec<ScriptRun> {
+        self.scripts.lock().unwrap().clone()
+    }
+
     pub fn eval(&self) -> bool {
-        self.eval_match(&self.m)
+        self.eval_match(&self.m).is_some()
     }
 
-    fn eval_match(&self, m: &Match) -> bool {
-        m.conditions
-            .iter()
-            .fold(true, |acc, c| acc && self.eval_condition(c))
+    fn eval_match(&self, m: &Match) -> Option<Consumed> {
+        let mut consumed = Consumed::default();
+        if self.eval_all(&m.conditions, &mut consumed) {
+            Some(consumed)
+        } else {
+            None
+        }
     }
 
-    fn eval_condition(&self, cond: &Condition) -> bool {
-        let ctx = self.ctx.lock().unwrap();
+    /// Evaluate conditions which must all hold, leaving scripts to run until the others do.
+    fn eval_all(&self, conditions: &Vec<Condition>, consumed: &mut Consumed) -> bool {
+        let (costly, cheap): (Vec<_>, Vec<_>) = conditions.iter().partition(|c| c.is_costly());
+        cheap.into_iter().chain(costly).all(|c| self.eval_condition(c, consumed))
+    }
+
+    fn eval_condition(&self, cond: &Condition, consumed: &mut Consumed) -> bool {
+        match cond {
+            &Condition::Not(ref c) => return !self.eval_condition(c, &mut Consumed::default()),
+            &Condition::Or(ref cs) => {
+                for c in cs {
+                    let mut branch = Consumed::default();
+                    if self.eval_condition(c, &mut branch) {
+                        consumed.extend(branch);
+                        return true;
+                    }
+                }
+                return false;
+            }
+            &Condition::And(ref cs) => return self.eval_all(cs, consumed),
+            &Condition::Check { ref script, timeout } => {
+                // Check scripts see the messages in their environment, so a result only holds
+                // for the same messages.
+                let key = {
+                    let ctx = self.ctx.lock().unwrap();
+                    let mut ids =
+                        ctx.msgs.values().map(|msg| msg.id.to_string()).collect::<Vec<_>>();
+                    ids.sort();
+                    (script.to_string(), ids)
+                };
+                let checked = self.checks.lock().unwrap().get(&key).cloned();
+                if let Some(holds) = checked {
+                    return holds;
+                }
+                let holds = match self.run_check(script, timeout) {
+                    Ok(holds) => holds,
+                    Err(e) => {
+                        warn!("check condition failed: {}", e);
+                        false
+                    }
+                };
+                self.checks.lock().unwrap().insert(key, holds);
+                return holds;
+            }
+            _ => {}
+        }
+        let mut ctx = self.ctx.lock().unwrap();
         match cond {
             &Condition::Cmp(ref l, ref op, ref r) => {
-                match l.get(&ctx.vars) {
+                match l.get(&mut ctx) {
                     Some(v) => op.eval(v, r),
                     None => false,
                 }
             }
-            &Condition::IsSet(ref k) => k.is_set(&ctx.vars),
-            &Condition::IsUnset(ref k) => !k.is_set(&ctx.vars),
+            &Condition::Regex(ref l, ref re) => {
+                match l.get(&mut ctx) {
+                    Some(&Value::Object(_)) |
+                    None => false,
+                    Some(v) => re.is_match(&v.to_string()),
+                }
+            }
+            &Condition::CmpRef(ref l, ref op, ref r) => {
+                let lv = l.get(&mut ctx).cloned();
+                let rv = r.get(&mut ctx).cloned();
+                match op.eval_maybe(lv.as_ref(), rv.as_ref()) {
+                    Some(result) => result,
+                    None => {
+                        // A ref unset until something sets it is normal, so this is not a
+                        // warning; the condition just does not hold.
+                        let why = match (&lv, &rv) {
+                            (&None, _) => format!("{} is unset", l),
+                            (_, &None) => format!("{} is unset", r),
+                            _ => "objects only compare for equality".to_string(),
+                        };
+                        debug!("{} {:?} {} does not hold: {}", l, op, r, why);
+                        false
+                    }
+                }
+            }
+            &Condition::IsSet(ref k) => k.get(&mut ctx).is_some(),
+            &Condition::IsUnset(ref k) => k.get(&mut ctx).is_none(),
             &Condition::Message {
                  ref topic,
                  ref src_role,
                  acting_role: _,
              } => {
-                if let Some(msg) = ctx.msgs.get(topic) {
-                    src_role.eq(&msg.src_role)
-                } else {
-                    false
+                let matched = match ctx.msgs.get(topic) {
+                    Some(msg) => src_role.eq(&msg.src_role),
+                    None => false,
+                };
+                if matched {
+                    consumed.topics.insert(topic.to_string());
                 }
+                matched
             }
+            &Condition::Timer { ref key, ref timer } => {
+                let due = timer.is_due(ctx.timers.get(key).cloned(), ctx.now);
+                if due {
+                    consumed.timers.insert(key.to_string());
+                }
+                due
+            }
+            &Condition::Check { .. } |
+            &Condition::Not(_) |
+            &Condition::Or(_) |
+            &Condition::And(_) => unreachable!(),
         }
     }
 
@@ -135,27 +329,68 @@ impl Transaction {
     }
 
     fn exec_script(&mut self, contents: &str) -> Result<Vec<Action>> {
-        let mut script_path_buf = std::env::temp_dir();
-        let script_path_base = textnonce::TextNonce::sized_urlsafe(32)
-            .unwrap()
-            .into_string();
-        script_path_buf.push(&script_path_base);
-        let script_path = script_path_buf.to_str().unwrap();
-        let cleanup = cleanup::Cleanup::File(script_path.to_string());
-        {
-            let mut script_file = OpenOptions::new()
-                .write(true)
-                .mode(0o700)
-                .create_new(true)
-                .open(script_path)
-                .map_err(error::Error::IO)?;
-            script_file
-                .write_all(contents.as_bytes())
-                .map_err(error::Error::IO)?;
-        }
+        let (script_path, cleanup) = write_script(contents)?;
+        let started = std::time::Instant::now();
+        let result = script::run_script(self.ctx.clone(), &script_path);
+        self.record_script(false, &result, started);
+        drop(cleanup);
+        result
+    }
 
-        let actions = script::run_script(self.ctx.clone(), script_path)?;
+    fn run_check(&self, contents: &str, timeout: u64) -> Result<bool> {
+        let (script_path, cleanup) = write_script(contents)?;
+        let started = std::time::Instant::now();
+        let result = script::run_check(self.ctx.clone(),
+                                       &script_path,
+                                       std::time::Duration::from_secs(timeout));
+        self.record_script(true, &result, started);
         drop(cleanup);
-        Ok(actions)
+        match result {
+            Ok(()) => Ok(true),
+            Err(Error::Exec(_, _)) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn record_script<T>(&self, check: bool, result: &Result<T>, started: std::time::Instant) {
+        let elapsed = started.elapsed();
+        self.scripts.lock().unwrap().push(ScriptRun {
+            check: check,
+            exit_status: match result {
+                &Ok(_) => Some(0),
+                &Err(Error::Exec(code, _)) => code,
+                &Err(_) => None,
+            },
+            elapsed_ms: elapsed.as_secs() * 1000 + (elapsed.subsec_nanos() / 1_000_000) as u64,
+        });
+    }
+}
+
+/// The error for a message sent without a role, when there's more than one it could be sent
+/// from.
+fn role_error(expected: &'static str) -> Error {
+    Error::Eval(format!("expected {}", expected))
+}
+
+/// Write a script to a temporary file, to be removed with the cleanup returned.
+fn write_script(contents: &str) -> Result<(String, cleanup::Cleanup)> {
+    let mut script_path_buf = std::env::temp_dir();
+    let script_path_base = textnonce::TextNonce::sized_urlsafe(32)
+        .unwrap()
+        .into_string();
+    script_path_buf.push(&script_path_base);
+    let script_path = script_path_buf.to_str().unwrap().to_string();
+    let cleanup = cleanup::Cleanup::File(script_path.clone());
+    {
+        let mut script_file = OpenOptions::new()
+            .write(true)
+            .mode(0o700)
+            .create_new(true)
+            .open(&script_path)
+            .map_err(error::Error::IO)?;
+        script_file
+            .write_all(contents.as_bytes())
+            .map_err(error::Error::IO)?;
     }
+    Ok((script_path, cleanup))
 }
@@ -0,0 +1,112 @@
+use std;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use super::ast;
+use super::diagnostic;
+use super::error::{Error, Result};
+
+/// A parsed glop source file.
+#[derive(Clone)]
+pub struct Unit {
+    pub path: String,
+    pub src: String,
+    pub glop: ast::Glop,
+}
+
+/// Load the glop source at `path`, along with all the sources it includes.
+///
+/// Include paths are resolved relative to the directory of the including file. Matches from
+/// included sources come first, in the order they are included. A source included more than
+/// once is only loaded the first time; include cycles are an error. Topic declarations are
+/// collected the same way, and may only be declared once across all sources.
+///
+/// The bundle is validated as a whole, the same as when it is parsed by the agent server: match
+/// names must be unique and references are resolved across all the sources, so that a
+/// reference to a topic consumed in another source is rejected as ambiguous here rather than
+/// there.
+pub fn load(path: &str) -> Result<ast::Glop> {
+    let mut topics = vec![];
+    let mut matches = vec![];
+    for unit in load_units(path)? {
+        topics.extend(unit.glop.topics);
+        matches.extend(unit.glop.matches);
+    }
+    let matches = ast::check_topics(&topics)
+        .and_then(|_| ast::check_match_names(&matches))
+        .and_then(|_| ast::resolve_refs(matches))
+        .and_then(|ms| ast::check_roles(&ms).map(|_| ms))
+        .map_err(|e| Error::InvalidArgument(format!("{}: {}", path, e)))?;
+    Ok(ast::Glop {
+           comments: vec![],
+           includes: vec![],
+           topics: topics,
+           matches: matches,
+           trailing_comments: vec![],
+       })
+}
+
+/// Load the glop source at `path` and the sources it includes, each kept apart. Included
+/// sources come first, in the same order as their matches are loaded by `load`.
+pub fn load_units(path: &str) -> Result<Vec<Unit>> {
+    let mut loader = Loader {
+        loaded: HashSet::new(),
+        stack: vec![],
+        units: vec![],
+    };
+    loader.load(Path::new(path))?;
+    Ok(loader.units)
+}
+
+struct Loader {
+    loaded: HashSet<PathBuf>,
+    stack: Vec<PathBuf>,
+    units: Vec<Unit>,
+}
+
+impl Loader {
+    fn load(&mut self, path: &Path) -> Result<()> {
+        let path = path.canonicalize().map_err(|e| path_error(path, e))?;
+        if self.stack.contains(&path) {
+            let mut cycle = self.stack
+                .iter()
+                .skip_while(|p| **p != path)
+                .map(|p| p.to_string_lossy().to_string())
+                .collect::<Vec<_>>();
+            cycle.push(path.to_string_lossy().to_string());
+            return Err(Error::InvalidArgument(format!("include cycle: {}", cycle.join(" -> "))));
+        }
+        if self.loaded.contains(&path) {
+            return Ok(());
+        }
+
+        let mut contents = String::new();
+        File::open(&path)
+            .and_then(|mut f| f.read_to_string(&mut contents))
+            .map_err(|e| path_error(&path, e))?;
+        let path_str = path.to_string_lossy().to_string();
+        let glop = diagnostic::parse(&path_str, &contents)
+            .map_err(Error::Diagnostics)?;
+
+        self.stack.push(path.clone());
+        for include in &glop.includes {
+            let include_path = path.parent().unwrap().join(include);
+            self.load(&include_path)?;
+        }
+        self.stack.pop();
+        self.loaded.insert(path);
+
+        self.units.push(Unit {
+                            path: path_str,
+                            src: contents,
+                            glop: glop,
+                        });
+        Ok(())
+    }
+}
+
+fn path_error(path: &Path, e: std::io::Error) -> Error {
+    Error::IO(std::io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+}
@@ -0,0 +1,128 @@
+#![cfg(test)]
+
+use super::check;
+use super::grammar;
+use super::source::Unit;
+
+fn unit(path: &str, src: &str) -> Unit {
+    Unit {
+        path: path.to_string(),
+        src: src.to_string(),
+        glop: grammar::glop(src).unwrap(),
+    }
+}
+
+fn messages(agents: Vec<Vec<Unit>>) -> Vec<String> {
+    check::check(&agents)
+        .iter()
+        .map(|d| format!("{}:{}: {}", d.source, d.line, d.message))
+        .collect()
+}
+
+#[test]
+fn check_clean() {
+    let pinger = unit("pinger.glop",
+                      r#"when (message init) {
+    var set count 0;
+    msg send ponger ping;
+}
+
+when (message pong, count < 10) #!/bin/bash
+glop var set count 1
+glop msg send ponger ping
+!#"#);
+    let ponger = unit("ponger.glop",
+                      r#"topic status { ok: string }
+
+when (message ping) { msg reply ping pong; }
+
+when (message status) { }"#);
+    assert_eq!(messages(vec![vec![pinger], vec![ponger]]), Vec::<String>::new());
+}
+
+#[test]
+fn check_never_set() {
+    let common = unit("common.glop", "when (message init) { var set ready true; }");
+    let agent = unit("agent.glop",
+                     r#"when (message init) { }
+
+when (ready == true, is_set runnning) {
+    var set running true;
+}"#);
+    assert_eq!(messages(vec![vec![common, agent]]),
+               vec!["agent.glop:3: var runnning is tested but never set"]);
+}
+
+#[test]
+fn check_never_sent() {
+    let agent = unit("agent.glop",
+                     r#"when (message init) { msg send self ping; }
+
+when (message ping) {
+    when (message pnog) { }
+}"#);
+    assert_eq!(messages(vec![vec![agent]]),
+               vec!["agent.glop:4: topic pnog is matched but never sent by any checked agent"]);
+}
+
+#[test]
+fn check_topic_patterns() {
+    let agent = unit("agent.glop",
+                     r#"when (message init) { msg send self alert.disk.full; }
+
+when (message alert.**) { }
+
+when (message config.*) { }"#);
+    assert_eq!(messages(vec![vec![agent]]),
+               vec!["agent.glop:5: topic config.* is matched but never sent by any checked agent"]);
+}
+
+#[test]
+fn check_contradictions() {
+    let agent = unit("agent.glop",
+                     r#"when (message init) { var set mode on; var set level 1; }
+
+when (is_set mode, is_unset mode) { }
+
+when (mode == on, (mode == off)) { }
+
+when (mode == on, mode != on) { }
+
+when (level > 5, level <= 3) { }
+
+when (level > 5, not level > 5) { }
+
+when (level > 1, level < 3, mode == on or mode == off) { }"#);
+    assert_eq!(messages(vec![vec![agent]]),
+               vec!["agent.glop:3: conditions can never all hold: `is_set mode` and `is_unset \
+                     mode`",
+                    "agent.glop:5: conditions can never all hold: `mode == on` and `mode == off`",
+                    "agent.glop:7: conditions can never all hold: `mode == on` and `mode != on`",
+                    "agent.glop:9: conditions on level can never all hold",
+                    "agent.glop:11: conditions can never all hold: `level > 5` and `not level \
+                     > 5`"]);
+}
+
+#[test]
+fn check_nested_topic() {
+    let agent = unit("agent.glop",
+                     r#"when (message init) { msg send self ping; }
+
+when (message ping) {
+    when (is_set ping.text) { }
+    when (message ping) { }
+}"#);
+    assert_eq!(messages(vec![vec![agent]]),
+               vec!["agent.glop:5: nested match on topic ping, which is consumed by an \
+                     enclosing match"]);
+}
+
+#[test]
+fn check_at_condition() {
+    // Problems with a condition are reported where the condition is, not where its match is.
+    let src = "when (message init,
+      is_set ready) { }";
+    let diags = check::check(&vec![vec![unit("a.glop", src)]]);
+    assert_eq!(diags.iter().map(|d| (d.line, d.column, d.message.as_str())).collect::<Vec<_>>(),
+               vec![(2, 7, "var ready is tested but never set")]);
+}
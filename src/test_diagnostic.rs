@@ -0,0 +1,101 @@
+#![cfg(test)]
+
+use super::ast;
+use super::diagnostic;
+
+#[test]
+fn diagnostic_position() {
+    let src = "when (message init) {\n    var set foo;\n}\n";
+    let diags = diagnostic::parse("test.glop", src).err().expect("expected errors");
+    assert_eq!(diags.len(), 1);
+    assert_eq!((diags[0].line, diags[0].column), (2, 16));
+    assert_eq!(diags[0].snippet, "    var set foo;");
+    assert_eq!(format!("{}", diags[0]),
+               format!("error: {}\n --> test.glop:2:16\n  |\n2 |     var set foo;\n  |                \
+                        ^",
+                       diags[0].message));
+}
+
+#[test]
+fn diagnostic_multiple() {
+    let src = r#"when (message init) {
+    var set foo bar
+}
+
+when (message ping) {
+    msg reply ping pong;
+}
+
+when (message pong {
+}
+"#;
+    let diags = diagnostic::parse("test.glop", src).err().expect("expected errors");
+    assert_eq!(diags.iter().map(|d| d.line).collect::<Vec<_>>(), vec![3, 9]);
+}
+
+#[test]
+fn diagnostic_unclosed_script() {
+    let src = r#"when (message init) {
+    script #!/bin/bash
+echo hello
+}
+
+when (message ping) #!/bin/bash
+echo pong
+!#
+"#;
+    let diags = diagnostic::parse("test.glop", src).err().expect("expected errors");
+    assert_eq!(diags.len(), 1);
+    assert_eq!((diags[0].line, diags[0].column), (2, 12));
+    assert_eq!(diags[0].message, "script is not closed with `!#`");
+}
+
+#[test]
+fn diagnostic_spans() {
+    let src = "topic ping {}\n\nwhen (message ping) {\n    when (is_set foo) {\n    }\n}\n";
+    let glop = diagnostic::parse("test.glop", src).unwrap();
+    let span = glop.topics[0].span;
+    assert_eq!(&src[span.start..span.end], "topic ping {}");
+    let span = glop.matches[0].span;
+    assert_eq!(&src[span.start..span.end], &src[15..src.len() - 1]);
+    assert_eq!(diagnostic::line_col(src, span.start), (3, 1));
+    let spans = &glop.matches[0].condition_spans;
+    assert_eq!(spans.iter().map(|span| &src[span.start..span.end]).collect::<Vec<_>>(),
+               vec!["message ping"]);
+    let nested = match glop.matches[0].actions[0] {
+        ast::Action::Match(ref m) => m,
+        _ => panic!("expected nested match"),
+    };
+    let span = nested.condition_spans[0];
+    assert_eq!(&src[span.start..span.end], "is_set foo");
+    assert_eq!(diagnostic::line_col(src, span.start), (4, 11));
+}
+
+#[test]
+fn diagnostic_indented_item() {
+    // Only items starting a line are parsed on their own; an indented one is reported
+    // together with the item before it.
+    let src = r#"when (message init) {
+    var set foo bar
+}
+
+  when (message pong {
+}
+"#;
+    let diags = diagnostic::parse("test.glop", src).err().expect("expected errors");
+    assert_eq!(diags.iter().map(|d| d.line).collect::<Vec<_>>(), vec![3]);
+}
+
+#[test]
+fn diagnostic_header_comment() {
+    // Comments before the first item are parsed with it, rather than as an item of their own.
+    let src = r#"// leading comment
+
+when (message init) {
+    var set x 1
+}
+"#;
+    let diags = diagnostic::parse("test.glop", src).err().expect("expected errors");
+    assert_eq!(diags.len(), 1);
+    assert_eq!((diags[0].line, diags[0].message.as_str()), (5, "expected `;`"));
+}
@@ -0,0 +1,66 @@
+#![cfg(test)]
+
+use super::error::Error;
+use super::format;
+
+#[test]
+fn format_comments() {
+    let src = r#"// Shared behavior.
+include "common.glop";
+
+// Requested configuration.
+topic configure { release: string }
+
+/* Set up
+   once. */
+when (message init) {
+    // Not yet installed.
+    var set installed false;
+    var set initialized true; // Ready.
+    when (is_set debug) {
+        var set verbose true;
+        // Nothing else.
+    }
+}
+
+when (message configure) #!/bin/bash
+echo "// not a comment"
+!#
+
+// The end.
+"#;
+    assert_eq!(format::format("test.glop", src).unwrap(), src);
+}
+
+#[test]
+fn format_canonical() {
+    let src = r#"when(message init){var set foo bar;   // done
+// more
+}
+when (message configure) {
+    script #!/bin/bash
+echo configure
+!#
+}"#;
+    let expected = r#"when (message init) {
+    var set foo bar; // done
+    // more
+}
+
+when (message configure) #!/bin/bash
+echo configure
+!#
+"#;
+    let formatted = format::format("test.glop", src).unwrap();
+    assert_eq!(formatted, expected);
+    assert_eq!(format::format("test.glop", &formatted).unwrap(), formatted);
+}
+
+#[test]
+fn err_format_comment() {
+    let src = "when (message init, /* here */ is_set foo) {\n}\n";
+    match format::format("test.glop", src) {
+        Err(Error::Diagnostics(ref diags)) => assert_eq!(diags[0].column, 21),
+        _ => panic!("expected a comment that cannot be kept"),
+    }
+}
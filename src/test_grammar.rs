@@ -1,7 +1,27 @@
 #![cfg(test)]
 
+use super::ast::{self, Action, Condition, Expr, Literal, Ref};
 use super::grammar;
 
+/// Keys and string values of the `var set` actions in the first match.
+fn set_strings(src: &str) -> Vec<(Vec<String>, String)> {
+    let g = grammar::glop(src).unwrap();
+    g.matches[0]
+        .actions
+        .iter()
+        .filter_map(|a| match a {
+                        &Action::SetVar(ref k, Expr::Literal(Literal::Str(ref s))) => {
+                            Some((k.clone(), s.to_string()))
+                        }
+                        _ => None,
+                    })
+        .collect()
+}
+
+fn key(parts: &[&str]) -> Vec<String> {
+    parts.iter().map(|p| p.to_string()).collect()
+}
+
 #[test]
 fn round_trip_simple() {
     let src = r#"when (message init) {
@@ -45,3 +65,474 @@ fn err_empty_actions() {
     assert!(grammar::glop(r#"when (foo == "bar") { }"#).is_ok());
     assert!(grammar::glop(r#"when (foo == "bar")"#).is_err());
 }
+
+#[test]
+fn round_trip_timers() {
+    let src = r#"when (elapsed 15s, is_set running) {
+    var set checked true;
+}
+
+when (every 5m) {
+}
+
+when (at 03:00, message backup) {
+}
+
+when (every 2h, elapsed 1d) {
+}
+
+"#;
+    let g = grammar::glop(src).unwrap();
+    assert_eq!(format!("{}", g), src);
+}
+
+#[test]
+fn err_timers() {
+    assert!(grammar::glop(r#"when (elapsed 15) { }"#).is_err());
+    assert!(grammar::glop(r#"when (elapsed 0s) { }"#).is_err());
+    assert!(grammar::glop(r#"when (every 5ms) { }"#).is_err());
+    assert!(grammar::glop(r#"when (every 999999999999999d) { }"#).is_err());
+    assert!(grammar::glop(r#"when (elapsed 99999999999999999999s) { }"#).is_err());
+    assert!(grammar::glop(r#"when (at 24:00) { }"#).is_err());
+    assert!(grammar::glop(r#"when (at 3:60) { }"#).is_err());
+    assert!(grammar::glop(r#"when (at 3:05) { }"#).is_ok());
+}
+
+#[test]
+fn examples_parse() {
+    for src in &[include_str!("../examples/pingmsg.glop"),
+                 include_str!("../examples/pingpong.glop"),
+                 include_str!("../examples/pingpong2.glop"),
+                 include_str!("../examples/pingpong3.glop"),
+                 include_str!("../examples/pinger.glop"),
+                 include_str!("../examples/ponger.glop"),
+                 include_str!("../examples/seafile.glop")] {
+        if let Err(e) = grammar::glop(src) {
+            panic!("{}: {}", e, src);
+        }
+    }
+}
+
+#[test]
+fn round_trip_msg_fields() {
+    let src = r#"when (message ping, ping.text == hello, is_set ping.reply.to, seen != ping) {
+    var set seen true;
+}
+
+when (message config.update, config.update.mode != debug) {
+}
+
+"#;
+    let g = grammar::glop(src).unwrap();
+    assert_eq!(format!("{}", g), src);
+}
+
+#[test]
+fn err_msg_fields() {
+    // Topics consumed by a different match can't be referenced.
+    assert!(grammar::glop(r#"when (message ping) { }
+when (ping.text == hello) { }"#)
+        .is_err());
+    // Nor can topics consumed only by a nested match.
+    assert!(grammar::glop(r#"when (message foo, bar.text == hello) {
+    when (message bar) { }
+}"#)
+        .is_err());
+    // A message itself has no value to compare.
+    assert!(grammar::glop(r#"when (message ping, ping == hello) { }"#).is_err());
+    assert!(grammar::glop(r#"when (message ping, pong.text == hello) { }"#).is_ok());
+}
+
+#[test]
+fn round_trip_typed_cmp() {
+    let src = r#"when (disk.usage > 80, replicas < desired, load >= -1, retries <= 3) {
+    var set alert true;
+    var set threshold 80;
+    var set label "80";
+}
+
+when (version =~ r"^1\.[0-9]+$", name =~ web, flag != false, tag == "true") {
+}
+
+"#;
+    let g = grammar::glop(src).unwrap();
+    assert_eq!(format!("{}", g), src);
+}
+
+#[test]
+fn round_trip_cmp_refs() {
+    let src = r#"when (message desired, desired.replicas != actual.replicas, version == ${applied}) {
+}
+
+when (name =~ ${pattern}, config.`log-level` <= ${`max-level`}, mode == on) {
+}
+
+"#;
+    let g = grammar::glop(src).unwrap();
+    assert_eq!(format!("{}", g), src);
+    match g.matches[0].conditions[1] {
+        Condition::CmpRef(Ref::Msg(ref topic, ref k), _, Ref::Var(ref r)) => {
+            assert_eq!(topic, "desired");
+            assert_eq!(k, &vec!["replicas"]);
+            assert_eq!(r, &vec!["actual", "replicas"]);
+        }
+        _ => panic!("expected comparison of message field with var"),
+    }
+    match g.matches[1].conditions[2] {
+        Condition::Cmp(_, _, Literal::Str(ref s)) => assert_eq!(s, "on"),
+        _ => panic!("expected comparison with string"),
+    }
+    assert!(grammar::glop("when (version == ${applied.}) { }").is_err());
+    assert!(grammar::glop("when (version == ${1}) { }").is_err());
+}
+
+#[test]
+fn err_typed_cmp() {
+    assert!(grammar::glop(r#"when (count > 99999999999) { }"#).is_err());
+    assert!(grammar::glop(r#"when (name =~ "(unclosed") { }"#).is_err());
+    assert!(grammar::glop(r#"when (name =~ 3) { }"#).is_err());
+}
+
+#[test]
+fn round_trip_boolean() {
+    let src = r#"when (message start or message stop, not is_set running) {
+    var set idle true;
+}
+
+when ((message start, is_set running) or (message stop, not is_set running)) {
+}
+
+when (not (foo == bar, every 1m) or notice == order) {
+}
+
+"#;
+    let g = grammar::glop(src).unwrap();
+    assert_eq!(format!("{}", g), src);
+}
+
+#[test]
+fn err_boolean() {
+    assert!(grammar::glop(r#"when (message foo or) { }"#).is_err());
+    assert!(grammar::glop(r#"when (not) { }"#).is_err());
+    assert!(grammar::glop(r#"when (()) { }"#).is_err());
+    assert!(grammar::glop(r#"when (not message foo as bar) { }"#).is_err());
+    assert!(grammar::glop(r#"when (message foo as a or message bar as b) { msg send c d; }"#)
+                .is_err());
+}
+
+#[test]
+fn round_trip_check() {
+    let src = r#"when (every 1m, check 30s #!/bin/sh
+pgrep -F /run/seafile.pid
+!#, not check #!/bin/sh
+test -e /etc/maintenance
+!#) {
+}
+
+"#;
+    assert_eq!(format!("{}", grammar::glop(src).unwrap()), src);
+    assert!(grammar::glop("when (check #!!#) { }").is_err());
+    assert!(grammar::glop("when (check 0s #!/bin/sh\ntrue\n!#) { }").is_err());
+}
+
+#[test]
+fn round_trip_named_matches() {
+    let src = r#"when "start-seafile" "Start seafile once it is configured" (message start) {
+    when "check-config" (is_set config) #!/bin/bash
+systemctl start seafile
+!#
+}
+
+when "heartbeat" (every 1m) {
+}
+
+when (message stop) {
+}
+
+"#;
+    let g = grammar::glop(src).unwrap();
+    assert_eq!(format!("{}", g), src);
+    assert_eq!(g.matches[0].name, Some("start-seafile".to_string()));
+    assert_eq!(g.matches[0].description,
+               Some("Start seafile once it is configured".to_string()));
+    assert_eq!(g.matches[1].description, None);
+    assert_eq!(g.matches[2].name, None);
+}
+
+#[test]
+fn err_named_matches() {
+    assert!(grammar::glop(r#"when "" (message a) { }"#).is_err());
+    assert!(grammar::glop(r#"when "a" "b" "c" (message a) { }"#).is_err());
+    assert!(grammar::glop(r#"when "a" (message a) { } when "a" (message b) { }"#).is_err());
+    assert!(grammar::glop(r#"when "a" (message a) { when "a" (message b) { } }"#).is_err());
+}
+
+#[test]
+fn round_trip_topic_patterns() {
+    let src = r#"when (message config.*, message alert.**.critical from monitor) {
+    msg reply config.* ack;
+}
+
+"#;
+    let g = grammar::glop(src).unwrap();
+    assert_eq!(format!("{}", g), src);
+    assert_eq!(g.matches[0].topics(),
+               ["config.*", "alert.**.critical"].iter().map(|t| t.to_string()).collect());
+    // Only messages are matched by pattern; sent topics are always concrete.
+    assert!(grammar::glop("when (message a) { msg send b c.*; }").is_err());
+    assert!(grammar::glop("when (message config.*x) { }").is_err());
+    assert!(grammar::glop("when (message config.***) { }").is_err());
+}
+
+#[test]
+fn topic_patterns() {
+    assert!(ast::topic_matches("config", "config"));
+    assert!(!ast::topic_matches("config", "config.db"));
+    assert!(ast::topic_matches("config.*", "config.db"));
+    assert!(!ast::topic_matches("config.*", "config"));
+    assert!(!ast::topic_matches("config.*", "config.db.port"));
+    assert!(ast::topic_matches("*.db", "config.db"));
+    assert!(ast::topic_matches("alert.**", "alert.disk"));
+    assert!(ast::topic_matches("alert.**", "alert.disk.full"));
+    assert!(!ast::topic_matches("alert.**", "alert"));
+    assert!(ast::topic_matches("alert.**.full", "alert.disk.sda.full"));
+    assert!(!ast::topic_matches("alert.**.full", "alert.full"));
+    assert!(!ast::is_topic_pattern("config.db"));
+    assert!(ast::is_topic_pattern("alert.**"));
+}
+
+#[test]
+fn round_trip_roles() {
+    let src = r#"when (message query as server, message sync as primary) {
+    msg send replica sync { seq = ${sync.seq} } as primary;
+    msg reply query result;
+    msg reply sync ack as server;
+    when (message status) {
+        msg send monitor status as observer;
+    }
+}
+
+when (message status as observer) {
+    msg send monitor status;
+}
+
+"#;
+    let g = grammar::glop(src).unwrap();
+    assert_eq!(format!("{}", g), src);
+    assert_eq!(g.matches[0].acting_roles(), vec!["primary", "server"]);
+}
+
+#[test]
+fn err_roles() {
+    // Sends must name their role when there's more than one to choose from, including the
+    // acting roles of enclosing matches.
+    assert!(grammar::glop(r#"when (message a as x, message b as y) { msg send c d; }"#).is_err());
+    assert!(grammar::glop(r#"when (message a as x) { when (message b as y) { msg send c d; } }"#)
+                .is_err());
+    assert!(grammar::glop(r#"when (message a as x, message b) { msg reply b d; }"#).is_ok());
+    assert!(grammar::glop(r#"when (message a as x, message b as y) { msg reply b d; }"#).is_ok());
+    assert!(grammar::glop(r#"when (message a as x) { msg send c d as; }"#).is_err());
+}
+
+#[test]
+fn round_trip_else() {
+    let src = r#"when (message config) {
+    when (config.mode == debug) {
+        var set verbose true;
+        when (is_set trace) {
+            var set verbose all;
+        } else {
+            var unset trace;
+        }
+    } else {
+        var set verbose false;
+    }
+    script #!/bin/bash
+echo done
+!#
+}
+
+"#;
+    let g = grammar::glop(src).unwrap();
+    assert_eq!(format!("{}", g), src);
+}
+
+#[test]
+fn err_else() {
+    assert!(grammar::glop(r#"when (message foo) { } else { }"#).is_err());
+    assert!(grammar::glop(r#"when (message foo) { when (is_set bar) { } else }"#).is_err());
+    // Message fields are not in scope in the else branch.
+    assert!(grammar::glop(r#"when (message foo) {
+    when (message bar) { } else { when (bar.x == 1) { } }
+}"#)
+                    .is_err());
+}
+
+#[test]
+fn round_trip_msg_actions() {
+    let src = r#"when (message ping from pinger as ponger) {
+    msg reply ping pong { text = ${ping.text}, count = 1, note = "a b" };
+    msg send monitor ping.seen { by = ${name}, origin.agent = pinger };
+    msg send self tick;
+}
+
+"#;
+    let g = grammar::glop(src).unwrap();
+    assert_eq!(format!("{}", g), src);
+}
+
+#[test]
+fn err_msg_actions() {
+    assert!(grammar::glop(r#"when (message ping) { msg send pong; }"#).is_err());
+    assert!(grammar::glop(r#"when (message ping) { msg send other pong { text }; }"#).is_err());
+    assert!(grammar::glop(r#"when (message ping) { msg send other pong { x = ${ping} }; }"#)
+                .is_err());
+    // Replies are only possible to messages consumed by the match.
+    assert!(grammar::glop(r#"when (is_set foo) { msg reply ping pong; }"#).is_err());
+}
+
+#[test]
+fn round_trip_exprs() {
+    let src = r#"when (message peer) {
+    var set backend.addr ${peer.addr};
+    var set count ${count + 1};
+    var set label ${"node-" + name + "-" + (count - 1)};
+    msg send self log { text = ${"seen " + peer.addr} };
+}
+
+"#;
+    let g = grammar::glop(src).unwrap();
+    assert_eq!(format!("{}", g), src);
+}
+
+#[test]
+fn err_exprs() {
+    assert!(grammar::glop(r#"when (is_set foo) { var set bar ${}; }"#).is_err());
+    assert!(grammar::glop(r#"when (is_set foo) { var set bar ${foo +}; }"#).is_err());
+    assert!(grammar::glop(r#"when (is_set foo) { var set bar ${(foo + 1}; }"#).is_err());
+    assert!(grammar::glop(r#"when (message foo) { var set bar ${foo}; }"#).is_err());
+}
+
+#[test]
+fn round_trip_includes() {
+    let src = r#"include "common/intro.glop";
+include "../health.glop";
+
+when (message init) {
+}
+
+"#;
+    let g = grammar::glop(src).unwrap();
+    assert_eq!(format!("{}", g), src);
+}
+
+#[test]
+fn round_trip_topics() {
+    let src = r#"include "common/intro.glop";
+
+topic configure { release: string, replicas: int? }
+topic status { health: object }
+topic ping {}
+
+when (message configure) {
+    var set release ${configure.release};
+}
+
+"#;
+    let g = grammar::glop(src).unwrap();
+    assert_eq!(format!("{}", g), src);
+    assert!(grammar::glop("topic ping {}").is_ok());
+}
+
+#[test]
+fn err_topics() {
+    assert!(grammar::glop(r#"topic ping { count: float }"#).is_err());
+    assert!(grammar::glop(r#"topic ping { count int }"#).is_err());
+    assert!(grammar::glop(r#"topic ping {} topic ping {}"#).is_err());
+    assert!(grammar::glop(r#"topic ping { count: int, count: string }"#).is_err());
+    assert!(grammar::glop(r#"when (message ping) {} topic ping {}"#).is_err());
+}
+
+#[test]
+fn lexical_strings() {
+    let src = r##"when (message init) {
+    var set empty "";
+    var set escaped "say \"hi\"\\\t\u{e9}\0";
+    var set lines "one
+two\r\n";
+    var set raw r"C:\dir\n";
+    var set raw_quoted r#"say "hi" \d+"#;
+    var set bare word_1;
+}"##;
+    assert_eq!(set_strings(src),
+               vec![(key(&["empty"]), "".to_string()),
+                    (key(&["escaped"]), "say \"hi\"\\\t\u{e9}\0".to_string()),
+                    (key(&["lines"]), "one\ntwo\r\n".to_string()),
+                    (key(&["raw"]), r"C:\dir\n".to_string()),
+                    (key(&["raw_quoted"]), r#"say "hi" \d+"#.to_string()),
+                    (key(&["bare"]), "word_1".to_string())]);
+}
+
+#[test]
+fn lexical_identifiers() {
+    let src = r#"when (message init) {
+    var set x "";
+    var set X.Y_2 "";
+    var set _private "";
+    var set config.`log-level`.`a.b` "";
+    var set `say \`hi\`` "";
+}"#;
+    let keys = set_strings(src).into_iter().map(|(k, _)| k).collect::<Vec<_>>();
+    assert_eq!(keys,
+               vec![key(&["x"]),
+                    key(&["X", "Y_2"]),
+                    key(&["_private"]),
+                    key(&["config", "log-level", "a.b"]),
+                    key(&["say `hi`"])]);
+}
+
+#[test]
+fn lexical_keywords() {
+    // Names which start with a keyword are names, not keywords.
+    assert!(grammar::glop(r#"when (messages > 1, is_settled == true, order == 1) { }"#).is_ok());
+    assert!(grammar::glop(r#"when (message p from q as r) { var set v.w 1; }"#).is_ok());
+    assert!(grammar::glop(r#"when (is_setx) { }"#).is_err());
+    assert!(grammar::glop(r#"when (message p fromq) { }"#).is_err());
+    assert!(grammar::glop(r#"when (x == 1 ory == 2) { }"#).is_err());
+}
+
+#[test]
+fn err_lexical() {
+    assert!(grammar::glop(r#"when (x == "a\qb") { }"#).is_err());
+    assert!(grammar::glop(r#"when (x == "\u{d800}") { }"#).is_err());
+    assert!(grammar::glop(r#"when (x == "\u{}") { }"#).is_err());
+    assert!(grammar::glop(r#"when (x == "unclosed) { }"#).is_err());
+    assert!(grammar::glop(r##"when (x == r#"unclosed") { }"##).is_err());
+    assert!(grammar::glop(r#"when (1x == y) { }"#).is_err());
+    assert!(grammar::glop(r#"when (a-b == y) { }"#).is_err());
+    assert!(grammar::glop(r#"when (`` == y) { }"#).is_err());
+    assert!(grammar::glop(r#"when (`a
+b` == y) { }"#).is_err());
+    assert!(grammar::glop(r#"when (x.. == y) { }"#).is_err());
+}
+
+#[test]
+fn round_trip_lexical() {
+    let src = r##"include "lib/\"quoted\".glop";
+
+topic `build-done` { `exit-code`: int }
+
+when (message `build-done` from `ci-server`, x == "", Y == "a b", `build-done`.`exit-code` != 0) {
+    var set msg "line one\nline two\t\"quoted\"";
+    var set pattern r"^v\d+$";
+    var set quoted_pattern r#"^"\w+"$"#;
+    msg send `log-agent` `build-done` { `exit-code` = ${`build-done`.`exit-code` + 1} };
+}
+
+"##;
+    let g = grammar::glop(src).unwrap();
+    assert_eq!(format!("{}", g), src);
+    let g = grammar::glop(r#"when (message ping) { var set x "a\\b"; }"#).unwrap();
+    assert_eq!(format!("{}", g),
+               "when (message ping) {\n    var set x r\"a\\b\";\n}\n\n");
+}
@@ -0,0 +1,269 @@
+#![cfg(test)]
+
+extern crate serde_json;
+extern crate textnonce;
+
+use std;
+use std::io::Write;
+
+use self::serde_json::Value as Json;
+
+use super::cleanup;
+use super::lsp;
+
+fn test_dir() -> (String, cleanup::Cleanup) {
+    let mut path_buf = std::env::temp_dir();
+    path_buf.push(textnonce::TextNonce::sized_urlsafe(32)
+                      .unwrap()
+                      .into_string());
+    let path = path_buf.to_str().unwrap().to_string();
+    std::fs::create_dir_all(&path).unwrap();
+    (path.clone(), cleanup::Cleanup::Dir(path))
+}
+
+fn msg(s: &str) -> Json {
+    serde_json::from_str(s).unwrap()
+}
+
+fn open(server: &mut lsp::Server, uri: &str, text: &str) -> Vec<Json> {
+    let mut params = msg(r#"{"textDocument": {"languageId": "glop", "version": 1}}"#);
+    params["textDocument"]["uri"] = Json::String(uri.to_string());
+    params["textDocument"]["text"] = Json::String(text.to_string());
+    let mut req = msg(r#"{"jsonrpc": "2.0", "method": "textDocument/didOpen"}"#);
+    req["params"] = params;
+    server.handle(&req)
+}
+
+fn at(server: &mut lsp::Server, method: &str, uri: &str, line: usize, character: usize) -> Json {
+    let req = msg(&format!(r#"{{"jsonrpc": "2.0", "id": 7, "method": "{}", "params": {{
+                                  "textDocument": {{"uri": "{}"}},
+                                  "position": {{"line": {}, "character": {}}}}}}}"#,
+                           method,
+                           uri,
+                           line,
+                           character));
+    let mut resp = server.handle(&req);
+    assert_eq!(resp.len(), 1);
+    assert_eq!(resp[0]["id"], Json::from(7));
+    resp.remove(0)["result"].clone()
+}
+
+#[test]
+fn lsp_diagnostics() {
+    let mut server = lsp::Server::new();
+    let out = open(&mut server, "file:///w/a.glop", "when (message ping) { var set count 1 }");
+    assert_eq!(out.len(), 1);
+    assert_eq!(out[0]["method"], Json::from("textDocument/publishDiagnostics"));
+    assert_eq!(out[0]["params"]["uri"], Json::from("file:///w/a.glop"));
+    let diags = out[0]["params"]["diagnostics"].as_array().unwrap();
+    assert_eq!(diags.len(), 1);
+    assert_eq!(diags[0]["severity"], Json::from(1));
+    assert_eq!(diags[0]["range"]["start"], msg(r#"{"line": 0, "character": 38}"#));
+
+    let out = server.handle(&msg(r#"{"jsonrpc": "2.0", "method": "textDocument/didChange",
+        "params": {"textDocument": {"uri": "file:///w/a.glop", "version": 2},
+                   "contentChanges": [{"text": "when (message ping) { var set count 1; }"}]}}"#));
+    let diags = out[0]["params"]["diagnostics"].as_array().unwrap();
+    assert_eq!(diags.len(), 1);
+    assert_eq!(diags[0]["severity"], Json::from(2));
+    assert_eq!(diags[0]["message"],
+               Json::from("topic ping is matched but never sent by any checked agent"));
+
+    // Sending the topic from another source resolves the warning.
+    let out = open(&mut server,
+                   "file:///w/b.glop",
+                   "when (message init) { msg send agent ping; }");
+    assert_eq!(out.len(), 2);
+    for n in out {
+        assert_eq!(n["params"]["diagnostics"], msg("[]"));
+    }
+}
+
+#[test]
+fn lsp_definition() {
+    let mut server = lsp::Server::new();
+    open(&mut server,
+         "file:///w/common.glop",
+         "when (message init) {\n    var set ready true;\n}");
+    open(&mut server,
+         "file:///w/agent.glop",
+         r#"include "common.glop";
+
+when (message go, is_set ready) #!/bin/bash
+glop var set ready.now true
+!#"#);
+    open(&mut server,
+         "file:///w/other.glop",
+         "when (message init) { var set ready false; }");
+    let result = at(&mut server, "textDocument/definition", "file:///w/agent.glop", 2, 27);
+    assert_eq!(result,
+               msg(r#"[
+        {"uri": "file:///w/agent.glop",
+         "range": {"start": {"line": 3, "character": 13},
+                   "end": {"line": 3, "character": 22}}},
+        {"uri": "file:///w/common.glop",
+         "range": {"start": {"line": 1, "character": 12},
+                   "end": {"line": 1, "character": 17}}}]"#));
+}
+
+#[test]
+fn lsp_hover() {
+    let mut server = lsp::Server::new();
+    open(&mut server,
+         "file:///w/pinger.glop",
+         "when (message init) { msg send ponger ping; }\n\nwhen (message pong) { }");
+    open(&mut server,
+         "file:///w/ponger.glop",
+         "topic ping { count: int? }\n\nwhen (message ping) {\n    msg reply ping pong;\n}");
+    let result = at(&mut server, "textDocument/hover", "file:///w/pinger.glop", 0, 40);
+    assert_eq!(result["contents"]["kind"], Json::from("markdown"));
+    assert_eq!(result["contents"]["value"],
+               Json::from("**topic ping**\n\n```glop\ntopic ping { count: int? }\n```\n\nConsumed \
+                           by:\n\n- `ponger.glop:3` `when (message ping) {`"));
+    assert_eq!(result["range"],
+               msg(r#"{"start": {"line": 0, "character": 38},
+                       "end": {"line": 0, "character": 42}}"#));
+
+    let result = at(&mut server, "textDocument/hover", "file:///w/ponger.glop", 3, 20);
+    assert_eq!(result["contents"]["value"],
+               Json::from("**topic pong**\n\nConsumed by:\n\n- `pinger.glop:3` `when (message \
+                           pong) { }`"));
+
+    // Not a topic.
+    assert_eq!(at(&mut server, "textDocument/hover", "file:///w/ponger.glop", 3, 8),
+               Json::Null);
+}
+
+#[test]
+fn lsp_completion() {
+    let (dir, _cleanup) = test_dir();
+    std::fs::create_dir_all(format!("{}/lib", dir)).unwrap();
+    std::fs::File::create(format!("{}/lib/status.glop", dir))
+        .unwrap()
+        .write_all(b"when (message status, is_set healthy) { msg reply status ok; }")
+        .unwrap();
+    let mut server = lsp::Server::new();
+    let req = msg(&format!(r#"{{"jsonrpc": "2.0", "id": 1, "method": "initialize",
+                               "params": {{"rootUri": "file://{}", "capabilities": {{}}}}}}"#,
+                           dir));
+    let resp = server.handle(&req);
+    assert_eq!(resp[0]["result"]["capabilities"]["hoverProvider"], Json::from(true));
+
+    let uri = format!("file://{}/agent.glop", dir);
+    open(&mut server, &uri, "when (message init) { var set count 0; }");
+    let result = at(&mut server, "textDocument/completion", &uri, 0, 0);
+    let items = result.as_array()
+        .unwrap()
+        .iter()
+        .map(|item| format!("{} {}", item["detail"].as_str().unwrap(), item["label"].as_str().unwrap()))
+        .collect::<Vec<_>>();
+    assert_eq!(items,
+               vec!["topic init", "topic ok", "topic status", "var count", "var healthy"]);
+}
+
+#[test]
+fn lsp_run() {
+    let mut input = vec![];
+    for body in &[r#"{"jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {}}"#,
+                  r#"{"jsonrpc": "2.0", "method": "initialized", "params": {}}"#,
+                  r#"{"jsonrpc": "2.0", "id": 2, "method": "workspace/symbol", "params": {}}"#,
+                  r#"{"jsonrpc": "2.0", "id": 3, "method": "shutdown"}"#,
+                  r#"{"jsonrpc": "2.0", "method": "exit"}"#,
+                  r#"{"jsonrpc": "2.0", "id": 4, "method": "shutdown"}"#] {
+        write!(input, "Content-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+    }
+    let mut output = vec![];
+    lsp::run(&mut std::io::Cursor::new(input), &mut output).unwrap();
+    let output = String::from_utf8(output).unwrap();
+    let bodies = output.split("Content-Length: ")
+        .skip(1)
+        .map(|part| msg(&part[part.find("\r\n\r\n").unwrap() + 4..]))
+        .collect::<Vec<_>>();
+    assert_eq!(bodies.len(), 3);
+    assert_eq!(bodies[0]["id"], Json::from(1));
+    assert_eq!(bodies[0]["result"]["capabilities"]["textDocumentSync"], Json::from(1));
+    assert_eq!(bodies[1]["error"]["code"], Json::from(-32601));
+    assert_eq!(bodies[2], msg(r#"{"jsonrpc": "2.0", "id": 3, "result": null}"#));
+}
+
+#[test]
+fn lsp_definition_from_parsed_units() {
+    let mut server = lsp::Server::new();
+    open(&mut server,
+         "file:///w/agent.glop",
+         r#"// var set ready false;
+when (message go) {
+    when (is_set ready) {
+        script #!/bin/bash
+echo glop var set ready.later
+!#
+    } else {
+        var set `ready` "now";
+    }
+}"#);
+    let result = at(&mut server, "textDocument/definition", "file:///w/agent.glop", 2, 20);
+    // The comment is not a definition; the quoted name is located by its action.
+    assert_eq!(result,
+               msg(r#"[
+        {"uri": "file:///w/agent.glop",
+         "range": {"start": {"line": 4, "character": 18},
+                   "end": {"line": 4, "character": 29}}},
+        {"uri": "file:///w/agent.glop",
+         "range": {"start": {"line": 7, "character": 8},
+                   "end": {"line": 7, "character": 30}}}]"#));
+}
+
+#[test]
+fn lsp_utf16_positions() {
+    let mut server = lsp::Server::new();
+    open(&mut server,
+         "file:///w/pinger.glop",
+         "when (message init) { /* 🏓 */ msg send ponger ping; }");
+    open(&mut server,
+         "file:///w/ponger.glop",
+         "when (message ping) { }");
+    // The paddle is one char but two UTF-16 code units, so `ping` starts at 47.
+    let result = at(&mut server, "textDocument/hover", "file:///w/pinger.glop", 0, 48);
+    assert_eq!(result["range"],
+               msg(r#"{"start": {"line": 0, "character": 47},
+                       "end": {"line": 0, "character": 51}}"#));
+
+    let out = open(&mut server, "file:///w/bad.glop", "when (message ping) { /* 🏓 */ oops }");
+    let diags = out.iter()
+        .find(|n| n["params"]["uri"] == Json::from("file:///w/bad.glop"))
+        .unwrap()["params"]["diagnostics"]
+        .as_array()
+        .unwrap()
+        .clone();
+    assert_eq!(diags[0]["range"],
+               msg(r#"{"start": {"line": 0, "character": 31},
+                       "end": {"line": 0, "character": 35}}"#));
+}
+
+#[test]
+fn lsp_encoded_uris() {
+    let (dir, _cleanup) = test_dir();
+    let dir = format!("{}/my agents", dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::File::create(format!("{}/common.glop", dir))
+        .unwrap()
+        .write_all(b"when (message init) { var set ready true; }")
+        .unwrap();
+    let mut server = lsp::Server::new();
+    let req = msg(&format!(r#"{{"jsonrpc": "2.0", "id": 1, "method": "initialize",
+                               "params": {{"rootUri": "file://{}", "capabilities": {{}}}}}}"#,
+                           dir.replace(" ", "%20")));
+    server.handle(&req);
+
+    // Clients may encode more than they need to; the document is found all the same.
+    let uri = format!("file://{}/agent%2Eglop", dir.replace(" ", "%20"));
+    let out = open(&mut server,
+                   &uri,
+                   "include \"common.glop\";\n\nwhen (message init, is_set ready) { }");
+    assert_eq!(out[0]["params"]["uri"],
+               Json::from(format!("file://{}/agent.glop", dir.replace(" ", "%20"))));
+    assert_eq!(out[0]["params"]["diagnostics"], msg("[]"));
+    let result = at(&mut server, "textDocument/definition", &uri, 2, 29);
+    assert_eq!(result[0]["uri"],
+               Json::from(format!("file://{}/common.glop", dir.replace(" ", "%20"))));
+}
@@ -0,0 +1,131 @@
+#![cfg(test)]
+
+extern crate textnonce;
+
+use std;
+use std::io::Write;
+
+use super::cleanup;
+use super::error::Error;
+use super::grammar;
+use super::source;
+
+fn test_dir() -> (String, cleanup::Cleanup) {
+    let mut path_buf = std::env::temp_dir();
+    path_buf.push(textnonce::TextNonce::sized_urlsafe(32)
+                      .unwrap()
+                      .into_string());
+    let path = path_buf.to_str().unwrap().to_string();
+    std::fs::create_dir_all(&path).unwrap();
+    (path.clone(), cleanup::Cleanup::Dir(path))
+}
+
+fn write_file(dir: &str, name: &str, contents: &str) -> String {
+    let path = std::path::PathBuf::from(dir).join(name);
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    let mut f = std::fs::File::create(&path).unwrap();
+    f.write_all(contents.as_bytes()).unwrap();
+    path.to_str().unwrap().to_string()
+}
+
+#[test]
+fn include_relative() {
+    let (dir, _cleanup) = test_dir();
+    write_file(&dir,
+               "common/intro.glop",
+               r#"include "log.glop";
+when (message intro) { var set introduced true; }"#);
+    write_file(&dir, "common/log.glop", r#"when (message log) { }"#);
+    let main = write_file(&dir,
+                          "agent.glop",
+                          r#"include "common/intro.glop";
+include "common/log.glop";
+
+when (message init) { }"#);
+    let glop = source::load(&main).unwrap();
+    // Included matches come first, and each source is included once.
+    assert!(glop.includes.is_empty());
+    assert_eq!(format!("{}", glop),
+               r#"when (message log) {
+}
+
+when (message intro) {
+    var set introduced true;
+}
+
+when (message init) {
+}
+
+"#);
+    // The bundled source stands on its own.
+    assert_eq!(grammar::glop(&format!("{}", glop)).unwrap().matches.len(), 3);
+}
+
+#[test]
+fn include_only() {
+    let (dir, _cleanup) = test_dir();
+    write_file(&dir, "a.glop", r#"when (message alpha) { }"#);
+    let main = write_file(&dir, "all.glop", r#"include "a.glop";"#);
+    assert_eq!(source::load(&main).unwrap().matches.len(), 1);
+}
+
+#[test]
+fn err_include_cycle() {
+    let (dir, _cleanup) = test_dir();
+    write_file(&dir, "a.glop", r#"include "b.glop"; when (message alpha) { }"#);
+    write_file(&dir, "b.glop", r#"include "a.glop"; when (message beta) { }"#);
+    match source::load(&std::path::PathBuf::from(&dir).join("a.glop").to_str().unwrap()) {
+        Err(Error::InvalidArgument(ref msg)) => assert!(msg.starts_with("include cycle")),
+        _ => panic!("expected include cycle"),
+    }
+}
+
+#[test]
+fn err_include() {
+    let (dir, _cleanup) = test_dir();
+    let missing = write_file(&dir, "missing.glop", r#"include "nope.glop";"#);
+    match source::load(&missing) {
+        Err(Error::IO(_)) => {}
+        _ => panic!("expected missing include"),
+    }
+    write_file(&dir, "bad.glop", r#"when (message bad {"#);
+    let main = write_file(&dir, "main.glop", r#"include "bad.glop";"#);
+    match source::load(&main) {
+        Err(Error::Diagnostics(ref diags)) => assert!(diags[0].source.ends_with("bad.glop")),
+        _ => panic!("expected parse error"),
+    }
+    // Includes must come before matches.
+    assert!(grammar::glop(r#"when (message alpha) { } include "a.glop";"#).is_err());
+}
+
+#[test]
+fn err_include_ambiguous_ref() {
+    let (dir, _cleanup) = test_dir();
+    // Loaded on its own, `config.mode` is a var; bundled with a source consuming config
+    // messages, it is ambiguous, as it would be to the agent server.
+    write_file(&dir, "config.glop", r#"when (message config) { }"#);
+    let main = write_file(&dir,
+                          "main.glop",
+                          r#"include "config.glop";
+when (message init, config.mode == debug) { }"#);
+    match source::load(&main) {
+        Err(Error::InvalidArgument(ref msg)) => {
+            assert!(msg.ends_with("message topic consumed by this match"))
+        }
+        _ => panic!("expected ambiguous reference"),
+    }
+}
+
+#[test]
+fn err_include_duplicate_match_name() {
+    let (dir, _cleanup) = test_dir();
+    write_file(&dir, "a.glop", r#"when "ready" (message init) { }"#);
+    let main = write_file(&dir,
+                          "main.glop",
+                          r#"include "a.glop";
+when "ready" (message config) { }"#);
+    match source::load(&main) {
+        Err(Error::InvalidArgument(ref msg)) => assert!(msg.ends_with("unique match name")),
+        _ => panic!("expected duplicate match name"),
+    }
+}
@@ -1,5 +1,6 @@
 #![cfg(test)]
 
+use super::grammar;
 use super::value::*;
 
 fn test_obj() -> Obj {
@@ -141,3 +142,45 @@ fn test_to_env() {
     assert_eq!(Identifier::from_str("apple.size").get(&o),
                Some(&Value::from_int(4)))
 }
+
+fn test_schemas() -> Schemas {
+    let g = grammar::glop("topic configure { release: string, replicas: int?, opts: object? }")
+        .unwrap();
+    Schemas::from_ast(&g.topics)
+}
+
+#[test]
+fn schema_check() {
+    let schemas = test_schemas();
+    let mut o = Obj::new();
+    assert!(schemas.check("configure", &o).is_err());
+    o.insert("release".to_string(), Value::from_str("1.2"));
+    assert!(schemas.check("configure", &o).is_ok());
+    o.insert("replicas".to_string(), Value::from_str("3"));
+    assert!(schemas.check("configure", &o).is_err());
+    o.insert("replicas".to_string(), Value::from_int(3));
+    assert!(schemas.check("configure", &o).is_ok());
+    o.insert("colour".to_string(), Value::from_str("red"));
+    assert!(schemas.check("configure", &o).is_err());
+    assert!(schemas.check("undeclared", &o).is_ok());
+}
+
+#[test]
+fn schema_coerce() {
+    let schemas = test_schemas();
+    let args = [("release".to_string(), "1.2".to_string()),
+                ("replicas".to_string(), "3".to_string()),
+                ("opts.debug".to_string(), "true".to_string())]
+        .iter()
+        .cloned()
+        .collect();
+    let o = schemas.coerce("configure", Value::from_flat_map(args)).unwrap();
+    assert_eq!(o.get("release"), Some(&Value::from_str("1.2")));
+    assert_eq!(o.get("replicas"), Some(&Value::from_int(3)));
+    let args = [("release".to_string(), "1.2".to_string()),
+                ("replicas".to_string(), "three".to_string())]
+        .iter()
+        .cloned()
+        .collect();
+    assert!(schemas.coerce("configure", Value::from_flat_map(args)).is_err());
+}
@@ -1,8 +1,10 @@
 extern crate textnonce;
 
 use std::collections::HashMap;
+use std::fmt;
 
 use super::ast;
+use super::error::Error;
 
 #[derive(Serialize, Deserialize)]
 #[derive(Clone, Debug)]
@@ -50,6 +52,24 @@ impl Value {
         Value::Object(o)
     }
 
+    /// Convert a literal from glop source. Booleans are represented as strings.
+    pub fn from_literal(l: &ast::Literal) -> Value {
+        match l {
+            &ast::Literal::Int(i) => Value::Int(i),
+            &ast::Literal::Str(ref s) => Value::Str(s.to_string()),
+            &ast::Literal::Bool(b) => Value::Str(b.to_string()),
+        }
+    }
+
+    /// Integer interpretation of the value, if it has one.
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            &Value::Int(i) => Some(i as i64),
+            &Value::Str(ref s) => s.trim().parse::<i64>().ok(),
+            &Value::Object(_) => None,
+        }
+    }
+
     pub fn from_flat_map(m: HashMap<String, String>) -> Obj {
         let mut result = Obj::new();
         for (k, v) in m.iter() {
@@ -59,6 +79,15 @@ impl Value {
         result
     }
 
+    /// Name of the value's type, as declared in topic schemas.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            &Value::Int(_) => "int",
+            &Value::Str(_) => "string",
+            &Value::Object(_) => "object",
+        }
+    }
+
     pub fn to_string(&self) -> String {
         match self {
             &Value::Int(ref i) => i.to_string(),
@@ -121,6 +150,10 @@ pub struct Message {
 
     /// Contents of the message.
     pub contents: Obj,
+
+    /// Messages with a higher priority are handled ahead of others pending on the same topic.
+    #[serde(default)]
+    pub priority: i32,
 }
 
 impl Message {
@@ -137,6 +170,7 @@ impl Message {
             topic: topic.to_string(),
             in_reply_to: None,
             contents: contents,
+            priority: 0,
         }
     }
 
@@ -176,6 +210,99 @@ impl Message {
         self.in_reply_to = in_reply_to.clone();
         self
     }
+
+    pub fn priority(mut self, priority: i32) -> Message {
+        self.priority = priority;
+        self
+    }
+}
+
+impl fmt::Display for Identifier {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0.join("."))
+    }
+}
+
+/// Schemas declared for message topics, by topic name.
+#[derive(Clone, Debug, Default)]
+pub struct Schemas(HashMap<String, ast::Topic>);
+
+impl Schemas {
+    pub fn from_ast(topics: &Vec<ast::Topic>) -> Schemas {
+        Schemas(topics.iter().map(|t| (t.name.to_string(), t.clone())).collect())
+    }
+
+    /// Declared topics, ordered by name.
+    pub fn topics(&self) -> Vec<ast::Topic> {
+        let mut topics = self.0.values().cloned().collect::<Vec<_>>();
+        topics.sort_by(|a, b| a.name.cmp(&b.name));
+        topics
+    }
+
+    /// Check message contents against the schema declared for its topic. Messages on
+    /// undeclared topics are not checked.
+    pub fn check(&self, topic: &str, contents: &Obj) -> Result<(), Error> {
+        let schema = match self.0.get(topic) {
+            Some(schema) => schema,
+            None => return Ok(()),
+        };
+        for field in &schema.fields {
+            match contents.get(&field.name) {
+                Some(v) => {
+                    let matched = match (&field.field_type, v) {
+                        (&ast::FieldType::String, &Value::Str(_)) |
+                        (&ast::FieldType::Int, &Value::Int(_)) |
+                        (&ast::FieldType::Object, &Value::Object(_)) => true,
+                        _ => false,
+                    };
+                    if !matched {
+                        return Err(Error::Schema(format!("{}.{}: expected {}, got {}",
+                                                         topic,
+                                                         field.name,
+                                                         field.field_type,
+                                                         v.type_name())));
+                    }
+                }
+                None => {
+                    if !field.optional {
+                        return Err(Error::Schema(format!("{}: missing field {}",
+                                                         topic,
+                                                         field.name)));
+                    }
+                }
+            }
+        }
+        let mut undeclared = contents.keys()
+            .filter(|k| !schema.fields.iter().any(|field| &field.name == *k))
+            .collect::<Vec<_>>();
+        undeclared.sort();
+        if let Some(k) = undeclared.first() {
+            return Err(Error::Schema(format!("{}: undeclared field {}", topic, k)));
+        }
+        Ok(())
+    }
+
+    /// Convert message contents given as strings, such as command-line arguments, into the
+    /// types declared for their topic, then check them.
+    pub fn coerce(&self, topic: &str, contents: Obj) -> Result<Obj, Error> {
+        let mut contents = contents;
+        if let Some(schema) = self.0.get(topic) {
+            for field in &schema.fields {
+                if field.field_type != ast::FieldType::Int {
+                    continue;
+                }
+                let parsed = match contents.get(&field.name) {
+                    Some(&Value::Str(ref s)) => s.trim().parse::<i32>().ok(),
+                    _ => None,
+                };
+                if let Some(i) = parsed {
+                    contents.insert(field.name.to_string(), Value::Int(i));
+                }
+            }
+        }
+        self.check(topic, &contents)?;
+        Ok(contents)
+    }
 }
 
 /// Environment variable settings.